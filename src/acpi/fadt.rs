@@ -0,0 +1,159 @@
+use core::mem::size_of;
+
+use x86_64::PhysAddr;
+
+use super::{GenericAddress, SdtHeader};
+
+/// Fixed ACPI Description Table, up to the fields introduced by ACPI 2.0
+/// which the kernel uses.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct FadtTable {
+    header: SdtHeader,
+    firmware_ctrl: u32,
+    dsdt: u32,
+    _reserved0: u8,
+    preferred_pm_profile: u8,
+    sci_interrupt: u16,
+    smi_command: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_request: u8,
+    pstate_control: u8,
+    pm1a_event_block: u32,
+    pm1b_event_block: u32,
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+    pm2_control_block: u32,
+    pm_timer_block: u32,
+    gpe0_block: u32,
+    gpe1_block: u32,
+    pm1_event_length: u8,
+    pm1_control_length: u8,
+    pm2_control_length: u8,
+    pm_timer_length: u8,
+    gpe0_block_length: u8,
+    gpe1_block_length: u8,
+    gpe1_base: u8,
+    cstate_control: u8,
+    worst_c2_latency: u16,
+    worst_c3_latency: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alarm: u8,
+    month_alarm: u8,
+    century: u8,
+    iapc_boot_architecture: u16,
+    _reserved1: u8,
+    flags: u32,
+    // fields below are only present in ACPI 2.0+ tables
+    reset_register: GenericAddress,
+    reset_value: u8,
+    arm_boot_architecture: u16,
+    minor_version: u8,
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+}
+
+/// A typed view of the FADT.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    table: &'static FadtTable,
+}
+
+impl Fadt {
+    pub const SIGNATURE: &'static [u8; 4] = b"FACP";
+
+    /// Flag indicating that the reset register is supported.
+    const RESET_REG_SUP: u32 = 1 << 10;
+
+    /// Locates the FADT in the ACPI tables.
+    #[must_use]
+    pub fn find() -> Option<Self> {
+        let header = super::find_table(Self::SIGNATURE)?;
+        let table = unsafe { &*(header as *const SdtHeader).cast::<FadtTable>() };
+        Some(Self { table })
+    }
+
+    /// Returns `true` if the table is long enough to contain the field ending at `end`.
+    fn contains(&self, end: usize) -> bool {
+        self.table.header.length as usize >= end
+    }
+
+    /// Returns the physical address of the Differentiated System Description Table.
+    #[must_use]
+    pub fn dsdt_address(&self) -> PhysAddr {
+        let x_dsdt = self.table.x_dsdt;
+        if self.contains(size_of::<FadtTable>()) && x_dsdt != 0 {
+            PhysAddr::new(x_dsdt)
+        } else {
+            PhysAddr::new(u64::from(self.table.dsdt))
+        }
+    }
+
+    /// Returns the ISA IRQ of the System Control Interrupt.
+    #[must_use]
+    pub fn sci_interrupt(&self) -> u16 {
+        self.table.sci_interrupt
+    }
+
+    /// Returns the I/O port to which `acpi_enable` must be written to switch
+    /// the machine into ACPI mode, or `None` if it is always in ACPI mode.
+    #[must_use]
+    pub fn smi_command_port(&self) -> Option<u16> {
+        match self.table.smi_command {
+            0 => None,
+            port => u16::try_from(port).ok(),
+        }
+    }
+
+    /// Returns the value written to the SMI command port to enable ACPI mode.
+    #[must_use]
+    pub fn acpi_enable(&self) -> u8 {
+        self.table.acpi_enable
+    }
+
+    /// Returns the I/O port of the PM1a event register block.
+    #[must_use]
+    pub fn pm1a_event_block(&self) -> u16 {
+        self.table.pm1a_event_block as u16
+    }
+
+    /// Returns the I/O port of the PM1a control register block.
+    #[must_use]
+    pub fn pm1a_control_block(&self) -> u16 {
+        self.table.pm1a_control_block as u16
+    }
+
+    /// Returns the I/O port of the PM1b control register block, if present.
+    #[must_use]
+    pub fn pm1b_control_block(&self) -> Option<u16> {
+        match self.table.pm1b_control_block {
+            0 => None,
+            port => Some(port as u16),
+        }
+    }
+
+    /// Returns the I/O port of the ACPI power management timer, if present.
+    #[must_use]
+    pub fn pm_timer_block(&self) -> Option<u16> {
+        match self.table.pm_timer_block {
+            0 => None,
+            port => Some(port as u16),
+        }
+    }
+
+    /// Returns the register to write to reset the machine, and the value to write.
+    #[must_use]
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        const RESET_VALUE_END: usize = 129;
+        if self.contains(RESET_VALUE_END) && self.table.flags & Self::RESET_REG_SUP != 0 {
+            Some((self.table.reset_register, self.table.reset_value))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use core::mem::size_of;
+
+use x86_64::PhysAddr;
+
+use super::{GenericAddress, SdtHeader};
+
+/// HPET Description Table, describing an High Precision Event Timer block.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct HpetTable {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+/// A typed view of the HPET table.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    table: &'static HpetTable,
+}
+
+impl Hpet {
+    pub const SIGNATURE: &'static [u8; 4] = b"HPET";
+
+    /// Locates the HPET table in the ACPI tables.
+    #[must_use]
+    pub fn find() -> Option<Self> {
+        let header = super::find_table(Self::SIGNATURE)?;
+        if (header.length as usize) < size_of::<HpetTable>() {
+            return None;
+        }
+        let table = unsafe { &*(header as *const SdtHeader).cast::<HpetTable>() };
+        Some(Self { table })
+    }
+
+    /// Returns the physical address of the memory-mapped timer registers.
+    #[must_use]
+    pub fn base_address(&self) -> PhysAddr {
+        PhysAddr::new(self.table.base_address.address)
+    }
+
+    /// Returns the sequence number of this timer block.
+    #[must_use]
+    pub fn hpet_number(&self) -> u8 {
+        self.table.hpet_number
+    }
+
+    /// Returns the minimum number of main counter ticks for periodic interrupts
+    /// without lost interrupts.
+    #[must_use]
+    pub fn minimum_tick(&self) -> u16 {
+        self.table.minimum_tick
+    }
+
+    /// Returns the number of comparators (timers) in the block.
+    #[must_use]
+    pub fn comparator_count(&self) -> u8 {
+        ((self.table.event_timer_block_id >> 8) & 0x1f) as u8 + 1
+    }
+
+    /// Returns `true` if the main counter is 64 bits wide.
+    #[must_use]
+    pub fn is_counter_64bit(&self) -> bool {
+        self.table.event_timer_block_id & (1 << 13) != 0
+    }
+
+    /// Returns the PCI vendor id of the timer block.
+    #[must_use]
+    pub fn vendor_id(&self) -> u16 {
+        (self.table.event_timer_block_id >> 16) as u16
+    }
+}
@@ -0,0 +1,143 @@
+use core::mem::size_of;
+
+use x86_64::PhysAddr;
+
+use super::SdtHeader;
+
+/// Multiple APIC Description Table, describing the interrupt controllers of the machine.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct MadtHeader {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+/// A typed view of the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    table: &'static MadtHeader,
+}
+
+/// An Interrupt Controller Structure listed in the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    /// A processor with its own local APIC.
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    /// An I/O APIC, handling the global system interrupts starting at `gsi_base`.
+    IoApic {
+        id: u8,
+        address: PhysAddr,
+        gsi_base: u32,
+    },
+    /// An ISA interrupt which is not identity mapped to a global system interrupt.
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    /// The 64-bit physical address of the local APIC, superseding the one in the table header.
+    LocalApicAddressOverride { address: PhysAddr },
+    /// An entry type which is not interpreted.
+    Unknown { entry_type: u8 },
+}
+
+impl Madt {
+    pub const SIGNATURE: &'static [u8; 4] = b"APIC";
+
+    /// Flag in the MADT header indicating that legacy 8259 PICs are installed.
+    const PCAT_COMPAT: u32 = 1 << 0;
+
+    /// Locates the MADT in the ACPI tables.
+    #[must_use]
+    pub fn find() -> Option<Self> {
+        let header = super::find_table(Self::SIGNATURE)?;
+        if (header.length as usize) < size_of::<MadtHeader>() {
+            return None;
+        }
+        let table = unsafe { &*(header as *const SdtHeader).cast::<MadtHeader>() };
+        Some(Self { table })
+    }
+
+    /// Returns the physical address of the local APIC registers.
+    #[must_use]
+    pub fn local_apic_address(&self) -> PhysAddr {
+        self.entries()
+            .find_map(|entry| match entry {
+                Entry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or_else(|| PhysAddr::new(u64::from(self.table.local_apic_address)))
+    }
+
+    /// Returns `true` if the machine also has legacy 8259 PICs, which need to
+    /// be masked when using the APIC.
+    #[must_use]
+    pub fn has_legacy_pics(&self) -> bool {
+        self.table.flags & Self::PCAT_COMPAT != 0
+    }
+
+    /// Returns an iterator over the Interrupt Controller Structures of the table.
+    pub fn entries(&self) -> impl Iterator<Item = Entry> {
+        let start = unsafe {
+            (self.table as *const MadtHeader)
+                .cast::<u8>()
+                .add(size_of::<MadtHeader>())
+        };
+        let length = self.table.header.length as usize - size_of::<MadtHeader>();
+
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            if offset + 2 > length {
+                return None;
+            }
+            let entry = unsafe { start.add(offset) };
+            let (entry_type, entry_length) = unsafe { (*entry, usize::from(*entry.add(1))) };
+            if entry_length < 2 || offset + entry_length > length {
+                return None; // malformed table
+            }
+            offset += entry_length;
+            Some(unsafe { Entry::parse(entry_type, entry) })
+        })
+    }
+}
+
+impl Entry {
+    /// Parses the entry of the given type starting at `entry`.
+    ///
+    /// # Safety
+    /// The caller must guarantee that `entry` points to a complete entry of the given type.
+    unsafe fn parse(entry_type: u8, entry: *const u8) -> Self {
+        let read_u16 = |offset: usize| entry.add(offset).cast::<u16>().read_unaligned();
+        let read_u32 = |offset: usize| entry.add(offset).cast::<u32>().read_unaligned();
+        let read_u64 = |offset: usize| entry.add(offset).cast::<u64>().read_unaligned();
+
+        match entry_type {
+            0 => Entry::LocalApic {
+                processor_id: *entry.add(2),
+                apic_id: *entry.add(3),
+                flags: read_u32(4),
+            },
+            1 => Entry::IoApic {
+                id: *entry.add(2),
+                address: PhysAddr::new(u64::from(read_u32(4))),
+                gsi_base: read_u32(8),
+            },
+            2 => Entry::InterruptSourceOverride {
+                bus: *entry.add(2),
+                source: *entry.add(3),
+                gsi: read_u32(4),
+                flags: read_u16(8),
+            },
+            5 => Entry::LocalApicAddressOverride {
+                address: PhysAddr::new(read_u64(4)),
+            },
+            entry_type => Entry::Unknown { entry_type },
+        }
+    }
+}
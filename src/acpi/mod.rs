@@ -0,0 +1,215 @@
+use core::mem::size_of;
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+
+/// Root System Description Pointer, as found in the BIOS memory area.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // fields below are only valid if revision >= 2
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+impl Rsdp {
+    const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+
+    /// The size of the ACPI 1.0 structure, which is covered by `checksum`.
+    const V1_LENGTH: usize = 20;
+    /// The size of the ACPI 2.0 structure, which is covered by `extended_checksum`.
+    const V2_LENGTH: usize = size_of::<Rsdp>();
+    /// The largest `length` accepted, so that a corrupt one does not make the
+    /// checksum read far beyond the structure.
+    const MAX_LENGTH: usize = 4096;
+
+    fn is_valid(&self) -> bool {
+        let ptr = (self as *const Self).cast::<u8>();
+        if &self.signature != Self::SIGNATURE || !unsafe { checksum_valid(ptr, Self::V1_LENGTH) } {
+            return false;
+        }
+        let length = self.length as usize;
+        self.revision < 2
+            || ((Self::V2_LENGTH..=Self::MAX_LENGTH).contains(&length)
+                && unsafe { checksum_valid(ptr, length) })
+    }
+}
+
+/// Generic Address Structure, describing the location of a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+}
+
+/// The header common to all System Description Tables.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// The largest `length` accepted, so that a corrupt one does not make the
+    /// checksum read far beyond the table. The DSDT, the largest table, takes
+    /// up a few hundred KiB at most.
+    const MAX_LENGTH: usize = 1024 * 1024;
+
+    /// Returns a pointer to the first byte of table data following the header.
+    fn data(&self) -> *const u8 {
+        unsafe { (self as *const Self).cast::<u8>().add(size_of::<SdtHeader>()) }
+    }
+
+    /// Returns the length of the table data following the header.
+    fn data_length(&self) -> usize {
+        (self.length as usize).saturating_sub(size_of::<SdtHeader>())
+    }
+
+    /// Returns `true` if all bytes of the table, including the header, sum to
+    /// zero, and its length is plausible.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        let length = self.length as usize;
+        (size_of::<SdtHeader>()..=Self::MAX_LENGTH).contains(&length)
+            && unsafe { checksum_valid((self as *const Self).cast::<u8>(), length) }
+    }
+}
+
+/// Returns `true` if the `length` bytes starting at `ptr` sum to zero.
+///
+/// # Safety
+/// The caller must guarantee that `ptr` is valid for reads of `length` bytes.
+unsafe fn checksum_valid(ptr: *const u8, length: usize) -> bool {
+    core::slice::from_raw_parts(ptr, length)
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        == 0
+}
+
+/// The table listing all other System Description Tables.
+#[derive(Debug, Clone, Copy)]
+enum RootTable {
+    /// Root System Description Table, with 32-bit entries.
+    Rsdt(PhysAddr),
+    /// Extended System Description Table, with 64-bit entries.
+    Xsdt(PhysAddr),
+}
+
+static ROOT_TABLE: Once<Option<RootTable>> = Once::new();
+
+/// Searches the physical memory areas specified by ACPI for the RSDP.
+fn find_rsdp() -> Option<&'static Rsdp> {
+    // The first KiB of the Extended BIOS Data Area, whose segment is stored at 0x40e
+    let ebda = {
+        let segment = unsafe { *memory::phys_to_virt(PhysAddr::new(0x40e)).as_ptr::<u16>() };
+        let start = u64::from(segment) << 4;
+        start..start + 1024
+    };
+    // The main BIOS area below 1 MiB
+    let bios = 0xe_0000..0x10_0000;
+
+    ebda.step_by(16)
+        .chain(bios.step_by(16))
+        .map(|addr| unsafe { &*memory::phys_to_virt(PhysAddr::new(addr)).as_ptr::<Rsdp>() })
+        .find(|rsdp| rsdp.is_valid())
+}
+
+fn root_table() -> Option<RootTable> {
+    if !memory::is_initialized() {
+        return None; // the tables are only reachable through the physical memory mapping
+    }
+    *ROOT_TABLE.call_once(|| {
+        let rsdp = find_rsdp()?;
+        let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+            RootTable::Xsdt(PhysAddr::new(rsdp.xsdt_address))
+        } else {
+            RootTable::Rsdt(PhysAddr::new(u64::from(rsdp.rsdt_address)))
+        };
+        let (RootTable::Rsdt(addr) | RootTable::Xsdt(addr)) = root;
+        unsafe { table_at(addr) }.is_valid().then_some(root)
+    })
+}
+
+/// Returns `true` if the ACPI tables were found and their root table is valid.
+#[must_use]
+pub fn is_present() -> bool {
+    root_table().is_some()
+}
+
+/// Returns a reference to the System Description Table at the given physical address.
+///
+/// # Safety
+/// The caller must guarantee that a table is located at `addr`.
+pub unsafe fn table_at(addr: PhysAddr) -> &'static SdtHeader {
+    &*memory::phys_to_virt(addr).as_ptr::<SdtHeader>()
+}
+
+/// Returns an iterator over the physical addresses of all tables listed in the root table.
+fn table_addresses() -> impl Iterator<Item = PhysAddr> {
+    let (header, entry_size) = match root_table() {
+        Some(RootTable::Rsdt(addr)) => (Some(unsafe { table_at(addr) }), size_of::<u32>()),
+        Some(RootTable::Xsdt(addr)) => (Some(unsafe { table_at(addr) }), size_of::<u64>()),
+        None => (None, 1),
+    };
+
+    header.into_iter().flat_map(move |header| {
+        let entries = header.data();
+        (0..header.data_length() / entry_size).map(move |i| {
+            let addr = unsafe {
+                let ptr = entries.add(i * entry_size);
+                if entry_size == size_of::<u32>() {
+                    u64::from(ptr.cast::<u32>().read_unaligned())
+                } else {
+                    ptr.cast::<u64>().read_unaligned()
+                }
+            };
+            PhysAddr::new(addr)
+        })
+    })
+}
+
+/// Returns an iterator over all tables listed in the root table, including
+/// ones with an invalid checksum.
+pub fn tables() -> impl Iterator<Item = &'static SdtHeader> {
+    table_addresses().map(|addr| unsafe { table_at(addr) })
+}
+
+/// Finds the System Description Table with the given signature, e.g. `b"APIC"`.
+///
+/// Returns `None` if no ACPI tables were found or the table is not present
+/// or fails checksum validation.
+#[must_use]
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    tables().find(|table| &table.signature == signature && table.is_valid())
+}
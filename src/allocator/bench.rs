@@ -0,0 +1,303 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::asm,
+    fmt,
+};
+
+use alloc::vec::Vec;
+
+use crate::serial_println;
+
+use super::{
+    bump, fixed_size_block,
+    linked_list::{self, FitPolicy},
+    HeapBackend, Locked,
+};
+
+/// The size of the heap each workload runs on, which is allocated from the kernel heap.
+pub const BENCH_HEAP_SIZE: usize = 1024 * 1024;
+
+/// The number of allocations each workload makes.
+const ALLOCATIONS: usize = 4096;
+
+/// A synthetic allocation pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// Blocks of 64 bytes, freed in allocation order while 256 of them are live.
+    Fifo,
+    /// Batches of 128 blocks between 32 and 512 bytes, freed in reverse order.
+    Lifo,
+    /// Blocks between 16 and 1024 bytes with mixed alignments, of which up to
+    /// 256 live at the same time and are freed in random order.
+    Random,
+    /// Blocks between 4 KiB and 32 KiB, of which up to 8 are live.
+    Large,
+}
+
+impl Workload {
+    pub const ALL: [Workload; 4] = [Self::Fifo, Self::Lifo, Self::Random, Self::Large];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Fifo => "fifo",
+            Self::Lifo => "lifo",
+            Self::Random => "random",
+            Self::Large => "large",
+        }
+    }
+}
+
+/// The outcome of running a workload on a backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchResult {
+    pub backend: &'static str,
+    pub workload: Workload,
+    /// The number of allocations and deallocations.
+    pub operations: u64,
+    pub failed_allocations: u64,
+    /// The number of TSC cycles spent in the allocator.
+    pub cycles: u64,
+    /// The size of the largest block which could still be allocated once the
+    /// workload has finished, with its last blocks still live.
+    pub largest_free_block: u64,
+    /// The percentage of the heap not used by live blocks which cannot be
+    /// allocated as a single block, because it is fragmented or lost as overhead.
+    pub fragmentation: u64,
+}
+
+impl BenchResult {
+    #[must_use]
+    pub fn cycles_per_operation(&self) -> u64 {
+        self.cycles / self.operations.max(1)
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<28} {:<8} {:>8} ops {:>6} cycles/op {:>5} failed {:>8} B largest {:>3}% fragmented",
+            self.backend,
+            self.workload.name(),
+            self.operations,
+            self.cycles_per_operation(),
+            self.failed_allocations,
+            self.largest_free_block,
+            self.fragmentation,
+        )
+    }
+}
+
+/// Runs every workload on every backend, and reports the results over serial.
+///
+/// Must be called after the kernel heap has been initialized, since the heaps
+/// of the backends are allocated from it.
+pub fn run_all() -> Vec<BenchResult> {
+    let mut results = Vec::new();
+    results.extend(run("bump", bump::Allocator::new));
+    results.extend(run("linked-list (first fit)", || {
+        linked_list::Allocator::with_policy(FitPolicy::FirstFit)
+    }));
+    results.extend(run("linked-list (best fit)", || {
+        linked_list::Allocator::with_policy(FitPolicy::BestFit)
+    }));
+    results.extend(run("linked-list (next fit)", || {
+        linked_list::Allocator::with_policy(FitPolicy::NextFit)
+    }));
+    results.extend(run("fixed-size-block", fixed_size_block::Allocator::new));
+    results
+}
+
+/// Runs every workload on a fresh instance of the backend created by `new`,
+/// and reports the results over serial.
+pub fn run<A: HeapBackend>(name: &'static str, new: fn() -> A) -> Vec<BenchResult>
+where
+    Locked<A>: GlobalAlloc,
+{
+    Workload::ALL
+        .iter()
+        .map(|&workload| {
+            let result = run_workload(name, &Locked::new(new()), workload);
+            serial_println!("{result}");
+            result
+        })
+        .collect()
+}
+
+fn run_workload<A: HeapBackend>(
+    name: &'static str,
+    allocator: &Locked<A>,
+    workload: Workload,
+) -> BenchResult
+where
+    Locked<A>: GlobalAlloc,
+{
+    let heap_layout = Layout::from_size_align(BENCH_HEAP_SIZE, 4096).unwrap();
+    let heap = unsafe { alloc::alloc::alloc(heap_layout) };
+    assert!(!heap.is_null(), "allocating the benchmark heap failed");
+    unsafe {
+        allocator.lock().init(heap as u64, BENCH_HEAP_SIZE as u64);
+    }
+
+    let mut bench = Bench {
+        allocator,
+        live: Vec::with_capacity(256),
+        rng: Rng(0x2545_f491_4f6c_dd1d),
+        operations: 0,
+        failed_allocations: 0,
+        cycles: 0,
+    };
+    match workload {
+        Workload::Fifo => {
+            for _ in 0..ALLOCATIONS {
+                if bench.live.len() == 256 {
+                    bench.free(0);
+                }
+                bench.alloc(64, 8);
+            }
+        }
+        Workload::Lifo => {
+            for _ in 0..ALLOCATIONS / 128 {
+                for _ in 0..128 {
+                    let size = bench.rng.range(32, 512);
+                    bench.alloc(size, 8);
+                }
+                while !bench.live.is_empty() {
+                    bench.free(bench.live.len() - 1);
+                }
+            }
+            // keep a batch live, so that the fragmentation is not trivially zero
+            for _ in 0..128 {
+                let size = bench.rng.range(32, 512);
+                bench.alloc(size, 8);
+            }
+        }
+        Workload::Random => {
+            for _ in 0..ALLOCATIONS {
+                if bench.live.len() == 256 || (!bench.live.is_empty() && bench.rng.next() % 2 == 0)
+                {
+                    let index = bench.rng.range(0, bench.live.len() - 1);
+                    bench.free(index);
+                }
+                let size = bench.rng.range(16, 1024);
+                let align = [8, 16, 64][bench.rng.range(0, 2)];
+                bench.alloc(size, align);
+            }
+        }
+        Workload::Large => {
+            for _ in 0..ALLOCATIONS / 16 {
+                if bench.live.len() == 8 {
+                    let index = bench.rng.range(0, 7);
+                    bench.free(index);
+                }
+                let size = bench.rng.range(4096, 32 * 1024);
+                bench.alloc(size, 8);
+            }
+        }
+    }
+
+    let live_bytes: usize = bench.live.iter().map(|(_, layout)| layout.size()).sum();
+    let largest_free_block = largest_free_block(allocator);
+    let free_bytes = (BENCH_HEAP_SIZE - live_bytes) as u64;
+    let result = BenchResult {
+        backend: name,
+        workload,
+        operations: bench.operations,
+        failed_allocations: bench.failed_allocations,
+        cycles: bench.cycles,
+        largest_free_block,
+        fragmentation: 100 - largest_free_block * 100 / free_bytes.max(1),
+    };
+
+    while !bench.live.is_empty() {
+        bench.free(bench.live.len() - 1);
+    }
+    unsafe {
+        alloc::alloc::dealloc(heap, heap_layout);
+    }
+    result
+}
+
+/// The state of a running workload.
+struct Bench<'a, A> {
+    allocator: &'a Locked<A>,
+    /// The live blocks, which are stored on the kernel heap.
+    live: Vec<(*mut u8, Layout)>,
+    rng: Rng,
+    operations: u64,
+    failed_allocations: u64,
+    cycles: u64,
+}
+
+impl<A> Bench<'_, A>
+where
+    Locked<A>: GlobalAlloc,
+{
+    fn alloc(&mut self, size: usize, align: usize) {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let start = rdtsc();
+        let ptr = unsafe { self.allocator.alloc(layout) };
+        self.cycles += rdtsc() - start;
+        self.operations += 1;
+
+        if ptr.is_null() {
+            self.failed_allocations += 1;
+        } else {
+            self.live.push((ptr, layout));
+        }
+    }
+
+    fn free(&mut self, index: usize) {
+        let (ptr, layout) = self.live.remove(index);
+        let start = rdtsc();
+        unsafe { self.allocator.dealloc(ptr, layout) };
+        self.cycles += rdtsc() - start;
+        self.operations += 1;
+    }
+}
+
+/// Returns the size of the largest block which can be allocated, to a granularity of 8 bytes.
+fn largest_free_block<A>(allocator: &Locked<A>) -> u64
+where
+    Locked<A>: GlobalAlloc,
+{
+    let (mut low, mut high) = (0, BENCH_HEAP_SIZE / 8);
+    while low < high {
+        let mid = (low + high + 1) / 2;
+        let layout = Layout::from_size_align(mid * 8, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        if ptr.is_null() {
+            high = mid - 1;
+        } else {
+            unsafe { allocator.dealloc(ptr, layout) };
+            low = mid;
+        }
+    }
+    low as u64 * 8
+}
+
+/// A xorshift generator, so that every backend sees the same sequence of requests.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number between `low` and `high`, inclusive.
+    fn range(&mut self, low: usize, high: usize) -> usize {
+        low + (self.next() % (high - low + 1) as u64) as usize
+    }
+}
+
+/// Reads the time stamp counter.
+fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    (u64::from(high) << 32) | u64::from(low)
+}
@@ -1,6 +1,6 @@
 use core::alloc::GlobalAlloc;
 
-use super::{Locked, align_up};
+use super::{align_up, HeapBackend, Locked};
 
 pub struct Allocator {
     heap_start: u64,
@@ -33,6 +33,18 @@ impl Allocator {
     }
 }
 
+impl HeapBackend for Allocator {
+    const NAME: &'static str = "bump";
+
+    unsafe fn init(&mut self, heap_start: u64, heap_size: u64) {
+        Allocator::init(self, heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, additional: u64) {
+        self.heap_end += additional;
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<Allocator> {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
         let mut bump = self.lock();
@@ -0,0 +1,266 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    mem::size_of,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::serial_println;
+
+use super::align_up;
+
+/// The number of guard bytes in front of and behind every allocation.
+pub const REDZONE_SIZE: usize = 16;
+/// The value of the guard bytes.
+pub const REDZONE_BYTE: u8 = 0xfd;
+/// The value written over freed memory.
+pub const POISON_BYTE: u8 = 0x6b;
+/// The number of freed allocations which are held back before they are given
+/// to the backend, so that double frees and writes after free can be detected.
+pub const QUARANTINE_SIZE: usize = 64;
+
+const ALLOCATED_MAGIC: u64 = 0xa110_c8ed_a110_c8ed;
+const FREED_MAGIC: u64 = 0xdead_f4ee_dead_f4ee;
+
+/// The bookkeeping in front of the front redzone of every allocation.
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+}
+
+/// A kind of heap corruption or misuse detected by a `DebugAllocator`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    /// The allocation has already been freed.
+    DoubleFree,
+    /// The pointer was not returned by the allocator, or the header in front of
+    /// the allocation has been overwritten.
+    InvalidFree,
+    /// The layout passed to `dealloc` differs from the one passed to `alloc`.
+    LayoutMismatch { allocated: Layout },
+    /// A byte in front of the allocation has been written, at the given
+    /// (negative) offset from its start.
+    Underflow { offset: isize },
+    /// A byte behind the allocation has been written, at the given offset from its start.
+    Overflow { offset: usize },
+    /// The allocation has been written after it was freed, at the given offset from its start.
+    UseAfterFree { offset: usize },
+}
+
+/// A detected error along with the offending allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapErrorReport {
+    pub error: HeapError,
+    pub ptr: *mut u8,
+    /// The layout passed to `dealloc`.
+    pub layout: Layout,
+}
+
+// The pointer is only reported, never dereferenced.
+unsafe impl Send for HeapErrorReport {}
+
+impl fmt::Display for HeapErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} in allocation {:p} of {} bytes aligned to {}",
+            self.error,
+            self.ptr,
+            self.layout.size(),
+            self.layout.align()
+        )
+    }
+}
+
+/// Freed allocations which have not been given to the backend yet, in a ring buffer.
+struct Quarantine {
+    allocations: [Option<(*mut u8, Layout)>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+/// An allocator wrapper which guards every allocation of the wrapped allocator
+/// with redzones, and checks them when it is freed.
+///
+/// Freed memory is poisoned and quarantined for a while, so that double frees
+/// and writes to freed memory are detected. A double free is missed if the
+/// allocation has left the quarantine and its memory has been reused already.
+///
+/// Every error is reported over serial, and panics unless disabled with
+/// `set_panic_on_error`. Allocations for which an error was detected are leaked.
+/// Like the backends, the wrapper itself never uses the heap.
+pub struct DebugAllocator<A> {
+    inner: A,
+    quarantine: Mutex<Quarantine>,
+    panic_on_error: AtomicBool,
+    errors: AtomicUsize,
+    last_error: Mutex<Option<HeapErrorReport>>,
+}
+
+unsafe impl<A: Sync> Sync for DebugAllocator<A> {}
+
+impl<A> DebugAllocator<A> {
+    #[must_use]
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            quarantine: Mutex::new(Quarantine {
+                allocations: [None; QUARANTINE_SIZE],
+                next: 0,
+            }),
+            panic_on_error: AtomicBool::new(true),
+            errors: AtomicUsize::new(0),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    /// Returns the wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Sets whether detected errors panic after being reported, which they do by default.
+    pub fn set_panic_on_error(&self, panic_on_error: bool) {
+        self.panic_on_error.store(panic_on_error, Ordering::Relaxed);
+    }
+
+    /// Returns the number of errors detected so far.
+    #[must_use]
+    pub fn error_count(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns the most recently detected error.
+    #[must_use]
+    pub fn last_error(&self) -> Option<HeapErrorReport> {
+        *self.last_error.lock()
+    }
+
+    fn report(&self, report: HeapErrorReport) {
+        serial_println!("heap error: {report}");
+        *self.last_error.lock() = Some(report);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        assert!(
+            !self.panic_on_error.load(Ordering::Relaxed),
+            "heap error: {report}"
+        );
+    }
+}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    /// Gives all quarantined allocations to the wrapped allocator, checking
+    /// that they have not been written since they were freed.
+    pub fn flush_quarantine(&self) {
+        let mut quarantine = self.quarantine.lock();
+        for slot in &mut quarantine.allocations {
+            if let Some((ptr, layout)) = slot.take() {
+                unsafe { self.release(ptr, layout) };
+            }
+        }
+    }
+
+    /// Checks the poison of a quarantined allocation, and frees it in the wrapped allocator.
+    unsafe fn release(&self, ptr: *mut u8, layout: Layout) {
+        let data = core::slice::from_raw_parts(ptr, layout.size());
+        if let Some(offset) = data.iter().position(|&byte| byte != POISON_BYTE) {
+            self.report(HeapErrorReport {
+                error: HeapError::UseAfterFree { offset },
+                ptr,
+                layout,
+            });
+        }
+        // the layout has been checked already, so it was valid for `alloc`
+        let (block_layout, prefix) = block_layout(layout).unwrap();
+        self.inner.dealloc(ptr.sub(prefix), block_layout);
+    }
+
+    /// Checks the header and the redzones of an allocation which is being freed.
+    unsafe fn check(ptr: *mut u8, layout: Layout) -> Result<(), HeapError> {
+        let header = &*header_of(ptr);
+        match header.magic {
+            ALLOCATED_MAGIC => {}
+            FREED_MAGIC => return Err(HeapError::DoubleFree),
+            _ => return Err(HeapError::InvalidFree),
+        }
+        let allocated = Layout::from_size_align(header.size, header.align)
+            .map_err(|_| HeapError::InvalidFree)?;
+        if allocated != layout {
+            return Err(HeapError::LayoutMismatch { allocated });
+        }
+
+        let front = core::slice::from_raw_parts(ptr.sub(REDZONE_SIZE), REDZONE_SIZE);
+        if let Some(index) = front.iter().position(|&byte| byte != REDZONE_BYTE) {
+            return Err(HeapError::Underflow {
+                offset: index as isize - REDZONE_SIZE as isize,
+            });
+        }
+        let back = core::slice::from_raw_parts(ptr.add(layout.size()), REDZONE_SIZE);
+        if let Some(index) = back.iter().position(|&byte| byte != REDZONE_BYTE) {
+            return Err(HeapError::Overflow {
+                offset: layout.size() + index,
+            });
+        }
+        Ok(())
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some((block_layout, prefix)) = block_layout(layout) else { return null_mut() };
+        let block = self.inner.alloc(block_layout);
+        if block.is_null() {
+            return null_mut();
+        }
+
+        let ptr = block.add(prefix);
+        header_of(ptr).write(Header {
+            magic: ALLOCATED_MAGIC,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        ptr.sub(REDZONE_SIZE)
+            .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+        ptr.add(layout.size())
+            .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(error) = Self::check(ptr, layout) {
+            self.report(HeapErrorReport { error, ptr, layout });
+            return;
+        }
+
+        (*header_of(ptr)).magic = FREED_MAGIC;
+        ptr.write_bytes(POISON_BYTE, layout.size());
+
+        let mut quarantine = self.quarantine.lock();
+        let next = quarantine.next;
+        quarantine.next = (next + 1) % QUARANTINE_SIZE;
+        if let Some((evicted, evicted_layout)) = quarantine.allocations[next].replace((ptr, layout))
+        {
+            self.release(evicted, evicted_layout);
+        }
+    }
+}
+
+/// Returns the layout of the block holding an allocation with the given layout,
+/// and the offset of the allocation in it.
+///
+/// The block starts with the header, followed by the front redzone, the
+/// allocation and the back redzone.
+fn block_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(size_of::<u64>());
+    let prefix = align_up((size_of::<Header>() + REDZONE_SIZE) as u64, align as u64) as usize;
+    let size = layout.size().checked_add(prefix + REDZONE_SIZE)?;
+    Some((Layout::from_size_align(size, align).ok()?, prefix))
+}
+
+/// Returns the header of the allocation at `ptr`, which directly precedes its front redzone.
+fn header_of(ptr: *mut u8) -> *mut Header {
+    ptr.wrapping_sub(REDZONE_SIZE + size_of::<Header>()).cast()
+}
@@ -1,6 +1,6 @@
 use core::alloc::GlobalAlloc;
 
-use super::Locked;
+use super::{HeapBackend, Locked};
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
@@ -41,14 +41,25 @@ impl Allocator {
 
     /// Allocates using the fallback allocator.
     fn fallback_alloc(&mut self, layout: core::alloc::Layout) -> *mut u8 {
-        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
-            ptr.as_ptr()
-        } else {
-            core::ptr::null_mut()
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(()) => core::ptr::null_mut(),
         }
     }
 }
 
+impl HeapBackend for Allocator {
+    const NAME: &'static str = "fixed-size-block";
+
+    unsafe fn init(&mut self, heap_start: u64, heap_size: u64) {
+        Allocator::init(self, heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, additional: u64) {
+        self.fallback_allocator.extend(additional as usize);
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<Allocator> {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
         let mut allocator = self.lock();
@@ -1,8 +1,8 @@
-use core::alloc::GlobalAlloc;
+use core::{alloc::GlobalAlloc, ops::Range};
 
 use crate::allocator::align_up;
 
-use super::Locked;
+use super::{HeapBackend, Locked};
 
 struct ListNode {
     size: u64,
@@ -26,68 +26,152 @@ impl ListNode {
     }
 }
 
+/// How the allocator picks a free region among those large enough for an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// The region with the lowest address.
+    FirstFit,
+    /// The smallest region, which keeps large regions intact.
+    BestFit,
+    /// The first region after the previous allocation, wrapping around at the
+    /// end of the list, which spreads allocations over the heap.
+    NextFit,
+}
+
+/// An allocator which keeps the free regions in a list ordered by address,
+/// merging adjacent regions whenever memory is freed.
 pub struct Allocator {
     head: ListNode,
+    heap_end: u64,
+    policy: FitPolicy,
+    /// The end of the previous allocation, where `FitPolicy::NextFit` continues.
+    next_fit_cursor: u64,
 }
 
 impl Allocator {
-    /// Creates an empty `Allocator`.
+    /// Creates an empty `Allocator` using `FitPolicy::FirstFit`.
     #[must_use]
     pub const fn new() -> Self {
+        Self::with_policy(FitPolicy::FirstFit)
+    }
+
+    /// Creates an empty `Allocator` using the given policy.
+    #[must_use]
+    pub const fn with_policy(policy: FitPolicy) -> Self {
         Self {
             head: ListNode::new(0),
+            heap_end: 0,
+            policy,
+            next_fit_cursor: 0,
         }
     }
 
     /// Initialize the allocator with the given heap bounds.
-    /// 
+    ///
     /// # Safety
     /// This function is unsafe because the caller must guarantee that the given
     /// heap bounds are valid and that the heap is unused. Also, this method must
     /// be called only once.
     pub unsafe fn init(&mut self, heap_start: u64, heap_size: u64) {
+        self.heap_end = heap_start + heap_size;
         self.add_free_region(heap_start, heap_size);
     }
 
-    /// Adds the given memory region to the front of the list.
+    #[must_use]
+    pub fn policy(&self) -> FitPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: FitPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the free regions in address order.
+    pub fn free_regions(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.regions().map(|region| region.start_addr()..region.end_addr())
+    }
+
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        core::iter::successors(self.head.next.as_deref(), |region| region.next.as_deref())
+    }
+
+    /// Inserts the given memory region into the list at its address, merging
+    /// it with the adjacent free regions.
     unsafe fn add_free_region(&mut self, addr: u64, size: u64) {
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, core::mem::align_of::<ListNode>() as u64), addr);
         assert!(size >= core::mem::size_of::<ListNode>() as u64);
 
-        // create a new list node and append to the start of list
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr);
+        // find the last region before the freed one
+        let mut current = &mut self.head;
+        while current.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+
+        let mut size = size;
+        let mut next = current.next.take();
+        if next.as_ref().is_some_and(|next| addr + size == next.start_addr()) {
+            let following = next.unwrap();
+            size += following.size;
+            next = following.next.take();
+        }
+
+        // the head is a dummy node of size 0, which must never be extended
+        if current.size > 0 && current.end_addr() == addr {
+            current.size += size;
+            current.next = next;
+        } else {
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(ListNode { size, next });
+            current.next = Some(&mut *node_ptr);
+        }
     }
 
-    /// Looks for a free region with the given size and alignment and removes
-    /// it from the list.
-    /// 
-    /// Returns a tuple of the list node and the start address of the allocation.
-    fn find_region(&mut self, size: u64, align: u64) -> Option<(&'static mut ListNode, u64)> {
+    /// Removes the region starting at the given address from the list.
+    fn remove_region(&mut self, start: u64) -> &'static mut ListNode {
         let mut current = &mut self.head;
-        
-        while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
-                let next = region.next.take();
-                let ret = (current.next.take().unwrap(), alloc_start);
-                current.next = next;
-                return Some(ret);
-            }
+        while current.next.as_ref().is_some_and(|next| next.start_addr() != start) {
             current = current.next.as_mut().unwrap();
         }
 
-        None
+        let region = current.next.take().expect("region is not in the free list");
+        current.next = region.next.take();
+        region
+    }
+
+    /// Looks for a free region with the given size and alignment according to
+    /// the policy.
+    ///
+    /// Returns the bounds of the region and the start address of the allocation.
+    fn find_region(&self, size: u64, align: u64) -> Option<(Range<u64>, u64)> {
+        let mut candidates = self.regions().filter_map(|region| {
+            let alloc_start = Self::alloc_from_region(region, size, align).ok()?;
+            Some((region.start_addr()..region.end_addr(), alloc_start))
+        });
+
+        match self.policy {
+            FitPolicy::FirstFit => candidates.next(),
+            FitPolicy::BestFit => candidates.min_by_key(|(region, _)| region.end - region.start),
+            FitPolicy::NextFit => {
+                let cursor = self.next_fit_cursor;
+                let mut wrapped = candidates.clone();
+                candidates
+                    .find(|(region, _)| region.start >= cursor)
+                    .or_else(|| wrapped.next())
+            }
+        }
     }
 
     /// Try to use the given region for an allocation with given size and alignment.
-    /// 
+    ///
     /// Returns the allocation start address on success.
     fn alloc_from_region(region: &ListNode, size: u64, align: u64) -> Result<u64, ()> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let node_size = core::mem::size_of::<ListNode>() as u64;
+        let mut alloc_start = align_up(region.start_addr(), align);
+        if alloc_start > region.start_addr() && alloc_start - region.start_addr() < node_size {
+            // the padding in front is too small to hold a ListNode
+            alloc_start = align_up(region.start_addr() + node_size, align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -96,7 +180,7 @@ impl Allocator {
         }
 
         let excess_size = region.end_addr() - alloc_end;
-        if excess_size > 0 && excess_size < core::mem::size_of::<ListNode>() as u64 {
+        if excess_size > 0 && excess_size < node_size {
             // rest of the region is too small to hold a ListNode
             return Err(());
         }
@@ -117,17 +201,34 @@ impl Allocator {
     }
 }
 
+impl HeapBackend for Allocator {
+    const NAME: &'static str = "linked-list";
+
+    unsafe fn init(&mut self, heap_start: u64, heap_size: u64) {
+        Allocator::init(self, heap_start, heap_size);
+    }
+
+    unsafe fn extend(&mut self, additional: u64) {
+        self.add_free_region(self.heap_end, additional);
+        self.heap_end += additional;
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<Allocator> {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
         let (size, align) = Allocator::size_align(layout);
         let mut allocator = self.lock();
 
         if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+            allocator.remove_region(region.start);
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
+            if alloc_start > region.start {
+                allocator.add_free_region(region.start, alloc_start - region.start);
+            }
+            if region.end > alloc_end {
+                allocator.add_free_region(alloc_end, region.end - alloc_end);
             }
+            allocator.next_fit_cursor = alloc_end;
             alloc_start as *mut u8
         } else {
             core::ptr::null_mut()
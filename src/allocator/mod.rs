@@ -1,15 +1,76 @@
-use core::{alloc::GlobalAlloc, ptr::null_mut};
+use core::{
+    alloc::GlobalAlloc,
+    ptr::null_mut,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
+use alloc::{boxed::Box, vec};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize,
+        PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
+use crate::memory::{
+    self,
+    kernel_space::{self, Region},
+};
+
+pub mod bench;
 pub mod bump;
+pub mod debug;
 pub mod fixed_size_block;
 pub mod linked_list;
+pub mod slab;
+pub mod tracking;
+
+#[cfg(not(any(
+    feature = "bump-allocator",
+    feature = "linked-list-allocator",
+    feature = "fixed-size-block-allocator"
+)))]
+compile_error!("one of the allocator backend features must be enabled");
+
+#[cfg(any(
+    all(feature = "bump-allocator", feature = "linked-list-allocator"),
+    all(feature = "bump-allocator", feature = "fixed-size-block-allocator"),
+    all(feature = "linked-list-allocator", feature = "fixed-size-block-allocator")
+))]
+compile_error!(
+    "only one allocator backend feature can be enabled, disable the default features to pick another"
+);
+
+/// The backend of the kernel heap, selected with the cargo feature of the same name.
+#[cfg(feature = "bump-allocator")]
+type SelectedBackend = bump::Allocator;
+#[cfg(feature = "linked-list-allocator")]
+type SelectedBackend = linked_list::Allocator;
+#[cfg(feature = "fixed-size-block-allocator")]
+type SelectedBackend = fixed_size_block::Allocator;
+
+/// The name of the backend of the kernel heap.
+pub const BACKEND_NAME: &str = <SelectedBackend as HeapBackend>::NAME;
+
+/// An allocator managing a contiguous heap, which can back the kernel heap.
+pub trait HeapBackend: Send {
+    const NAME: &'static str;
+
+    /// Initializes the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the given heap bounds are valid and that
+    /// the heap is unused. Also, this method must be called only once.
+    unsafe fn init(&mut self, heap_start: u64, heap_size: u64);
+
+    /// Adds the given number of bytes at the end of the heap.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the memory directly after the heap is
+    /// valid and unused.
+    unsafe fn extend(&mut self, additional: u64);
+}
 
 pub struct Stub;
 
@@ -39,50 +100,246 @@ impl<A> Locked<A> {
     }
 }
 
-pub const KERNEL_HEAP_START: u64 = 0x_4444_4444_0000; // An arbitrary value
+/// Memory of the kernel heap lent to another allocator, e.g. one under test,
+/// which is given back to the kernel heap when dropped.
+///
+/// The allocator managing the region must not be used after it is dropped.
+pub struct HeapRegion {
+    memory: Box<[u64]>,
+}
+
+impl HeapRegion {
+    /// Allocates a region of at least `size` bytes, aligned to 8 bytes.
+    #[must_use]
+    pub fn new(size: usize) -> Self {
+        Self {
+            memory: vec![0; (size + 7) / 8].into_boxed_slice(),
+        }
+    }
+
+    #[must_use]
+    pub fn start(&mut self) -> u64 {
+        self.memory.as_mut_ptr() as u64
+    }
+
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        (self.memory.len() * 8) as u64
+    }
+}
+
 pub const KERNEL_HEAP_SIZE: u64 = 100 * 1024; // 100 KiB
+/// The default ceiling up to which the kernel heap grows.
+pub const KERNEL_HEAP_MAX_SIZE: u64 = 64 * 1024 * 1024; // 64 MiB
+/// The minimum number of bytes by which the kernel heap grows at once.
+const KERNEL_HEAP_GROWTH: u64 = 64 * 1024; // 64 KiB
+
+/// The start of the kernel heap, allocated from the kernel address space by `init_kernel_heap`.
+static HEAP_START: AtomicU64 = AtomicU64::new(0);
+/// The number of bytes currently mapped for the kernel heap.
+static HEAP_SIZE: AtomicU64 = AtomicU64::new(0);
+static HEAP_SIZE_LIMIT: AtomicU64 = AtomicU64::new(KERNEL_HEAP_MAX_SIZE);
+
+/// The backend of the kernel heap, which is grown by `KernelHeap`.
+static BACKEND: Locked<SelectedBackend> = Locked::new(SelectedBackend::new());
 
 #[global_allocator]
-static ALLOCATOR: Locked<fixed_size_block::Allocator> = Locked::new(fixed_size_block::Allocator::new());
+static ALLOCATOR: TrackingLayer<DebugLayer<KernelHeap>> = tracking_layer(debug_layer(KernelHeap));
+
+/// With the `heap-debug` feature, every allocation is checked for heap corruption.
+#[cfg(feature = "heap-debug")]
+type DebugLayer<A> = debug::DebugAllocator<A>;
+#[cfg(not(feature = "heap-debug"))]
+type DebugLayer<A> = A;
 
-/// Initializes the kernel heap memory.
-/// 
+#[cfg(feature = "heap-debug")]
+const fn debug_layer<A>(allocator: A) -> DebugLayer<A> {
+    debug::DebugAllocator::new(allocator)
+}
+
+#[cfg(not(feature = "heap-debug"))]
+const fn debug_layer<A>(allocator: A) -> DebugLayer<A> {
+    allocator
+}
+
+/// With the `heap-tracking` feature, every live allocation is recorded.
+#[cfg(feature = "heap-tracking")]
+type TrackingLayer<A> = tracking::TrackingAllocator<A>;
+#[cfg(not(feature = "heap-tracking"))]
+type TrackingLayer<A> = A;
+
+#[cfg(feature = "heap-tracking")]
+const fn tracking_layer<A>(allocator: A) -> TrackingLayer<A> {
+    tracking::TrackingAllocator::new(allocator)
+}
+
+#[cfg(not(feature = "heap-tracking"))]
+const fn tracking_layer<A>(allocator: A) -> TrackingLayer<A> {
+    allocator
+}
+
+/// Returns the tracker of the kernel heap, if built with the `heap-tracking` feature.
+#[cfg(feature = "heap-tracking")]
+#[must_use]
+pub fn tracker() -> Option<&'static tracking::Tracker> {
+    Some(ALLOCATOR.tracker())
+}
+
+/// Returns the tracker of the kernel heap, if built with the `heap-tracking` feature.
+#[cfg(not(feature = "heap-tracking"))]
+#[must_use]
+pub fn tracker() -> Option<&'static tracking::Tracker> {
+    None
+}
+
+/// The innermost layer of the global allocator, which grows the heap whenever
+/// the backend runs out of memory.
+struct KernelHeap;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        loop {
+            let ptr = BACKEND.alloc(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+
+            // the new pages might not be merged with the free space at the end
+            // of the heap, so they have to fit the allocation on their own
+            let mut backend = BACKEND.lock();
+            let grown = grow_heap((layout.size() + layout.align()) as u64);
+            if grown == 0 {
+                return null_mut();
+            }
+            backend.extend(grown);
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        BACKEND.dealloc(ptr, layout);
+    }
+}
+
+/// Initializes the kernel heap memory, reserving `KERNEL_HEAP_MAX_SIZE` bytes
+/// of the heap region of the kernel address space for it.
+///
+/// The heap starts at a 2 MiB boundary, so that it can grow by 2 MiB pages.
+///
 /// # Errors
 /// The initialization might fail if the frame allocator fails
 /// to allocate enough physical memory frames for kernel heap memory
 /// or the page tables for virtual memory mappings.
+///
+/// # Panics
+/// Panics if the heap region has no room for the heap, which means that this
+/// function has been called before.
 pub fn init_kernel_heap(
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
 ) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(KERNEL_HEAP_START);
-        let heap_end = heap_start + KERNEL_HEAP_SIZE - 1u64;
-        Page::range_inclusive(
-            Page::containing_address(heap_start),
-            Page::containing_address(heap_end),
-        )
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-        }
-    }
+    let range = kernel_space::allocate(Region::Heap, KERNEL_HEAP_MAX_SIZE, Size2MiB::SIZE)
+        .expect("no room for the kernel heap");
+    let heap_start = range.start().as_u64();
+    let pages = Page::range(
+        Page::containing_address(range.start()),
+        Page::containing_address(range.start() + KERNEL_HEAP_SIZE),
+    );
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    kernel_space::map_range(mapper, frame_allocator, pages, flags)?;
 
     unsafe {
-        ALLOCATOR
-            .lock()
-            .init(KERNEL_HEAP_START, KERNEL_HEAP_SIZE);
+        BACKEND.lock().init(heap_start, KERNEL_HEAP_SIZE);
     }
+    HEAP_START.store(heap_start, Ordering::Relaxed);
+    HEAP_SIZE.store(KERNEL_HEAP_SIZE, Ordering::Relaxed);
 
     Ok(())
 }
 
+/// Returns the number of bytes currently mapped for the kernel heap.
+#[must_use]
+pub fn heap_size() -> u64 {
+    HEAP_SIZE.load(Ordering::Relaxed)
+}
+
+/// Returns the size up to which the kernel heap grows.
+#[must_use]
+pub fn heap_size_limit() -> u64 {
+    HEAP_SIZE_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Sets the size up to which the kernel heap grows, `KERNEL_HEAP_MAX_SIZE` by default.
+///
+/// Memory which is already mapped for the heap is never given back, so a
+/// limit below the current size only prevents further growth. The heap never
+/// grows beyond `KERNEL_HEAP_MAX_SIZE`, which is reserved for it.
+pub fn set_heap_size_limit(limit: u64) {
+    HEAP_SIZE_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Maps at least `additional` more bytes at the end of the kernel heap, and
+/// returns the number of bytes which have actually been mapped.
+///
+/// Once the end of the heap reaches a 2 MiB boundary, the heap grows by whole
+/// 2 MiB pages as long as the limit allows it and such frames are available,
+/// which saves page tables and TLB entries for large heaps.
+///
+/// Called with the allocator lock held, so it must not allocate. The heap can
+/// only grow after `memory::init_demand_paging`.
+fn grow_heap(additional: u64) -> u64 {
+    let size = HEAP_SIZE.load(Ordering::Relaxed);
+    // the heap cannot grow beyond the range reserved for it
+    let limit = heap_size_limit().min(KERNEL_HEAP_MAX_SIZE);
+    let additional = align_up(additional.max(KERNEL_HEAP_GROWTH), Page::<Size4KiB>::SIZE)
+        .min(limit.saturating_sub(size) & !(Page::<Size4KiB>::SIZE - 1));
+
+    let heap_end = VirtAddr::new(HEAP_START.load(Ordering::Relaxed) + size);
+    let grown = memory::with_kernel_memory(|mapper, frame_allocator| {
+        // stop at the first failure, keeping the pages mapped so far
+        let mut grown = 0;
+        while grown < additional {
+            let end = heap_end + grown;
+            let huge = end.is_aligned(Size2MiB::SIZE) && size + grown + Size2MiB::SIZE <= limit;
+            if huge && map_heap_page::<Size2MiB>(mapper, frame_allocator, end) {
+                grown += Size2MiB::SIZE;
+            } else if map_heap_page::<Size4KiB>(mapper, frame_allocator, end) {
+                grown += Size4KiB::SIZE;
+            } else {
+                break;
+            }
+        }
+        grown
+    })
+    .unwrap_or(0);
+
+    HEAP_SIZE.store(size + grown, Ordering::Relaxed);
+    grown
+}
+
+/// Maps the page of size `S` starting at `addr` to a new frame, and returns
+/// whether it has been mapped.
+fn map_heap_page<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut (impl FrameAllocator<S> + FrameAllocator<Size4KiB> + FrameDeallocator<S>),
+    addr: VirtAddr,
+) -> bool {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let page = Page::<S>::containing_address(addr);
+    let Some(frame) = FrameAllocator::<S>::allocate_frame(frame_allocator) else { return false };
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe {
+                frame_allocator.deallocate_frame(frame);
+            }
+            false
+        }
+    }
+}
+
 /// Align the given address `addr` upwards to alignment `align`.
 fn align_up(addr: u64, align: u64) -> u64 {
     (addr + align - 1) & !(align - 1)
@@ -0,0 +1,386 @@
+use core::{
+    alloc::Layout,
+    fmt,
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// The size and alignment of a slab, which is allocated from the kernel heap.
+const SLAB_SIZE: usize = 4096;
+/// The minimum number of objects in the larger slabs of objects which do not
+/// fit into a slab of `SLAB_SIZE` bytes, e.g. page tables.
+const MIN_LARGE_SLAB_OBJECTS: usize = 7;
+
+/// All caches which have been used, for reporting statistics.
+static CACHES: Mutex<Vec<&'static Mutex<RawCache>>> = Mutex::new(Vec::new());
+
+/// Statistics of a single slab cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub name: &'static str,
+    /// The size of a slot, which is the object size rounded up to its alignment.
+    pub slot_size: usize,
+    /// The size and alignment of a slab, which is `SLAB_SIZE` unless an object
+    /// does not fit into it.
+    pub slab_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub allocations: u64,
+    pub released_slabs: u64,
+}
+
+/// The header at the start of every slab, followed by its slots.
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>,
+    free: Option<NonNull<FreeSlot>>,
+    /// The number of slots which hold an object.
+    in_use: usize,
+}
+
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// The untyped part of a cache: a list of slabs holding slots of a fixed size.
+struct RawCache {
+    slabs: Option<NonNull<SlabHeader>>,
+    /// The number of slabs without any object, which are kept for reuse.
+    empty_slabs: usize,
+    first_slot_offset: usize,
+    stats: CacheStats,
+}
+
+// The slabs are only accessed with the cache lock held.
+unsafe impl Send for RawCache {}
+
+impl RawCache {
+    const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = max(align, align_of::<FreeSlot>());
+        let slot_size = align_up(max(size, size_of::<FreeSlot>()), align);
+        let first_slot_offset = align_up(size_of::<SlabHeader>(), align);
+        let slab_size = if first_slot_offset + slot_size <= SLAB_SIZE {
+            SLAB_SIZE
+        } else {
+            (first_slot_offset + MIN_LARGE_SLAB_OBJECTS * slot_size).next_power_of_two()
+        };
+        let objects_per_slab = (slab_size - first_slot_offset) / slot_size;
+        Self {
+            slabs: None,
+            empty_slabs: 0,
+            first_slot_offset,
+            stats: CacheStats {
+                name,
+                slot_size,
+                slab_size,
+                objects_per_slab,
+                slabs: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                released_slabs: 0,
+            },
+        }
+    }
+
+    fn slab_layout(&self) -> Layout {
+        Layout::from_size_align(self.stats.slab_size, self.stats.slab_size).unwrap()
+    }
+
+    /// Returns a free slot, creating a new slab if all slabs are full.
+    fn allocate(&mut self) -> Option<NonNull<u8>> {
+        let mut slab = self.slabs;
+        while let Some(mut current) = slab {
+            let header = unsafe { current.as_mut() };
+            if header.free.is_some() {
+                break;
+            }
+            slab = header.next;
+        }
+        let mut slab = match slab {
+            Some(slab) => slab,
+            None => self.create_slab()?,
+        };
+
+        let header = unsafe { slab.as_mut() };
+        let slot = header.free.take()?;
+        header.free = unsafe { slot.as_ref().next };
+        if header.in_use == 0 {
+            self.empty_slabs -= 1;
+        }
+        header.in_use += 1;
+        self.stats.objects_in_use += 1;
+        self.stats.allocations += 1;
+        Some(slot.cast())
+    }
+
+    /// Returns the slot to its slab, releasing the slab if it becomes empty
+    /// while another empty slab is kept already.
+    ///
+    /// # Safety
+    /// The slot must have been returned by `allocate` of this cache.
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        let slab_mask = !(self.stats.slab_size - 1);
+        let mut slab =
+            NonNull::new_unchecked((ptr.as_ptr() as usize & slab_mask) as *mut SlabHeader);
+        let header = slab.as_mut();
+        let mut slot = ptr.cast::<FreeSlot>();
+        slot.as_mut().next = header.free;
+        header.free = Some(slot);
+        header.in_use -= 1;
+        self.stats.objects_in_use -= 1;
+
+        if header.in_use == 0 {
+            // the slab is not counted in `empty_slabs` yet
+            if self.empty_slabs > 0 {
+                self.release_slab(slab);
+            } else {
+                self.empty_slabs += 1;
+            }
+        }
+    }
+
+    fn create_slab(&mut self) -> Option<NonNull<SlabHeader>> {
+        let slab = NonNull::new(unsafe { alloc::alloc::alloc(self.slab_layout()) })?;
+
+        let mut free = None;
+        for index in (0..self.stats.objects_per_slab).rev() {
+            let offset = self.first_slot_offset + index * self.stats.slot_size;
+            unsafe {
+                let slot = slab.as_ptr().add(offset).cast::<FreeSlot>();
+                slot.write(FreeSlot { next: free });
+                free = Some(NonNull::new_unchecked(slot));
+            }
+        }
+
+        let header = slab.cast::<SlabHeader>();
+        unsafe {
+            header.as_ptr().write(SlabHeader {
+                next: self.slabs,
+                free,
+                in_use: 0,
+            });
+        }
+        self.slabs = Some(header);
+        self.empty_slabs += 1;
+        self.stats.slabs += 1;
+        Some(header)
+    }
+
+    /// Unlinks an empty slab and gives its memory back to the kernel heap.
+    ///
+    /// Callers releasing a slab counted in `empty_slabs` update the count.
+    fn release_slab(&mut self, slab: NonNull<SlabHeader>) {
+        let mut link = &mut self.slabs;
+        while let Some(mut current) = *link {
+            if current == slab {
+                *link = unsafe { current.as_ref().next };
+                break;
+            }
+            link = unsafe { &mut current.as_mut().next };
+        }
+
+        unsafe {
+            alloc::alloc::dealloc(slab.as_ptr().cast(), self.slab_layout());
+        }
+        self.stats.slabs -= 1;
+        self.stats.released_slabs += 1;
+    }
+
+    /// Releases all empty slabs.
+    fn shrink(&mut self) -> usize {
+        let mut released = 0;
+        let mut slab = self.slabs;
+        while let Some(current) = slab {
+            let header = unsafe { current.as_ref() };
+            slab = header.next;
+            if header.in_use == 0 {
+                self.release_slab(current);
+                self.empty_slabs -= 1;
+                released += 1;
+            }
+        }
+        released
+    }
+}
+
+/// A named cache of objects of type `T`, which are packed into page-sized slabs.
+///
+/// Frequently allocated kernel objects of the same type share slabs instead of
+/// going through the general-purpose heap. Slabs which become empty are given
+/// back to the heap, except for one which is kept to avoid allocating a new
+/// slab for the next object right away.
+///
+/// Objects which do not fit into a page-sized slab, like page tables, are
+/// packed into larger slabs. The cache must not be used in interrupt context,
+/// since creating and releasing slabs uses the heap.
+pub struct SlabCache<T> {
+    raw: Mutex<RawCache>,
+    constructor: Option<fn() -> T>,
+    registered: AtomicBool,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// Creates a cache whose objects are initialized with `constructor` by `allocate`.
+    #[must_use]
+    pub const fn new(name: &'static str, constructor: fn() -> T) -> Self {
+        let mut cache = Self::without_constructor(name);
+        cache.constructor = Some(constructor);
+        cache
+    }
+
+    /// Creates a cache whose objects are only allocated with `allocate_with`.
+    #[must_use]
+    pub const fn without_constructor(name: &'static str) -> Self {
+        Self {
+            raw: Mutex::new(RawCache::new(name, size_of::<T>(), align_of::<T>())),
+            constructor: None,
+            registered: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Allocates an object initialized by the constructor of the cache.
+    ///
+    /// # Panics
+    /// Panics if the cache has no constructor or the heap is exhausted.
+    pub fn allocate(&'static self) -> SlabBox<T> {
+        let constructor = self.constructor.expect("slab cache has no constructor");
+        self.allocate_with(constructor())
+    }
+
+    /// Allocates an object holding the given value.
+    ///
+    /// # Panics
+    /// Panics if the heap is exhausted.
+    pub fn allocate_with(&'static self, value: T) -> SlabBox<T> {
+        let ptr = self.allocate_slot();
+        unsafe {
+            ptr.as_ptr().write(value);
+        }
+        SlabBox { ptr, cache: self }
+    }
+
+    /// Allocates an object whose bytes are all zero, which is not moved
+    /// through the stack, e.g. a page table.
+    ///
+    /// # Safety
+    /// All-zero bytes must be a valid value of `T`.
+    ///
+    /// # Panics
+    /// Panics if the heap is exhausted.
+    pub unsafe fn allocate_zeroed(&'static self) -> SlabBox<T> {
+        let ptr = self.allocate_slot();
+        ptr.as_ptr().write_bytes(0, 1);
+        SlabBox { ptr, cache: self }
+    }
+
+    fn allocate_slot(&'static self) -> NonNull<T> {
+        if !self.registered.swap(true, Ordering::SeqCst) {
+            CACHES.lock().push(&self.raw);
+        }
+
+        self.raw
+            .lock()
+            .allocate()
+            .expect("slab allocation failed")
+            .cast::<T>()
+    }
+
+    /// Gives all empty slabs back to the heap, and returns how many were released.
+    pub fn shrink(&self) -> usize {
+        self.raw.lock().shrink()
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> CacheStats {
+        self.raw.lock().stats
+    }
+}
+
+/// An object allocated from a `SlabCache`, which returns it to the cache on drop.
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+unsafe impl<T: Send + 'static> Send for SlabBox<T> {}
+unsafe impl<T: Sync + 'static> Sync for SlabBox<T> {}
+
+impl<T: 'static> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: 'static> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: 'static> SlabBox<T> {
+    /// Consumes the box without dropping the object, e.g. to share it with
+    /// reference counting of its own.
+    #[must_use]
+    pub fn into_raw(this: Self) -> NonNull<T> {
+        let ptr = this.ptr;
+        core::mem::forget(this);
+        ptr
+    }
+
+    /// Takes ownership of an object again which has been given up with `into_raw`.
+    ///
+    /// # Safety
+    /// The pointer must have been returned by `into_raw` of a box allocated
+    /// from the given cache, and must not be used otherwise afterwards.
+    pub unsafe fn from_raw(ptr: NonNull<T>, cache: &'static SlabCache<T>) -> Self {
+        Self { ptr, cache }
+    }
+}
+
+impl<T: fmt::Debug + 'static> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: 'static> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            self.cache.raw.lock().deallocate(self.ptr.cast());
+        }
+    }
+}
+
+/// Calls `f` with the statistics of every cache which has allocated an object.
+///
+/// `f` must not allocate from a slab cache which has not been used before.
+pub fn for_each_cache(mut f: impl FnMut(&CacheStats)) {
+    for cache in CACHES.lock().iter() {
+        f(&cache.lock().stats);
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,339 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::asm,
+    fmt,
+};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::serial_println;
+
+/// The number of allocations which can be tracked at the same time; further
+/// allocations are only counted.
+pub const MAX_TRACKED: usize = 3072;
+/// The number of return addresses recorded for every allocation.
+pub const BACKTRACE_DEPTH: usize = 3;
+
+/// The number of slots of the hash table, which is kept at most 75% full.
+const TABLE_SIZE: usize = 4096;
+/// Frames further apart than this are not considered part of the same stack
+/// when walking the frame pointers.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// A live allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationRecord {
+    pub ptr: usize,
+    pub layout: Layout,
+    /// The number of allocations tracked before this one, which tells apart
+    /// allocations reusing the same memory.
+    pub sequence: u64,
+    /// The return addresses of the innermost calls leading to the allocation,
+    /// to be resolved with `addr2line` against the kernel binary, or 0 if unknown.
+    pub callers: [usize; BACKTRACE_DEPTH],
+}
+
+impl fmt::Display for AllocationRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{} {:#x}: {} bytes aligned to {}, called from",
+            self.sequence,
+            self.ptr,
+            self.layout.size(),
+            self.layout.align()
+        )?;
+        for caller in self.callers.iter().take_while(|&&caller| caller != 0) {
+            write!(f, " {caller:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Totals of the allocations seen by a `Tracker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackingStats {
+    pub live_allocations: usize,
+    pub live_bytes: usize,
+    pub total_allocations: u64,
+    /// The number of allocations which were not recorded since `MAX_TRACKED`
+    /// allocations were live already.
+    pub untracked_allocations: u64,
+}
+
+/// A hash table of the live allocations keyed by address, using linear probing.
+struct Table {
+    slots: [Option<AllocationRecord>; TABLE_SIZE],
+    stats: TrackingStats,
+}
+
+impl Table {
+    fn home(ptr: usize) -> usize {
+        ((ptr as u64 >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 52) as usize % TABLE_SIZE
+    }
+
+    fn insert(&mut self, ptr: usize, layout: Layout, callers: [usize; BACKTRACE_DEPTH]) {
+        let sequence = self.stats.total_allocations;
+        self.stats.total_allocations += 1;
+        if self.stats.live_allocations == MAX_TRACKED {
+            self.stats.untracked_allocations += 1;
+            return;
+        }
+
+        let mut index = Self::home(ptr);
+        while self.slots[index].is_some() {
+            index = (index + 1) % TABLE_SIZE;
+        }
+        self.slots[index] = Some(AllocationRecord {
+            ptr,
+            layout,
+            sequence,
+            callers,
+        });
+        self.stats.live_allocations += 1;
+        self.stats.live_bytes += layout.size();
+    }
+
+    fn remove(&mut self, ptr: usize) {
+        let mut index = Self::home(ptr);
+        loop {
+            // allocations which were not recorded are not found
+            let Some(record) = self.slots[index] else { return };
+            if record.ptr == ptr {
+                self.stats.live_allocations -= 1;
+                self.stats.live_bytes -= record.layout.size();
+                break;
+            }
+            index = (index + 1) % TABLE_SIZE;
+        }
+
+        // move later records of the same cluster into the hole, unless that
+        // would place them in front of their home slot
+        let mut hole = index;
+        let mut next = index;
+        loop {
+            next = (next + 1) % TABLE_SIZE;
+            let Some(record) = self.slots[next] else { break };
+            let home = Self::home(record.ptr);
+            let distance_to_home = (next + TABLE_SIZE - home) % TABLE_SIZE;
+            let distance_to_hole = (next + TABLE_SIZE - hole) % TABLE_SIZE;
+            if distance_to_home >= distance_to_hole {
+                self.slots[hole] = Some(record);
+                hole = next;
+            }
+        }
+        self.slots[hole] = None;
+    }
+}
+
+/// The record of the live allocations of a `TrackingAllocator`.
+pub struct Tracker {
+    table: Mutex<Table>,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        Self {
+            table: Mutex::new(Table {
+                slots: [None; TABLE_SIZE],
+                stats: TrackingStats {
+                    live_allocations: 0,
+                    live_bytes: 0,
+                    total_allocations: 0,
+                    untracked_allocations: 0,
+                },
+            }),
+        }
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> TrackingStats {
+        self.table.lock().stats
+    }
+
+    /// Returns a copy of the records of all live allocations.
+    ///
+    /// The copy is stored on the tracked heap, so this must not be called by
+    /// the allocator itself.
+    #[must_use]
+    pub fn snapshot(&self) -> Snapshot {
+        loop {
+            let capacity = self.stats().live_allocations + 16;
+            let mut allocations: Vec<AllocationRecord> = Vec::with_capacity(capacity);
+            let table = self.table.lock();
+            if table.stats.live_allocations > capacity {
+                // more allocations have been made in the meantime
+                continue;
+            }
+
+            let buffer = allocations.as_ptr() as usize;
+            allocations.extend(
+                table
+                    .slots
+                    .iter()
+                    .flatten()
+                    .filter(|record| record.ptr != buffer),
+            );
+            let sequence = table.stats.total_allocations;
+            drop(table);
+
+            allocations.sort_unstable_by_key(|record| record.sequence);
+            return Snapshot {
+                allocations,
+                sequence,
+            };
+        }
+    }
+
+    /// Prints the allocations made after `since` which are still live over
+    /// serial, and returns how many there are.
+    pub fn report_leaks(&self, since: &Snapshot) -> usize {
+        let diff = since.diff(&self.snapshot());
+        if !diff.allocated.is_empty() {
+            let bytes: usize = diff
+                .allocated
+                .iter()
+                .map(|record| record.layout.size())
+                .sum();
+            serial_println!(
+                "leak report: {} allocations ({} bytes) still live",
+                diff.allocated.len(),
+                bytes
+            );
+            for record in &diff.allocated {
+                serial_println!("  {record}");
+            }
+        }
+        diff.allocated.len()
+    }
+}
+
+/// The live allocations at some point in time.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The live allocations, in the order they were made.
+    pub allocations: Vec<AllocationRecord>,
+    /// The number of allocations made before the snapshot was taken.
+    pub sequence: u64,
+}
+
+impl Snapshot {
+    /// Returns the allocations made and freed between this snapshot and a later one.
+    ///
+    /// The memory holding this snapshot is not reported as allocated.
+    #[must_use]
+    pub fn diff(&self, later: &Snapshot) -> SnapshotDiff {
+        let buffer = self.allocations.as_ptr() as usize;
+        let allocated = later
+            .allocations
+            .iter()
+            .filter(|record| record.sequence >= self.sequence && record.ptr != buffer)
+            .copied()
+            .collect();
+        let freed = self
+            .allocations
+            .iter()
+            .filter(|record| {
+                later
+                    .allocations
+                    .binary_search_by_key(&record.sequence, |later| later.sequence)
+                    .is_err()
+            })
+            .copied()
+            .collect();
+        SnapshotDiff { allocated, freed }
+    }
+}
+
+/// The difference between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    /// Allocations made after the earlier snapshot which are live in the later one.
+    pub allocated: Vec<AllocationRecord>,
+    /// Allocations live in the earlier snapshot which have been freed since.
+    pub freed: Vec<AllocationRecord>,
+}
+
+/// An allocator wrapper which records every live allocation of the wrapped
+/// allocator, along with the addresses it was called from.
+///
+/// The records are kept in a fixed-size table, so that tracking never uses
+/// the heap itself. Caller addresses are found by walking the frame pointers,
+/// which the kernel target keeps in `rbp`.
+pub struct TrackingAllocator<A> {
+    inner: A,
+    tracker: Tracker,
+}
+
+impl<A> TrackingAllocator<A> {
+    #[must_use]
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            tracker: Tracker::new(),
+        }
+    }
+
+    /// Returns the wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn tracker(&self) -> &Tracker {
+        &self.tracker
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            let callers = backtrace();
+            self.tracker
+                .table
+                .lock()
+                .insert(ptr as usize, layout, callers);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.tracker.table.lock().remove(ptr as usize);
+        self.inner.dealloc(ptr, layout);
+    }
+}
+
+/// Returns the return addresses of the innermost frames of the caller.
+///
+/// The walk stops at a null frame pointer, which the threads and the boot
+/// stack start with, or at one which does not look like an outer frame.
+#[inline(never)]
+fn backtrace() -> [usize; BACKTRACE_DEPTH] {
+    let mut callers = [0; BACKTRACE_DEPTH];
+    let mut frame: usize;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags));
+    }
+
+    // skip the frame of this function
+    let mut skip = 1;
+    let mut recorded = 0;
+    while recorded < BACKTRACE_DEPTH && frame != 0 && frame % 8 == 0 {
+        let (next, return_address) = unsafe {
+            let frame = frame as *const usize;
+            (*frame, *frame.add(1))
+        };
+        if skip > 0 {
+            skip -= 1;
+        } else {
+            callers[recorded] = return_address;
+            recorded += 1;
+        }
+        if next <= frame || next - frame > MAX_FRAME_SIZE {
+            break;
+        }
+        frame = next;
+    }
+    callers
+}
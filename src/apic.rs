@@ -0,0 +1,399 @@
+use core::{
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use volatile::Volatile;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{FrameAllocator, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi::madt::{Entry, Madt},
+    memory::{
+        mmio::{self, CacheMode, MmioError},
+        HugePageMapper,
+    },
+    time,
+};
+
+/// The size of the local APIC registers.
+const LAPIC_REGISTERS_SIZE: u64 = 0x400;
+
+// Local APIC register offsets
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TASK_PRIORITY: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SPURIOUS_INTERRUPT_VECTOR: u64 = 0xf0;
+const LAPIC_INTERRUPT_COMMAND_LOW: u64 = 0x300;
+const LAPIC_INTERRUPT_COMMAND_HIGH: u64 = 0x310;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: u64 = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE_CONFIGURATION: u64 = 0x3e0;
+
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LAPIC_LVT_MASKED: u32 = 1 << 16;
+const LAPIC_LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LAPIC_TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const LAPIC_IPI_DELIVERY_INIT: u32 = 0b101 << 8;
+const LAPIC_IPI_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const LAPIC_IPI_DELIVERY_PENDING: u32 = 1 << 12;
+const LAPIC_IPI_LEVEL_ASSERT: u32 = 1 << 14;
+const LAPIC_IPI_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// The size of the I/O APIC registers.
+const IOAPIC_REGISTERS_SIZE: u64 = 0x20;
+
+// I/O APIC register offsets
+const IOAPIC_REGISTER_SELECT: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const IOAPIC_ACTIVE_LOW: u64 = 1 << 13;
+const IOAPIC_LEVEL_TRIGGERED: u64 = 1 << 15;
+const IOAPIC_MASKED: u64 = 1 << 16;
+
+/// The ISA IRQ of the PIT, used to calibrate the local APIC timer.
+const PIT_IRQ: u8 = 0;
+
+/// The number of PIT ticks during which the local APIC timer is calibrated.
+const CALIBRATION_TICKS: u64 = 10;
+
+/// Virtual address of the local APIC registers, or zero if the APIC is not used.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+/// Frequency of the local APIC timer in Hz, or zero if it is not used.
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_VECTOR: AtomicU8 = AtomicU8::new(0xff);
+
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// The CPU has no local APIC, or the ACPI tables do not describe one.
+    NotPresent,
+    /// The registers cannot be mapped.
+    Mmio(MmioError),
+}
+
+impl From<MmioError> for ApicError {
+    fn from(err: MmioError) -> Self {
+        ApicError::Mmio(err)
+    }
+}
+
+struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    /// Returns the local APIC, if it has been enabled by `init`.
+    fn get() -> Option<Self> {
+        match LOCAL_APIC.load(Ordering::Acquire) {
+            0 => None,
+            base => Some(Self {
+                base: VirtAddr::new(base),
+            }),
+        }
+    }
+
+    fn register(&self, offset: u64) -> &'static mut Volatile<u32> {
+        unsafe { &mut *(self.base + offset).as_mut_ptr::<Volatile<u32>>() }
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        self.register(offset).read()
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        self.register(offset).write(value);
+    }
+
+    fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    fn enable(&self) {
+        self.write(LAPIC_TASK_PRIORITY, 0);
+        self.write(
+            LAPIC_SPURIOUS_INTERRUPT_VECTOR,
+            LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR.load(Ordering::Relaxed)),
+        );
+        self.write(LAPIC_LVT_TIMER, LAPIC_LVT_MASKED);
+    }
+
+    /// Sends an inter-processor interrupt to the local APIC with the given id
+    /// and waits until it has been delivered.
+    fn send_ipi(&self, apic_id: u8, command: u32) {
+        self.write(LAPIC_INTERRUPT_COMMAND_HIGH, u32::from(apic_id) << 24);
+        self.write(LAPIC_INTERRUPT_COMMAND_LOW, command);
+        while self.read(LAPIC_INTERRUPT_COMMAND_LOW) & LAPIC_IPI_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    fn new(base: VirtAddr, gsi_base: u32) -> Self {
+        let mut io_apic = Self {
+            base,
+            gsi_base,
+            redirection_entries: 0,
+        };
+        io_apic.redirection_entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    fn read(&mut self, register: u32) -> u32 {
+        unsafe {
+            (*(self.base + IOAPIC_REGISTER_SELECT).as_mut_ptr::<Volatile<u32>>()).write(register);
+            (*(self.base + IOAPIC_WINDOW).as_ptr::<Volatile<u32>>()).read()
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            (*(self.base + IOAPIC_REGISTER_SELECT).as_mut_ptr::<Volatile<u32>>()).write(register);
+            (*(self.base + IOAPIC_WINDOW).as_mut_ptr::<Volatile<u32>>()).write(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.redirection_entries).contains(&gsi)
+    }
+
+    fn set_redirection(&mut self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        self.write(register, entry as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+    }
+
+    fn redirection(&mut self, gsi: u32) -> u64 {
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        u64::from(self.read(register)) | u64::from(self.read(register + 1)) << 32
+    }
+}
+
+/// Returns `true` if interrupts are delivered through the APIC rather than the legacy PICs.
+#[must_use]
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Acquire) != 0
+}
+
+/// Returns the frequency of the local APIC timer in Hz, if it drives the timer interrupt.
+#[must_use]
+pub fn timer_frequency() -> Option<u64> {
+    match TIMER_FREQUENCY.load(Ordering::Acquire) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+///
+/// Must not block or allocate.
+pub(crate) fn end_of_interrupt() {
+    if let Some(local_apic) = LocalApic::get() {
+        local_apic.write(LAPIC_EOI, 0);
+    }
+}
+
+/// Maps the APIC registers, enables the local APIC and routes the given ISA
+/// IRQs to the given interrupt vectors through the I/O APIC.
+///
+/// After this function returns successfully, all interrupts must be
+/// acknowledged with `end_of_interrupt` and the legacy PICs must be masked.
+///
+/// # Errors
+/// Returns `ApicError::NotPresent` if the machine has no APIC, in which case
+/// the legacy PICs remain in use.
+pub(crate) fn init(
+    mapper: &mut impl HugePageMapper,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    spurious_vector: u8,
+    isa_routes: &[(u8, u8)],
+) -> Result<(), ApicError> {
+    let has_apic = unsafe { core::arch::x86_64::__cpuid(1) }.edx & (1 << 9) != 0;
+    let madt = Madt::find()
+        .filter(|_| has_apic)
+        .ok_or(ApicError::NotPresent)?;
+
+    // the registers stay mapped for as long as the kernel runs
+    let mut map_registers = |addr: PhysAddr, size: u64| -> Result<VirtAddr, ApicError> {
+        let mapping = unsafe {
+            mmio::ioremap_with(mapper, frame_allocator, addr, size, CacheMode::Uncached)
+        }?;
+        Ok(mapping.addr())
+    };
+
+    let local_apic = LocalApic {
+        base: map_registers(madt.local_apic_address(), LAPIC_REGISTERS_SIZE)?,
+    };
+    let mut io_apics = Vec::new();
+    for entry in madt.entries() {
+        if let Entry::IoApic {
+            address, gsi_base, ..
+        } = entry
+        {
+            io_apics.push(IoApic::new(
+                map_registers(address, IOAPIC_REGISTERS_SIZE)?,
+                gsi_base,
+            ));
+        }
+    }
+
+    SPURIOUS_VECTOR.store(spurious_vector, Ordering::Relaxed);
+    local_apic.enable();
+
+    let destination = u64::from(local_apic.id()) << 56;
+    for &(irq, vector) in isa_routes {
+        let (gsi, flags) = isa_irq_to_gsi(&madt, irq);
+        if let Some(io_apic) = io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+            io_apic.set_redirection(gsi, destination | flags | u64::from(vector));
+        }
+    }
+
+    *IO_APICS.lock() = io_apics;
+    LOCAL_APIC.store(local_apic.base.as_u64(), Ordering::Release);
+
+    Ok(())
+}
+
+/// Measures the frequency of the local APIC timer against the PIT and lets
+/// it drive the timer interrupt instead of the PIT at the current tick rate.
+///
+/// Must be called with interrupts enabled after a successful `init` which
+/// routed the PIT IRQ to the timer interrupt.
+pub(crate) fn init_timer(timer_vector: u8) {
+    let Some(local_apic) = LocalApic::get() else { return };
+
+    local_apic.write(LAPIC_TIMER_DIVIDE_CONFIGURATION, LAPIC_TIMER_DIVIDE_BY_16);
+    local_apic.write(LAPIC_LVT_TIMER, LAPIC_LVT_MASKED);
+
+    // start counting on a tick boundary
+    let start = time::ticks() + 1;
+    while time::ticks() < start {
+        x86_64::instructions::hlt();
+    }
+    let start_uptime = time::uptime();
+    local_apic.write(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+    while time::ticks() < start + CALIBRATION_TICKS {
+        x86_64::instructions::hlt();
+    }
+    let elapsed_count = u64::from(u32::MAX - local_apic.read(LAPIC_TIMER_CURRENT_COUNT));
+    let elapsed_nanos = (time::uptime() - start_uptime).as_nanos() as u64;
+    local_apic.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+
+    let frequency = elapsed_count * 1_000_000_000 / elapsed_nanos;
+
+    interrupts::without_interrupts(|| {
+        mask_isa_irq(PIT_IRQ);
+        local_apic.write(
+            LAPIC_LVT_TIMER,
+            LAPIC_LVT_TIMER_PERIODIC | u32::from(timer_vector),
+        );
+        TIMER_FREQUENCY.store(frequency, Ordering::Release);
+    });
+    time::set_tick_rate(time::tick_rate());
+}
+
+/// Enables the local APIC of the calling application processor.
+///
+/// The registers of every local APIC are mapped at the same address, so this
+/// only works after `init` has been called on the bootstrap processor.
+pub(crate) fn init_ap() {
+    if let Some(local_apic) = LocalApic::get() {
+        local_apic.enable();
+    }
+}
+
+/// Returns the id of the local APIC of the calling CPU.
+#[must_use]
+pub fn local_apic_id() -> u8 {
+    // bits 24..32 of EBX hold the initial APIC id
+    (unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24) as u8
+}
+
+/// Starts the application processor with the given local APIC id with the
+/// INIT-SIPI-SIPI sequence, letting it execute real mode code at the given
+/// page below 1 MiB.
+pub(crate) fn start_application_processor(apic_id: u8, start_page: u8) {
+    let Some(local_apic) = LocalApic::get() else { return };
+
+    local_apic.send_ipi(apic_id, LAPIC_IPI_DELIVERY_INIT | LAPIC_IPI_LEVEL_ASSERT);
+    time::busy_wait(Duration::from_millis(10));
+
+    for _ in 0..2 {
+        local_apic.send_ipi(
+            apic_id,
+            LAPIC_IPI_DELIVERY_STARTUP | LAPIC_IPI_LEVEL_ASSERT | u32::from(start_page),
+        );
+        time::busy_wait(Duration::from_micros(200));
+    }
+}
+
+/// Sends a fixed interrupt with the given vector to all CPUs except the calling one.
+///
+/// Must not block or allocate.
+pub(crate) fn broadcast_ipi(vector: u8) {
+    let Some(local_apic) = LocalApic::get() else { return };
+
+    // an interrupt handler sending an IPI must not interleave with the two register writes
+    interrupts::without_interrupts(|| {
+        local_apic.send_ipi(0, LAPIC_IPI_ALL_EXCLUDING_SELF | u32::from(vector));
+    });
+}
+
+/// Sets the number of local APIC timer cycles between two timer interrupts.
+pub(crate) fn set_timer_count(count: u32) {
+    if let Some(local_apic) = LocalApic::get() {
+        local_apic.write(LAPIC_TIMER_INITIAL_COUNT, count);
+    }
+}
+
+/// Returns the global system interrupt the given ISA IRQ is connected to,
+/// along with the polarity and trigger mode flags for its redirection entry.
+fn isa_irq_to_gsi(madt: &Madt, irq: u8) -> (u32, u64) {
+    madt.entries()
+        .find_map(|entry| match entry {
+            Entry::InterruptSourceOverride {
+                bus: 0,
+                source,
+                gsi,
+                flags,
+            } if source == irq => {
+                let mut redirection_flags = 0;
+                if flags & 0b11 == 0b11 {
+                    redirection_flags |= IOAPIC_ACTIVE_LOW;
+                }
+                if (flags >> 2) & 0b11 == 0b11 {
+                    redirection_flags |= IOAPIC_LEVEL_TRIGGERED;
+                }
+                Some((gsi, redirection_flags))
+            }
+            _ => None,
+        })
+        .unwrap_or((u32::from(irq), 0)) // ISA IRQs are identity mapped, active high and edge triggered by default
+}
+
+fn mask_isa_irq(irq: u8) {
+    let Some(madt) = Madt::find() else { return };
+    let (gsi, _) = isa_irq_to_gsi(&madt, irq);
+    let mut io_apics = IO_APICS.lock();
+    if let Some(io_apic) = io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+        let entry = io_apic.redirection(gsi);
+        io_apic.set_redirection(gsi, entry | IOAPIC_MASKED);
+    }
+}
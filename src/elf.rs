@@ -0,0 +1,167 @@
+use core::mem::size_of;
+
+use x86_64::VirtAddr;
+
+/// The first bytes of every ELF file.
+pub const MAGIC: [u8; 4] = *b"\x7fELF";
+
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const MACHINE_X86_64: u16 = 62;
+
+/// The type of an executable file, which is loaded at fixed addresses.
+pub const ET_EXEC: u16 = 2;
+
+/// The type of a program header describing a segment to be loaded.
+pub const PT_LOAD: u32 = 1;
+
+// segment flags
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// The ELF64 file header.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Header {
+    pub ident: [u8; 16],
+    pub kind: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub section_header_offset: u64,
+    pub flags: u32,
+    pub header_size: u16,
+    pub program_header_size: u16,
+    pub program_header_count: u16,
+    pub section_header_size: u16,
+    pub section_header_count: u16,
+    pub section_name_index: u16,
+}
+
+impl Header {
+    /// Returns the number of bytes from the start of the file to the end of
+    /// the program header table, or `None` if it overflows.
+    #[must_use]
+    pub fn program_headers_end(&self) -> Option<u64> {
+        u64::from(self.program_header_count)
+            .checked_mul(u64::from(self.program_header_size))?
+            .checked_add(self.program_header_offset)
+    }
+}
+
+/// An ELF64 program header, describing a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub physical_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    #[must_use]
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+
+    #[must_use]
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    #[must_use]
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    #[must_use]
+    pub fn start(&self) -> VirtAddr {
+        VirtAddr::new(self.virtual_address)
+    }
+
+    /// Returns the first address after the segment in memory.
+    #[must_use]
+    pub fn end(&self) -> VirtAddr {
+        self.start() + self.memory_size
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The data is too short to hold the header or the program header table.
+    Truncated,
+    /// The data does not start with the ELF magic.
+    NotElf,
+    /// The file is not a little endian, 64-bit file for x86-64.
+    Unsupported,
+}
+
+/// An ELF64 file for x86-64 in memory.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: Header,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the header of the given file.
+    ///
+    /// # Errors
+    /// Returns an error if the data is not an ELF64 file for x86-64, or too
+    /// short to hold its program header table.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < size_of::<Header>() {
+            return Err(ElfError::Truncated);
+        }
+        let header = unsafe { data.as_ptr().cast::<Header>().read_unaligned() };
+        if header.ident[..4] != MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if header.ident[4] != CLASS_64
+            || header.ident[5] != DATA_LITTLE_ENDIAN
+            || header.ident[6] != VERSION_CURRENT
+            || header.machine != MACHINE_X86_64
+            || usize::from(header.program_header_size) < size_of::<ProgramHeader>()
+        {
+            return Err(ElfError::Unsupported);
+        }
+        match header.program_headers_end() {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ElfError::Truncated),
+        }
+        Ok(Self { data, header })
+    }
+
+    #[must_use]
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let offset = self.header.program_header_offset as usize;
+        let size = usize::from(self.header.program_header_size);
+        (0..usize::from(self.header.program_header_count)).map(move |index| unsafe {
+            data.as_ptr()
+                .add(offset + index * size)
+                .cast::<ProgramHeader>()
+                .read_unaligned()
+        })
+    }
+
+    /// Returns the bytes of the given segment stored in the file, or `None` if
+    /// they do not lie within the file.
+    #[must_use]
+    pub fn segment_data(&self, segment: &ProgramHeader) -> Option<&'a [u8]> {
+        let start = usize::try_from(segment.offset).ok()?;
+        let len = usize::try_from(segment.file_size).ok()?;
+        self.data.get(start..start.checked_add(len)?)
+    }
+}
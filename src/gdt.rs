@@ -1,43 +1,174 @@
+use core::ptr::{addr_of, addr_of_mut};
+
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use x86_64::{
     instructions::tables::load_tss,
-    registers::segmentation::{Segment, CS},
+    registers::segmentation::{Segment, CS, DS, ES, SS},
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        paging::{FrameAllocator, FrameDeallocator, Mapper, Size4KiB},
         tss::TaskStateSegment,
     },
-    VirtAddr,
+    PrivilegeLevel, VirtAddr,
+};
+
+use crate::{
+    memory::stack::{self, KernelStack, StackError, StackOwner},
+    percpu,
 };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Page faults are handled on their own stack, so that a fault on the guard
+/// page of an overflowed stack can still be handled and reported.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+const INTERRUPT_STACK_SIZE: usize = 4096 * 5;
+
+// The order of the segments is fixed by `syscall` and `sysret`: the kernel
+// data segment must follow the kernel code segment, and the user code segment
+// must follow the user data segment.
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+/// The TSS of the bootstrap processor until `load_interrupt_stacks` replaces
+/// its stacks, which have no guard pages, by guarded ones.
+///
+/// Mutable, since `set_kernel_stack` updates the stack used on entry from user mode.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// The guarded stacks on which a CPU handles exceptions.
+#[derive(Debug)]
+pub struct InterruptStacks {
+    double_fault: KernelStack,
+    page_fault: KernelStack,
+}
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
+impl InterruptStacks {
+    /// Allocates the interrupt stacks of the given CPU with the given page table.
+    ///
+    /// # Errors
+    /// Returns an error if a stack could not be allocated.
+    pub fn allocate(
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+        cpu: usize,
+    ) -> Result<Self, StackError> {
+        let size = INTERRUPT_STACK_SIZE as u64;
+        let double_fault = stack::allocate_with(
+            mapper,
+            frame_allocator,
+            size,
+            StackOwner::DoubleFault { cpu },
+        )?;
+        let page_fault =
+            stack::allocate_with(mapper, frame_allocator, size, StackOwner::PageFault { cpu })
+                .map_err(|err| {
+                    stack::free_with(mapper, frame_allocator, double_fault)
+                        .expect("stack has just been allocated");
+                    err
+                })?;
+        Ok(Self {
+            double_fault,
+            page_fault,
+        })
+    }
+
+    fn task_state_segment(&self) -> TaskStateSegment {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = self.double_fault.top();
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = self.page_fault.top();
         tss
-    };
+    }
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, SegmentSelector, SegmentSelector) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, code_selector, tss_selector)
-    };
+    static ref GDT: GlobalDescriptorTable = new_gdt(unsafe { &*addr_of!(TSS) });
+}
+
+fn new_gdt(tss: &'static TaskStateSegment) -> GlobalDescriptorTable {
+    let mut gdt = GlobalDescriptorTable::new();
+    for (descriptor, selector) in [
+        (Descriptor::kernel_code_segment(), KERNEL_CODE_SELECTOR),
+        (Descriptor::kernel_data_segment(), KERNEL_DATA_SELECTOR),
+        (Descriptor::user_data_segment(), USER_DATA_SELECTOR),
+        (Descriptor::user_code_segment(), USER_CODE_SELECTOR),
+        (Descriptor::tss_segment(tss), TSS_SELECTOR),
+    ] {
+        assert_eq!(gdt.add_entry(descriptor), selector);
+    }
+    gdt
 }
 
+/// Loads the given GDT, and the TSS it refers to, which is remembered as the
+/// TSS of the calling CPU if its per-CPU data area has been set up.
+fn load(gdt: &'static GlobalDescriptorTable, tss: *mut TaskStateSegment) {
+    gdt.load();
+    unsafe {
+        CS::set_reg(KERNEL_CODE_SELECTOR);
+        load_tss(TSS_SELECTOR);
+    }
+    if let Some(cpu) = percpu::try_current() {
+        cpu.set_tss(tss);
+    }
+}
+
+/// Loads the GDT and TSS of the bootstrap processor.
 pub fn init() {
-    GDT.0.load();
+    let tss = unsafe { &mut *addr_of_mut!(TSS) };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: [u8; INTERRUPT_STACK_SIZE] = [0; INTERRUPT_STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        stack_start + INTERRUPT_STACK_SIZE
+    };
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: [u8; INTERRUPT_STACK_SIZE] = [0; INTERRUPT_STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        stack_start + INTERRUPT_STACK_SIZE
+    };
+    load(&GDT, unsafe { addr_of_mut!(TSS) });
+}
+
+/// Sets up and loads a new GDT and TSS for the calling CPU, which handle
+/// exceptions on the given stacks.
+///
+/// Lets the bootstrap processor replace the unguarded stacks set up by `init`
+/// once the kernel heap exists. The stacks are never freed.
+pub fn load_interrupt_stacks(stacks: InterruptStacks) {
+    let tss = Box::into_raw(Box::new(stacks.task_state_segment()));
+    load(Box::leak(Box::new(new_gdt(unsafe { &*tss }))), tss);
+}
+
+/// Sets up and loads a GDT and TSS for the calling application processor,
+/// which handle exceptions on the given stacks.
+pub fn init_ap(stacks: InterruptStacks) {
+    load_interrupt_stacks(stacks);
+
+    // the segments set up by the trampoline refer to its own GDT
+    unsafe {
+        SS::set_reg(SegmentSelector(0));
+        DS::set_reg(SegmentSelector(0));
+        ES::set_reg(SegmentSelector(0));
+    }
+}
+
+/// Sets the stack onto which the calling CPU switches when an interrupt or a
+/// system call enters the kernel from user mode.
+///
+/// # Panics
+/// Panics if the per-CPU data area of the calling CPU has not been set up
+/// before loading its GDT.
+pub fn set_kernel_stack(top: VirtAddr) {
+    let cpu = percpu::current();
+    let tss = cpu.tss();
+    assert!(!tss.is_null(), "the TSS of the CPU is unknown");
     unsafe {
-        CS::set_reg(GDT.1);
-        load_tss(GDT.2);
+        (*tss).privilege_stack_table[0] = top;
     }
+    cpu.set_kernel_stack(top);
 }
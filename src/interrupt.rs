@@ -5,10 +5,14 @@ use spin::Mutex;
 use x86_64::{
     instructions::{self, port::Port},
     registers::control::Cr2,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        paging::{FrameAllocator, Size4KiB},
+    },
+    VirtAddr,
 };
 
-use crate::{gdt, halt, println, task};
+use crate::{apic, gdt, memory, percpu, println, task, thread, time, user};
 
 const PIC_1_OFFSET: u8 = 0x20; // Primary Interrupt Controller: Interrupt vectors from 0x20 to 0x27
 const PIC_2_OFFSET: u8 = 0x28; // Secondary Interrupt Controller: Interrupt vectors from 0x28 to 0x2f
@@ -21,8 +25,20 @@ static PICS: Mutex<ChainedPics> =
 enum InterruptIndex {
     Timer = PIC_1_OFFSET,        // Line 0 of Primary Interrupt Controller
     Keyboard = PIC_1_OFFSET + 1, // Line 1 of Primary Interrupt Controller
+    Yield = 0x81,                // Software interrupt raised by `thread::yield_now`
+    Wakeup = 0x82,               // Inter-processor interrupt waking up halted CPUs
+    Spurious = 0xff,             // Spurious interrupt of the local APIC
 }
 
+/// ISA IRQs routed through the I/O APIC when the APIC replaces the legacy PICs.
+const ISA_IRQ_ROUTES: &[(u8, u8)] = &[
+    (0, InterruptIndex::Timer as u8), // PIT, until the local APIC timer takes over
+    (1, InterruptIndex::Keyboard as u8),
+];
+
+thread::context::context_switch_entry!(timer_interrupt_entry => timer_interrupt_handler);
+thread::context::context_switch_entry!(yield_interrupt_entry => yield_interrupt_handler);
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -32,9 +48,21 @@ lazy_static! {
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer as usize].set_handler_fn(timer_interrupt_handler);
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        unsafe {
+            idt[InterruptIndex::Timer as usize]
+                .set_handler_addr(VirtAddr::new(timer_interrupt_entry as usize as u64));
+            idt[InterruptIndex::Yield as usize]
+                .set_handler_addr(VirtAddr::new(yield_interrupt_entry as usize as u64));
+        }
         idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Wakeup as usize].set_handler_fn(wakeup_interrupt_handler);
+        idt[InterruptIndex::Spurious as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
     static ref KEYBOARD: Mutex<Keyboard<Us104Key, ScancodeSet1>> =
@@ -57,33 +85,88 @@ extern "x86-interrupt" fn double_fault_handler(
 }
 
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    let accessed_address = Cr2::read();
+    let Err(err) = memory::vma::handle_page_fault(accessed_address, error_code) else { return };
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        unsafe { user::abort(user::Exit::PageFault(accessed_address)) };
+    }
+    // a copy from or to user mode fails instead
+    if let Some(fixup) = memory::user_copy::fixup(stack_frame.instruction_pointer) {
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = fixup);
+        }
+        return;
+    }
 
-    halt();
+    if let memory::vma::FaultError::StackOverflow(owner) = err {
+        panic!(
+            "EXCEPTION: KERNEL STACK OVERFLOW in {:?}\nAccessed Address: {:?}\n{:#?}",
+            owner, accessed_address, stack_frame
+        );
+    }
+    panic!(
+        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\nReason: {:?}\n{:#?}",
+        accessed_address, error_code, err, stack_frame
+    );
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer as u8);
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    if stack_frame.code_segment & 0b11 != 0 {
+        unsafe { user::abort(user::Exit::GeneralProtectionFault) };
     }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "C" fn timer_interrupt_handler(stack_pointer: u64) -> u64 {
+    time::tick();
+    end_of_interrupt(InterruptIndex::Timer);
+
+    thread::scheduler::schedule(stack_pointer)
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "C" fn yield_interrupt_handler(stack_pointer: u64) -> u64 {
+    thread::scheduler::schedule(stack_pointer)
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    // waking the keyboard task uses the per-CPU data
+    let _gs = percpu::KernelGs::enter(&stack_frame);
     let mut port = Port::new(0x60); // I/O port of PS/2 controller
     let scancode: u8 = unsafe { port.read() };
 
     task::keyboard::add_scancode(scancode);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+extern "x86-interrupt" fn wakeup_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // only interrupts the `hlt` of the receiving CPU
+    end_of_interrupt(InterruptIndex::Wakeup);
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // spurious interrupts must not be acknowledged
+}
+
+/// Signals the end of the given hardware interrupt to the active interrupt controller.
+fn end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(index as u8);
+        }
     }
 }
 
@@ -93,6 +176,43 @@ pub fn init_pic() {
     }
 }
 
+/// Replaces the legacy PICs with the local APIC and I/O APIC, and lets the
+/// local APIC timer drive the timer interrupt.
+///
+/// Requires the kernel heap and `memory::init`, since the APIC is discovered
+/// through the ACPI tables and its registers need to be mapped.
+///
+/// # Errors
+/// Returns an error if the machine has no APIC or its registers could not be
+/// mapped, in which case interrupts keep being delivered by the legacy PICs.
+pub fn init_apic(
+    mapper: &mut impl memory::HugePageMapper,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), apic::ApicError> {
+    instructions::interrupts::without_interrupts(|| {
+        apic::init(
+            mapper,
+            frame_allocator,
+            InterruptIndex::Spurious as u8,
+            ISA_IRQ_ROUTES,
+        )?;
+        unsafe {
+            PICS.lock().write_masks(0xff, 0xff);
+        }
+        Ok(())
+    })?;
+
+    apic::init_timer(InterruptIndex::Timer as u8);
+    Ok(())
+}
+
+/// Sends a wakeup interrupt to all other CPUs, so that halted CPUs resume.
+///
+/// Does nothing if the APIC is not in use. Must not block or allocate.
+pub fn wake_other_cpus() {
+    apic::broadcast_ipi(InterruptIndex::Wakeup as u8);
+}
+
 pub fn enable_interrupts() {
     instructions::interrupts::enable();
 }
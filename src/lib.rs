@@ -10,21 +10,38 @@
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod elf;
 pub mod gdt;
 pub mod interrupt;
+pub mod loader;
 pub mod memory;
+pub mod percpu;
+pub mod power;
+pub mod process;
 pub mod qemu;
 pub mod serial;
+pub mod smp;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod user;
 pub mod vga_buffer;
 
 mod test;
 
 pub fn init() {
+    percpu::init(); // before the GDT, which records its TSS in the per-CPU data
     gdt::init();
+    memory::protection::enable();
+    memory::mmio::init_pat();
+    syscall::init();
     interrupt::init_idt();
     interrupt::init_pic();
+    time::init();
     interrupt::enable_interrupts();
 }
 
@@ -37,7 +54,13 @@ pub fn halt() -> ! {
 pub fn test_runner(tests: &[&dyn test::Testable]) {
     serial_println!("Running {} tests", tests.len());
     for test in tests {
+        // snapshots are stored on the heap, so leaks are only reported once it exists
+        let tracker = allocator::tracker().filter(|_| allocator::heap_size() > 0);
+        let before = tracker.map(allocator::tracking::Tracker::snapshot);
         test.run();
+        if let (Some(tracker), Some(before)) = (tracker, before) {
+            tracker.report_leaks(&before);
+        }
     }
     qemu::exit(qemu::ExitCode::Success);
 }
@@ -0,0 +1,256 @@
+use alloc::vec::Vec;
+use x86_64::{
+    structures::paging::{
+        mapper::TranslateResult, page::PageRange, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+        Translate,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    elf::{self, Elf, ElfError, ProgramHeader},
+    memory::address_space::{is_user_range, AddressSpace, AddressSpaceError},
+    process::Process,
+    user::{self, Exit},
+};
+
+// entry types of the auxiliary vector
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+
+/// The maximum number of bytes the arguments, environment and auxiliary
+/// vector may take on the initial stack, leaving the rest to the program.
+pub const MAX_ARGUMENTS_SIZE: u64 = user::STACK_SIZE / 2;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// The file is not an executable, e.g. a shared object.
+    NotExecutable,
+    /// The segment does not lie within the file, is smaller in memory than in
+    /// the file, or does not fit below the stack in the part of user mode.
+    InvalidSegment(ProgramHeader),
+    /// The entry point does not lie in an executable segment.
+    InvalidEntry(u64),
+    /// The arguments and environment exceed `MAX_ARGUMENTS_SIZE`.
+    ArgumentsTooLong,
+    AddressSpace(AddressSpaceError),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+impl From<AddressSpaceError> for LoadError {
+    fn from(err: AddressSpaceError) -> Self {
+        LoadError::AddressSpace(err)
+    }
+}
+
+/// Where a loaded program starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Image {
+    pub entry: VirtAddr,
+    /// The initial stack pointer, pointing to `argc` as the System V ABI requires.
+    pub stack_pointer: VirtAddr,
+}
+
+/// Maps the `PT_LOAD` segments of the given executable into the given address
+/// space, and sets up its stack with the given arguments and environment.
+///
+/// Segments are mapped read-only and non-executable unless their flags say
+/// otherwise, and the part of each segment beyond its file data is zeroed.
+/// A page shared by two segments is writable or executable if either of them is.
+/// The stack of `user::STACK_SIZE` bytes ends at `user::STACK_TOP`, and holds
+/// `argc`, `argv`, `envp` and the auxiliary vector like on Linux.
+///
+/// # Errors
+/// Returns an error if the file is not a valid static executable for
+/// x86-64, or the address space could not be set up.
+pub fn load(
+    address_space: &mut AddressSpace,
+    data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<Image, LoadError> {
+    let elf = Elf::parse(data)?;
+    let header = elf.header();
+    if header.kind != elf::ET_EXEC {
+        return Err(LoadError::NotExecutable);
+    }
+
+    let stack_bottom = user::STACK_TOP - user::STACK_SIZE;
+    let segments = || elf.program_headers().filter(ProgramHeader::is_load);
+    for segment in segments() {
+        let fits = segment.file_size <= segment.memory_size
+            && VirtAddr::try_new(segment.virtual_address)
+                .map_or(false, |start| is_user_range(start, segment.memory_size))
+            && segment.virtual_address + segment.memory_size <= stack_bottom;
+        if !fits || elf.segment_data(&segment).is_none() {
+            return Err(LoadError::InvalidSegment(segment));
+        }
+    }
+    let contains_entry = |segment: &ProgramHeader| {
+        segment.virtual_address <= header.entry
+            && header.entry < segment.virtual_address + segment.memory_size
+    };
+    if !segments().any(|segment| segment.is_executable() && contains_entry(&segment)) {
+        return Err(LoadError::InvalidEntry(header.entry));
+    }
+    let entry = VirtAddr::new(header.entry);
+
+    for segment in segments() {
+        let mut flags = PageTableFlags::empty();
+        if segment.is_writable() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment.is_executable() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        map_segment(address_space, &segment, flags)?;
+        let data = elf
+            .segment_data(&segment)
+            .expect("segment has been checked");
+        address_space.write(segment.start(), data)?;
+    }
+
+    address_space.map_user(
+        VirtAddr::new(stack_bottom),
+        user::STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+    let auxv = [
+        (
+            AT_PHDR,
+            program_headers_address(&elf).map_or(0, VirtAddr::as_u64),
+        ),
+        (AT_PHENT, u64::from(header.program_header_size)),
+        (AT_PHNUM, u64::from(header.program_header_count)),
+        (AT_PAGESZ, Size4KiB::SIZE),
+        (AT_ENTRY, entry.as_u64()),
+    ];
+    let stack_pointer = write_initial_stack(address_space, argv, envp, &auxv)?;
+
+    Ok(Image {
+        entry,
+        stack_pointer,
+    })
+}
+
+/// Loads the given executable into a new process, and runs it on the calling
+/// thread until it exits or faults.
+///
+/// # Errors
+/// Returns an error if the executable could not be loaded, see `load`.
+pub fn run(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<Exit, LoadError> {
+    let mut process = Process::new()?;
+    let image = load(process.address_space_mut(), data, argv, envp)?;
+    Ok(process.run(image.entry, image.stack_pointer))
+}
+
+/// Maps the pages of the given segment with the given flags.
+///
+/// Pages which an earlier segment has mapped already keep their frame and
+/// contents, and get the permissions of both segments instead.
+fn map_segment(
+    address_space: &mut AddressSpace,
+    segment: &ProgramHeader,
+    flags: PageTableFlags,
+) -> Result<(), LoadError> {
+    if segment.memory_size == 0 {
+        return Ok(());
+    }
+    let start = Page::<Size4KiB>::containing_address(segment.start());
+    let end = Page::containing_address(segment.start() + (segment.memory_size - 1)) + 1;
+
+    // the pages from `unmapped` up to the current one are not mapped yet
+    let mut unmapped = start;
+    for page in Page::range(start, end) {
+        let shared = address_space.with_mapper(|mapper, _| {
+            let TranslateResult::Mapped { flags: mapped, .. } =
+                mapper.translate(page.start_address())
+            else {
+                return false;
+            };
+            let mut merged = mapped | (flags & PageTableFlags::WRITABLE);
+            if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                merged.remove(PageTableFlags::NO_EXECUTE);
+            }
+            unsafe { mapper.update_flags(page, merged) }
+                .expect("page is mapped")
+                .flush();
+            true
+        })?;
+        if shared {
+            map_pages(address_space, Page::range(unmapped, page), flags)?;
+            unmapped = page + 1;
+        }
+    }
+    map_pages(address_space, Page::range(unmapped, end), flags)
+}
+
+fn map_pages(
+    address_space: &mut AddressSpace,
+    pages: PageRange,
+    flags: PageTableFlags,
+) -> Result<(), LoadError> {
+    let size = (pages.end - pages.start) * Size4KiB::SIZE;
+    address_space.map_user(pages.start.start_address(), size, flags)?;
+    Ok(())
+}
+
+/// Returns the address at which the program header table is loaded, if a
+/// `PT_LOAD` segment contains it.
+fn program_headers_address(elf: &Elf) -> Option<VirtAddr> {
+    let offset = elf.header().program_header_offset;
+    let end = elf.header().program_headers_end()?;
+    elf.program_headers()
+        .filter(ProgramHeader::is_load)
+        .find(|segment| segment.offset <= offset && end <= segment.offset + segment.file_size)
+        .map(|segment| segment.start() + (offset - segment.offset))
+}
+
+/// Writes the strings of the arguments and environment to the top of the
+/// stack, followed by `argc`, the pointers of `argv` and `envp` and the
+/// auxiliary vector below them, and returns the resulting stack pointer.
+fn write_initial_stack(
+    address_space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, LoadError> {
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * (auxv.len() + 1);
+    if (strings_size + words * 8 + 15) as u64 > MAX_ARGUMENTS_SIZE {
+        return Err(LoadError::ArgumentsTooLong);
+    }
+
+    let strings_start = user::STACK_TOP - strings_size as u64;
+    let mut strings = Vec::with_capacity(strings_size);
+    let mut table = Vec::with_capacity(words);
+    table.push(argv.len() as u64);
+    for list in [argv, envp] {
+        for s in list {
+            table.push(strings_start + strings.len() as u64);
+            strings.extend_from_slice(s.as_bytes());
+            strings.push(0);
+        }
+        table.push(0);
+    }
+    for &(kind, value) in auxv {
+        table.extend([kind, value]);
+    }
+    table.extend([AT_NULL, 0]);
+
+    let stack_pointer = x86_64::align_down(strings_start - (words * 8) as u64, 16);
+    let table: Vec<u8> = table.iter().flat_map(|word| word.to_le_bytes()).collect();
+    address_space.write(VirtAddr::new(strings_start), &strings)?;
+    address_space.write(VirtAddr::new(stack_pointer), &table)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
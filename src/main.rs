@@ -7,10 +7,15 @@
 
 extern crate alloc;
 
+use alloc::boxed::Box;
+
 use rust_os::{
     allocator,
-    memory::{self, BootInfoFrameAllocator},
-    task::{executor::Executor, keyboard, Task},
+    gdt::{self, InterruptStacks},
+    interrupt,
+    memory::{self, frame::BitmapFrameAllocator},
+    smp,
+    task::{keyboard, work_stealing_executor::WorkStealingExecutor, Task},
 };
 use x86_64::VirtAddr;
 
@@ -21,17 +26,34 @@ fn kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
 
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(physical_memory_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    if let Err(err) = memory::protection::remap_kernel(&mut mapper, &boot_info.memory_map) {
+        rust_os::println!("Kernel is not remapped with W^X permissions: {err:?}");
+    }
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    rust_os::println!("Kernel heap: {} allocator", allocator::BACKEND_NAME);
+    match InterruptStacks::allocate(&mut mapper, &mut frame_allocator, 0) {
+        Ok(stacks) => gdt::load_interrupt_stacks(stacks),
+        Err(err) => rust_os::println!("Interrupt stacks have no guard pages: {err:?}"),
+    }
+    if let Err(err) = interrupt::init_apic(&mut mapper, &mut frame_allocator) {
+        rust_os::println!("APIC unavailable, using legacy PICs: {err:?}");
+    }
+    match smp::init(&mut mapper, &mut frame_allocator) {
+        Ok(cpus) => rust_os::println!("{cpus} CPUs online"),
+        Err(err) => rust_os::println!("Running on the bootstrap processor only: {err:?}"),
+    }
+    memory::init_demand_paging(mapper, frame_allocator);
 
-    let mut executor = Executor::new();
+    let executor: &'static WorkStealingExecutor = Box::leak(Box::new(WorkStealingExecutor::new()));
     executor.spawn(Task::new(keyboard::print_keypress()));
     #[cfg(test)]
     executor.spawn(Task::new(async {
         test_main();
     }));
 
+    smp::run_on_application_processors(Box::leak(Box::new(|| executor.run())));
     executor.run();
 }
 
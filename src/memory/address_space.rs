@@ -0,0 +1,590 @@
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr3, Cr3Flags},
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MapToError, UnmapError},
+            page::PageRange,
+            page_table::PageTableEntry,
+            FrameAllocator, FrameDeallocator, OffsetPageTable, Page, PageSize, PageTable,
+            PageTableFlags, PageTableIndex, PhysFrame, Size4KiB, Translate,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    frame::BitmapFrameAllocator, kernel_space, phys_to_virt, vma::FaultError, with_kernel_memory,
+};
+use crate::allocator::slab::{SlabBox, SlabCache};
+
+/// The start of the part of every address space which belongs to user mode.
+pub const USER_START: u64 = 0x_1000_0000_0000;
+/// The end of the part of every address space which belongs to user mode,
+/// where the part managed by `kernel_space` starts.
+pub const USER_END: u64 = 0x_4000_0000_0000;
+
+/// Marks a writable page of user mode which is shared read-only with other
+/// address spaces since `duplicate`, and is copied on the first write.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The number of pages `map_user` maps at a time, so that mapping a large
+/// range does not keep interrupts disabled and the kernel memory locked for long.
+const MAP_CHUNK_PAGES: u64 = 512;
+
+/// Returns the level 4 entries which map the part of user mode.
+fn user_entries() -> impl Iterator<Item = PageTableIndex> {
+    let first = VirtAddr::new(USER_START).p4_index();
+    let last = VirtAddr::new(USER_END - 1).p4_index();
+    (u16::from(first)..=u16::from(last)).map(PageTableIndex::new)
+}
+
+/// Returns `true` if the given range lies in the part of user mode.
+#[must_use]
+pub fn is_user_range(start: VirtAddr, size: u64) -> bool {
+    start.as_u64() >= USER_START
+        && start
+            .as_u64()
+            .checked_add(size)
+            .map_or(false, |end| end <= USER_END)
+}
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// No kernel page table has been handed over with `init_demand_paging`.
+    NotInitialized,
+    /// The kernel maps something in the part of user mode, e.g. the
+    /// bootloader put the physical memory mapping there.
+    UserPartInUse,
+    /// The range does not lie in the part of user mode.
+    NotUserRange,
+    /// A page of the range is not mapped.
+    NotMapped,
+    /// A page of the range does not allow the access.
+    AccessDenied,
+    /// The area in which mappings are placed has no room left.
+    Exhausted,
+    MappingFailed(MapToError<Size4KiB>),
+    UnmappingFailed(UnmapError),
+}
+
+/// How `AddressSpace::duplicate` duplicates the pages of user mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateMode {
+    /// Every page is copied to a new frame right away.
+    Copy,
+    /// Every frame is shared, and writable pages are copied on the first write
+    /// to them in either address space.
+    CopyOnWrite,
+}
+
+impl From<MapToError<Size4KiB>> for AddressSpaceError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        AddressSpaceError::MappingFailed(err)
+    }
+}
+
+impl From<UnmapError> for AddressSpaceError {
+    fn from(err: UnmapError) -> Self {
+        AddressSpaceError::UnmappingFailed(err)
+    }
+}
+
+/// The level 4 tables of all address spaces.
+///
+/// The page tables of the lower levels are allocated by the mapper with the
+/// kernel memory locked, where the heap must not be used, so they are frames
+/// of the frame allocator instead.
+static PAGE_TABLES: SlabCache<PageTable> = SlabCache::without_constructor("page_table");
+
+/// A level 4 page table, which shares every mapping of the kernel and has a
+/// part of its own for user mode.
+///
+/// Only the level 4 entries are copied from the kernel page table, so mappings
+/// of the kernel must be made in level 4 entries which exist when the address
+/// space is created; the kernel heap and `kernel_space` share a single one.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    /// The memory of the level 4 table, which is accessed through `level_4_frame`.
+    _level_4_table: SlabBox<PageTable>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty part for user mode.
+    ///
+    /// # Errors
+    /// Returns an error if the kernel page table has not been handed over, or
+    /// the kernel maps something in the part of user mode.
+    ///
+    /// # Panics
+    /// Panics if the kernel heap is exhausted.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        // allocated before the kernel memory is locked, see `PAGE_TABLES`;
+        // a zeroed page table is empty
+        let mut level_4_table = unsafe { PAGE_TABLES.allocate_zeroed() };
+        let table_addr = VirtAddr::from_ptr(&*level_4_table);
+        let level_4_frame = with_kernel_memory(|mapper, _| {
+            let kernel_table = mapper.level_4_table();
+            if user_entries().any(|index| !kernel_table[index].is_unused()) {
+                return Err(AddressSpaceError::UserPartInUse);
+            }
+            for (entry, kernel_entry) in level_4_table.iter_mut().zip(kernel_table.iter()) {
+                *entry = kernel_entry.clone();
+            }
+            let table_addr = mapper
+                .translate_addr(table_addr)
+                .expect("kernel heap is not mapped");
+            Ok(PhysFrame::from_start_address(table_addr).expect("page table is not aligned"))
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized))?;
+        Ok(Self {
+            level_4_frame,
+            _level_4_table: level_4_table,
+        })
+    }
+
+    #[must_use]
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Runs the given function with a mapper of this address space and the
+    /// kernel frame allocator.
+    ///
+    /// # Errors
+    /// Returns an error if the kernel page table has not been handed over.
+    pub fn with_mapper<R>(
+        &mut self,
+        f: impl FnOnce(&mut OffsetPageTable<'_>, &mut BitmapFrameAllocator) -> R,
+    ) -> Result<R, AddressSpaceError> {
+        let level_4_frame = self.level_4_frame;
+        with_kernel_memory(|_, frame_allocator| {
+            let mut mapper = unsafe { Self::mapper(level_4_frame) };
+            f(&mut mapper, frame_allocator)
+        })
+        .ok_or(AddressSpaceError::NotInitialized)
+    }
+
+    unsafe fn mapper(level_4_frame: PhysFrame) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(table_mut(level_4_frame), phys_to_virt(PhysAddr::new(0)))
+    }
+
+    /// Creates an address space which shares the kernel part of this one and
+    /// has a duplicate of its part of user mode, like `fork`.
+    ///
+    /// With `DuplicateMode::CopyOnWrite`, the writable pages of this address
+    /// space become read-only as well until they are written to.
+    ///
+    /// # Errors
+    /// Returns an error if the kernel page table has not been handed over or
+    /// no frame is left.
+    pub fn duplicate(&mut self, mode: DuplicateMode) -> Result<Self, AddressSpaceError> {
+        let duplicate = Self::new()?;
+        let (level_4_frame, duplicate_frame) = (self.level_4_frame, duplicate.level_4_frame);
+        let result = with_kernel_memory(|_, frame_allocator| {
+            let table = unsafe { table_mut(level_4_frame) };
+            let duplicate_table = unsafe { table_mut(duplicate_frame) };
+            for index in user_entries() {
+                duplicate_entry(
+                    &mut table[index],
+                    &mut duplicate_table[index],
+                    3,
+                    mode,
+                    frame_allocator,
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized));
+        // pages which have become copy-on-write may still be writable in the TLB
+        if mode == DuplicateMode::CopyOnWrite && self.is_active() {
+            tlb::flush_all();
+        }
+        // a partial duplicate is freed when dropped
+        result.map(|()| duplicate)
+    }
+
+    /// Maps the pages of the given range in the part of user mode to zeroed
+    /// frames, with the given flags and `USER_ACCESSIBLE`.
+    ///
+    /// The kernel memory is locked for `MAP_CHUNK_PAGES` pages at a time. If a
+    /// page cannot be mapped, the pages mapped so far are unmapped again.
+    ///
+    /// # Errors
+    /// Returns an error if the range does not lie in the part of user mode, a
+    /// page is mapped already or no frame is left.
+    pub fn map_user(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        if !is_user_range(start, size) {
+            return Err(AddressSpaceError::NotUserRange);
+        }
+        if size == 0 {
+            return Ok(());
+        }
+        let pages = pages(start, size);
+        let mut chunk_start = pages.start;
+        while chunk_start < pages.end {
+            let chunk_end = (chunk_start + MAP_CHUNK_PAGES).min(pages.end);
+            let mapped = self.with_mapper(|mapper, frame_allocator| {
+                kernel_space::map_range(
+                    mapper,
+                    frame_allocator,
+                    Page::range(chunk_start, chunk_end),
+                    flags | PageTableFlags::USER_ACCESSIBLE,
+                )
+            })?;
+            if let Err(err) = mapped {
+                // the chunks before have just been mapped, so unmapping them cannot fail
+                self.with_mapper(|mapper, frame_allocator| {
+                    kernel_space::unmap_range(
+                        mapper,
+                        frame_allocator,
+                        Page::range(pages.start, chunk_start),
+                    )
+                })?
+                .expect("unmapping failed");
+                return Err(err.into());
+            }
+            chunk_start = chunk_end;
+        }
+        Ok(())
+    }
+
+    /// Unmaps the pages of the given range in the part of user mode, and gives
+    /// their frames back. Pages which are not mapped are skipped.
+    ///
+    /// # Errors
+    /// Returns an error if the range does not lie in the part of user mode.
+    pub fn unmap_user(&mut self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        if !is_user_range(start, size) {
+            return Err(AddressSpaceError::NotUserRange);
+        }
+        if size == 0 {
+            return Ok(());
+        }
+        let pages = pages(start, size);
+        self.with_mapper(|mapper, frame_allocator| {
+            kernel_space::unmap_range(mapper, frame_allocator, pages)
+        })??;
+        Ok(())
+    }
+
+    /// Checks that user mode may access every byte of the given range, i.e.
+    /// that all of its pages are mapped in the part of user mode, and are
+    /// writable or copy-on-write if `write` is set.
+    ///
+    /// # Errors
+    /// Returns an error describing the first page which denies the access.
+    pub fn check_access(
+        &self,
+        addr: VirtAddr,
+        len: u64,
+        write: bool,
+    ) -> Result<(), AddressSpaceError> {
+        if !is_user_range(addr, len) {
+            return Err(AddressSpaceError::NotUserRange);
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        let level_4_frame = self.level_4_frame;
+        with_kernel_memory(|_, _| {
+            for page in pages(addr, len) {
+                let entry = unsafe { leaf_entry(level_4_frame, page.start_address()) }
+                    .ok_or(AddressSpaceError::NotMapped)?;
+                let writable = PageTableFlags::WRITABLE | COPY_ON_WRITE;
+                if write && !entry.flags().intersects(writable) {
+                    return Err(AddressSpaceError::AccessDenied);
+                }
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized))
+    }
+
+    /// Copies the given data to the given address of user mode through the
+    /// physical memory mapping, ignoring the protection of the pages.
+    ///
+    /// Copy-on-write pages are copied first, so that other address spaces do
+    /// not see the data.
+    ///
+    /// # Errors
+    /// Returns an error if the range does not lie in the part of user mode, a
+    /// page of it is not mapped or no frame is left.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), AddressSpaceError> {
+        self.for_each_piece(addr, data.len(), true, |ptr, range| {
+            let data = &data[range];
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()) };
+        })
+    }
+
+    /// Copies data from the given address of user mode to the given buffer
+    /// through the physical memory mapping, ignoring the protection of the pages.
+    ///
+    /// # Errors
+    /// Returns an error if the range does not lie in the part of user mode or
+    /// a page of it is not mapped.
+    pub fn read(&mut self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), AddressSpaceError> {
+        self.for_each_piece(addr, buf.len(), false, |ptr, range| {
+            let buf = &mut buf[range];
+            unsafe { core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), buf.len()) };
+        })
+    }
+
+    /// Calls the given function with a pointer into the physical memory
+    /// mapping for each part of the given range which lies in a single page,
+    /// and the position of the part in the range. Copy-on-write pages are
+    /// copied first if `writing` is set.
+    fn for_each_piece(
+        &mut self,
+        addr: VirtAddr,
+        len: usize,
+        writing: bool,
+        mut f: impl FnMut(*mut u8, core::ops::Range<usize>),
+    ) -> Result<(), AddressSpaceError> {
+        if !is_user_range(addr, len as u64) {
+            return Err(AddressSpaceError::NotUserRange);
+        }
+        let level_4_frame = self.level_4_frame;
+        let active = self.is_active();
+        with_kernel_memory(|_, frame_allocator| {
+            let mut done = 0;
+            while done < len {
+                let addr = addr + done as u64;
+                let entry = unsafe { leaf_entry(level_4_frame, addr) }
+                    .ok_or(AddressSpaceError::NotMapped)?;
+                if writing && entry.flags().contains(COPY_ON_WRITE) {
+                    unshare(entry, frame_allocator)?;
+                    if active {
+                        tlb::flush(addr);
+                    }
+                }
+                let offset = addr.as_u64() % Size4KiB::SIZE;
+                let piece = (Size4KiB::SIZE - offset).min((len - done) as u64) as usize;
+                f(
+                    phys_to_virt(entry.addr() + offset).as_mut_ptr(),
+                    done..done + piece,
+                );
+                done += piece;
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized))
+    }
+
+    /// Returns `true` if this address space is loaded in CR3 of the calling CPU.
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads this address space in CR3 of the calling CPU, and returns the
+    /// level 4 frame which was loaded before.
+    ///
+    /// # Safety
+    /// The address space must stay alive while it is loaded, and the caller must
+    /// not rely on mappings of the part of user mode of the previous one.
+    pub unsafe fn activate(&self) -> PhysFrame {
+        let (previous, _) = Cr3::read();
+        if previous != self.level_4_frame {
+            Cr3::write(self.level_4_frame, Cr3Flags::empty());
+        }
+        previous
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Gives the frames of the part of user mode and its page tables back to
+    /// the kernel frame allocator, and the level 4 table to `PAGE_TABLES`
+    /// once the kernel memory is unlocked again.
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "address space is still active");
+        let level_4_frame = self.level_4_frame;
+        with_kernel_memory(|_, frame_allocator| {
+            let table = unsafe { table_mut(level_4_frame) };
+            for index in user_entries() {
+                free_table(&mut table[index], 3, frame_allocator);
+            }
+        })
+        .expect("kernel memory is not initialized");
+    }
+}
+
+/// Gives the frames mapped through the given entry back to the frame
+/// allocator, including the page tables down from the given level.
+///
+/// The part of user mode only maps 4 KiB pages.
+fn free_table(
+    entry: &mut PageTableEntry,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    if entry.is_unused() {
+        return;
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    if level > 0 {
+        let table = unsafe { table_mut(frame) };
+        for entry in table.iter_mut() {
+            free_table(entry, level - 1, frame_allocator);
+        }
+    }
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    entry.set_unused();
+}
+
+/// Returns the pages containing the given non-empty range.
+fn pages(start: VirtAddr, size: u64) -> PageRange {
+    Page::range(
+        Page::containing_address(start),
+        Page::containing_address(start + (size - 1)) + 1,
+    )
+}
+
+/// Duplicates the given entry of user mode into the empty entry of another
+/// address space, including the page tables down from the given level.
+fn duplicate_entry(
+    entry: &mut PageTableEntry,
+    duplicate: &mut PageTableEntry,
+    level: u8,
+    mode: DuplicateMode,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), AddressSpaceError> {
+    if entry.is_unused() {
+        return Ok(());
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    if level > 0 {
+        let table_frame = allocate_copy(None, frame_allocator)?;
+        duplicate.set_addr(table_frame.start_address(), entry.flags());
+        let (table, duplicate_table) = unsafe { (table_mut(frame), table_mut(table_frame)) };
+        for (entry, duplicate) in table.iter_mut().zip(duplicate_table.iter_mut()) {
+            duplicate_entry(entry, duplicate, level - 1, mode, frame_allocator)?;
+        }
+        return Ok(());
+    }
+
+    match mode {
+        DuplicateMode::Copy => {
+            let copy = allocate_copy(Some(frame), frame_allocator)?;
+            duplicate.set_addr(copy.start_address(), entry.flags());
+        }
+        DuplicateMode::CopyOnWrite => {
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                entry.set_flags(flags);
+            }
+            frame_allocator.share(frame);
+            duplicate.set_addr(frame.start_address(), flags);
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a write to a copy-on-write page of user mode in the address space
+/// loaded in CR3, by giving the page a frame of its own.
+///
+/// Called by the page fault handler for protection violations, so it must not
+/// allocate.
+pub(crate) fn resolve_copy_on_write(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), FaultError> {
+    if !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        return Err(FaultError::AccessViolation);
+    }
+    with_kernel_memory(|_, frame_allocator| {
+        let (level_4_frame, _) = Cr3::read();
+        let entry = unsafe { leaf_entry(level_4_frame, addr) }.ok_or(FaultError::Unmapped)?;
+        if !entry.flags().contains(COPY_ON_WRITE) {
+            return Err(FaultError::AccessViolation);
+        }
+        unshare(entry, frame_allocator).map_err(FaultError::MappingFailed)?;
+        tlb::flush(addr);
+        Ok(())
+    })
+    .unwrap_or(Err(FaultError::NotInitialized))
+}
+
+/// Makes the page of the given copy-on-write entry writable, copying its frame
+/// first unless no other address space shares it anymore.
+fn unshare(
+    entry: &mut PageTableEntry,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = PhysFrame::containing_address(entry.addr());
+    let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if frame_allocator.is_shared(frame) {
+        let copy = allocate_copy(Some(frame), frame_allocator)?;
+        entry.set_addr(copy.start_address(), flags);
+        // drops the share of this address space
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    } else {
+        entry.set_flags(flags);
+    }
+    Ok(())
+}
+
+/// Allocates a frame holding a copy of the given frame, or zeroes if none is given.
+fn allocate_copy(
+    frame: Option<PhysFrame>,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<PhysFrame, MapToError<Size4KiB>> {
+    let copy: PhysFrame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let dst = phys_to_virt(copy.start_address()).as_mut_ptr::<u8>();
+    unsafe {
+        match frame {
+            Some(frame) => core::ptr::copy_nonoverlapping(
+                phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                dst,
+                Size4KiB::SIZE as usize,
+            ),
+            None => dst.write_bytes(0, Size4KiB::SIZE as usize),
+        }
+    }
+    Ok(copy)
+}
+
+/// Returns the present level 1 entry which maps the given address in the
+/// given level 4 table.
+///
+/// # Safety
+/// The caller must not create other references to the entry.
+unsafe fn leaf_entry<'a>(
+    level_4_frame: PhysFrame,
+    addr: VirtAddr,
+) -> Option<&'a mut PageTableEntry> {
+    let mut table = table_mut(level_4_frame);
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT)
+            || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+        {
+            return None;
+        }
+        table = table_mut(PhysFrame::containing_address(entry.addr()));
+    }
+    let entry = &mut table[addr.p1_index()];
+    entry
+        .flags()
+        .contains(PageTableFlags::PRESENT)
+        .then_some(entry)
+}
+
+/// Returns the page table stored in the given frame, through the physical memory mapping.
+///
+/// # Safety
+/// The frame must hold a page table, and the caller must not create other
+/// references to it.
+unsafe fn table_mut<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
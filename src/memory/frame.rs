@@ -0,0 +1,315 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB,
+        Size2MiB, Size4KiB,
+    },
+    PhysAddr,
+};
+
+use super::phys_to_virt;
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Numbers of usable frames, as reported by `BitmapFrameAllocator::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// The number of frames marked usable in the memory map, excluding those
+    /// holding the bitmap of the allocator.
+    pub total: usize,
+    pub free: usize,
+}
+
+impl FrameStats {
+    #[must_use]
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+}
+
+/// A frame allocator which tracks every physical frame with one bit.
+///
+/// The bitmap is stored in the first usable region large enough to hold it,
+/// and accessed through the complete physical memory mapping. A set bit
+/// means that the frame is in use or not usable at all.
+///
+/// A frame can have several owners, e.g. address spaces which map it
+/// copy-on-write, see `share`. It is only freed once every owner has
+/// deallocated it.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// The number of owners of each frame beyond the first one, stored behind the bitmap.
+    shares: &'static mut [u16],
+    /// The number of frames covered by the bitmap, starting at physical address 0.
+    frame_count: usize,
+    stats: FrameStats,
+    /// The frame at which the search for a free frame starts.
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a `BitmapFrameAllocator` from the passed memory map.
+    ///
+    /// # Safety
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. The main requirement is that all frames that are marked
+    /// as `USABLE` in it are really unused. Also, `memory::init` must have been
+    /// called, since the bitmap is accessed through the physical memory mapping.
+    ///
+    /// # Panics
+    /// Panics if no usable region is large enough to hold the bitmap.
+    #[must_use]
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| {
+                    (r.range.start_addr() / FRAME_SIZE) as usize
+                        ..(r.range.end_addr() / FRAME_SIZE) as usize
+                })
+        };
+
+        let frame_count = usable_regions().map(|r| r.end).max().unwrap_or(0);
+        let words = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_frames = (words * 8 + frame_count * 2).div_ceil(FRAME_SIZE as usize);
+        let bitmap_start = usable_regions()
+            .map(|r| r.start.max(1)..r.end) // frame 0 is never handed out
+            .find(|r| r.len() >= bitmap_frames)
+            .expect("no usable region can hold the frame bitmap")
+            .start;
+
+        let bitmap_ptr =
+            phys_to_virt(PhysAddr::new(bitmap_start as u64 * FRAME_SIZE)).as_mut_ptr::<u64>();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+        bitmap.fill(u64::MAX);
+        let shares =
+            core::slice::from_raw_parts_mut(bitmap_ptr.add(words).cast::<u16>(), frame_count);
+        shares.fill(0);
+
+        let mut allocator = Self {
+            bitmap,
+            shares,
+            frame_count,
+            stats: FrameStats { total: 0, free: 0 },
+            next: 0,
+        };
+        for region in usable_regions() {
+            for frame in region {
+                if frame != 0 {
+                    allocator.set_used(frame, false);
+                }
+            }
+        }
+        for frame in bitmap_start..bitmap_start + bitmap_frames {
+            allocator.set_used(frame, true);
+        }
+        allocator.stats.total = allocator.stats.free;
+        allocator
+    }
+
+    /// Returns the number of usable and free frames.
+    #[must_use]
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Adds an owner to an allocated frame, which then stays allocated until
+    /// `deallocate_frame` has been called once more.
+    ///
+    /// # Panics
+    /// Panics if the frame is free or has `u16::MAX` additional owners already.
+    pub fn share(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = index_of(frame);
+        assert!(
+            index < self.frame_count && self.is_used(index),
+            "frame {frame:?} is free"
+        );
+        self.shares[index] = self.shares[index]
+            .checked_add(1)
+            .expect("frame has too many owners");
+    }
+
+    /// Returns `true` if the frame has more than one owner.
+    #[must_use]
+    pub fn is_shared(&self, frame: PhysFrame<Size4KiB>) -> bool {
+        self.shares
+            .get(index_of(frame))
+            .map_or(false, |&shares| shares > 0)
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        let word = &mut self.bitmap[frame / BITS_PER_WORD];
+        let bit = 1 << (frame % BITS_PER_WORD);
+        if used {
+            *word |= bit;
+            self.stats.free -= 1;
+        } else {
+            *word &= !bit;
+            self.stats.free += 1;
+        }
+    }
+
+    /// Returns the first free frame at or after `start`, skipping full words at once.
+    fn find_free(&self, start: usize) -> Option<usize> {
+        let mut frame = start;
+        while frame < self.frame_count {
+            let word = self.bitmap[frame / BITS_PER_WORD] | ((1 << (frame % BITS_PER_WORD)) - 1);
+            if word == u64::MAX {
+                frame = (frame / BITS_PER_WORD + 1) * BITS_PER_WORD;
+            } else {
+                frame = frame / BITS_PER_WORD * BITS_PER_WORD + word.trailing_ones() as usize;
+                return Some(frame).filter(|&frame| frame < self.frame_count);
+            }
+        }
+        None
+    }
+
+    /// Allocates `count` physically contiguous frames, the first of which is
+    /// aligned to `align` frames.
+    ///
+    /// # Panics
+    /// Panics if `align` is not a power of two.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        self.allocate_contiguous_before(count, align, self.frame_count)
+    }
+
+    /// Allocates `count` physically contiguous frames like `allocate_contiguous`,
+    /// all of which end at or below `limit`, e.g. for devices which can only
+    /// address low memory.
+    ///
+    /// # Panics
+    /// Panics if `align` is not a power of two.
+    pub fn allocate_contiguous_below(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange> {
+        let end = (limit.as_u64() / FRAME_SIZE) as usize;
+        self.allocate_contiguous_before(count, align, end.min(self.frame_count))
+    }
+
+    /// Allocates `count` contiguous frames, aligned to `align` frames, before
+    /// the frame with index `end`.
+    fn allocate_contiguous_before(
+        &mut self,
+        count: usize,
+        align: usize,
+        end: usize,
+    ) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+
+        let mut start = self.find_free(0)?;
+        loop {
+            start = (start + align - 1) & !(align - 1);
+            if start + count > end {
+                return None;
+            }
+            match (start..start + count).find(|&frame| self.is_used(frame)) {
+                Some(used) => start = self.find_free(used + 1)?,
+                None => break,
+            }
+        }
+
+        for frame in start..start + count {
+            self.set_used(frame, true);
+        }
+        let start = frame_at(start);
+        Some(PhysFrame::range(start, start + count as u64))
+    }
+
+    /// Frees frames allocated with `allocate_contiguous`.
+    ///
+    /// # Safety
+    /// The caller must ensure that the frames are unused.
+    pub unsafe fn deallocate_contiguous(&mut self, frames: PhysFrameRange) {
+        for frame in frames {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    /// Allocates a frame of size `S` as naturally aligned, contiguous 4 KiB frames.
+    fn allocate_huge_frame<S: PageSize>(&mut self) -> Option<PhysFrame<S>> {
+        let count = frames_per::<S>();
+        let frames = self.allocate_contiguous(count, count)?;
+        Some(PhysFrame::containing_address(frames.start.start_address()))
+    }
+
+    unsafe fn deallocate_huge_frame<S: PageSize>(&mut self, frame: PhysFrame<S>) {
+        let start = index_of(frame);
+        for index in start..start + frames_per::<S>() {
+            self.deallocate_frame(frame_at(index));
+        }
+    }
+}
+
+/// Returns the number of 4 KiB frames in a frame of size `S`.
+fn frames_per<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE) as usize
+}
+
+fn frame_at(index: usize) -> PhysFrame<Size4KiB> {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn index_of<S: PageSize>(frame: PhysFrame<S>) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = self.find_free(self.next).or_else(|| self.find_free(0))?;
+        self.set_used(frame, true);
+        self.next = frame + 1;
+        Some(frame_at(frame))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    /// Removes an owner of the frame, and frees it if it was the last one.
+    ///
+    /// # Panics
+    /// Panics if the frame is already free, which means it was freed twice.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = index_of(frame);
+        assert!(
+            index < self.frame_count && self.is_used(index),
+            "frame {frame:?} is already free"
+        );
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+            return;
+        }
+        self.set_used(index, false);
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_huge_frame()
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate_huge_frame(frame);
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate_huge_frame()
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        self.deallocate_huge_frame(frame);
+    }
+}
@@ -0,0 +1,454 @@
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+        page::PageRange,
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame,
+        Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{has_1gib_pages, phys_to_virt, HugePageMapper};
+
+/// The start of the part of the address space managed here.
+const KERNEL_SPACE_START: u64 = 0x_4000_0000_0000;
+/// The size of every region.
+const REGION_SIZE: u64 = 0x_10_0000_0000; // 64 GiB
+
+/// The maximum number of ranges that can be allocated at the same time.
+const MAX_RANGES: usize = 128;
+
+/// The allocated ranges, in no particular order.
+///
+/// Ranges are allocated before the kernel heap exists, so the registry has a
+/// fixed capacity instead of living on the heap.
+static RANGES: Mutex<[Option<KernelRange>; MAX_RANGES]> = Mutex::new([None; MAX_RANGES]);
+
+/// The parts of the kernel address space, each of which holds one kind of mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Heap,
+    Stacks,
+    Mmio,
+    PerCpu,
+}
+
+impl Region {
+    pub const ALL: [Region; 4] = [Self::Heap, Self::Stacks, Self::Mmio, Self::PerCpu];
+
+    #[must_use]
+    pub fn start(self) -> VirtAddr {
+        VirtAddr::new(KERNEL_SPACE_START + self as u64 * REGION_SIZE)
+    }
+
+    /// Returns the first address after the region.
+    #[must_use]
+    pub fn end(self) -> VirtAddr {
+        self.start() + REGION_SIZE
+    }
+
+    /// Returns the region containing the given address.
+    #[must_use]
+    pub fn containing(addr: VirtAddr) -> Option<Region> {
+        Self::ALL
+            .into_iter()
+            .find(|region| region.start() <= addr && addr < region.end())
+    }
+}
+
+/// A page-aligned range of kernel virtual addresses handed out by `allocate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelRange {
+    region: Region,
+    start: VirtAddr,
+    size: u64,
+}
+
+impl KernelRange {
+    #[must_use]
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    #[must_use]
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Returns the first address after the range.
+    #[must_use]
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    #[must_use]
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Returns the pages of size `S` covering the range.
+    ///
+    /// # Panics
+    /// Panics if the range is not aligned to `S`.
+    #[must_use]
+    pub fn pages<S: PageSize>(&self) -> PageRange<S> {
+        let start =
+            Page::from_start_address(self.start).expect("range is not aligned to the page size");
+        let end =
+            Page::from_start_address(self.end()).expect("range is not aligned to the page size");
+        Page::range(start, end)
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start < end && start < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceError {
+    /// No free part of the region is large enough.
+    OutOfSpace,
+    /// `MAX_RANGES` ranges are allocated already.
+    TooManyRanges,
+    /// The range has not been allocated, or has been freed already.
+    NotAllocated,
+}
+
+/// Allocates a range of at least `size` bytes in the given region, aligned to
+/// `align` bytes, which does not overlap with any other allocated range.
+///
+/// The range is not mapped; see `map_range` and `map_physical_range`.
+///
+/// # Errors
+/// Returns an error if the region has no room for the range or the registry is full.
+///
+/// # Panics
+/// Panics if `align` is not a power of two.
+pub fn allocate(region: Region, size: u64, align: u64) -> Result<KernelRange, SpaceError> {
+    assert!(align.is_power_of_two(), "alignment must be a power of two");
+    let align = align.max(Size4KiB::SIZE);
+    let size = x86_64::align_up(size.max(1), Size4KiB::SIZE);
+
+    interrupts::without_interrupts(|| {
+        let mut ranges = RANGES.lock();
+        let slot = ranges
+            .iter()
+            .position(Option::is_none)
+            .ok_or(SpaceError::TooManyRanges)?;
+
+        // first fit: move past every allocated range overlapping the candidate
+        let mut start = region.start().align_up(align);
+        while let Some(other) = ranges
+            .iter()
+            .flatten()
+            .find(|other| other.overlaps(start, start + size))
+        {
+            start = other.end().align_up(align);
+        }
+        if start + size > region.end() {
+            return Err(SpaceError::OutOfSpace);
+        }
+
+        let range = KernelRange {
+            region,
+            start,
+            size,
+        };
+        ranges[slot] = Some(range);
+        Ok(range)
+    })
+}
+
+/// Gives a range back, so that it can be allocated again.
+///
+/// Pages of the range which are still mapped stay mapped; unmapping them is up to the caller.
+///
+/// # Errors
+/// Returns `SpaceError::NotAllocated` if the range is not allocated.
+pub fn free(range: KernelRange) -> Result<(), SpaceError> {
+    interrupts::without_interrupts(|| {
+        let mut ranges = RANGES.lock();
+        let slot = ranges
+            .iter_mut()
+            .find(|slot| **slot == Some(range))
+            .ok_or(SpaceError::NotAllocated)?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+/// Returns the allocated range containing the given address.
+#[must_use]
+pub fn find(addr: VirtAddr) -> Option<KernelRange> {
+    interrupts::without_interrupts(|| {
+        RANGES
+            .lock()
+            .iter()
+            .flatten()
+            .find(|range| range.contains(addr))
+            .copied()
+    })
+}
+
+/// Maps every page to a newly allocated, zeroed frame, with the given flags
+/// and `PRESENT`.
+///
+/// If a page cannot be mapped, the pages mapped so far are unmapped again.
+///
+/// # Errors
+/// Returns an error if a frame cannot be allocated or a page is mapped already.
+pub fn map_range<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut (impl FrameAllocator<S> + FrameAllocator<Size4KiB> + FrameDeallocator<S>),
+    pages: PageRange<S>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<S>> {
+    for page in pages {
+        let result = FrameAllocator::<S>::allocate_frame(frame_allocator)
+            .ok_or(MapToError::FrameAllocationFailed)
+            .and_then(|frame| {
+                unsafe {
+                    phys_to_virt(frame.start_address())
+                        .as_mut_ptr::<u8>()
+                        .write_bytes(0, S::SIZE as usize);
+                }
+                let mapped = unsafe {
+                    mapper.map_to(
+                        page,
+                        frame,
+                        flags | PageTableFlags::PRESENT,
+                        frame_allocator,
+                    )
+                };
+                if mapped.is_err() {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                mapped
+            });
+
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                // the pages before have just been mapped, so unmapping them cannot fail
+                unmap_range(mapper, frame_allocator, Page::range(pages.start, page))
+                    .expect("unmapping failed");
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Maps the pages to the physically contiguous frames starting at
+/// `start_frame`, e.g. device memory, with the given flags and `PRESENT`.
+///
+/// The frames are not owned by the mapping, so they are not given back when
+/// unmapping the pages with `unmap_physical_range`. If a page cannot be mapped,
+/// the pages mapped so far are unmapped again.
+///
+/// # Errors
+/// Returns an error if a page is mapped already or a page table cannot be allocated.
+pub fn map_physical_range<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    pages: PageRange<S>,
+    start_frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<S>> {
+    for (index, page) in pages.enumerate() {
+        let frame = start_frame + index as u64;
+        match unsafe {
+            mapper.map_to(
+                page,
+                frame,
+                flags | PageTableFlags::PRESENT,
+                frame_allocator,
+            )
+        } {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                unmap_physical_range(mapper, Page::range(pages.start, page))
+                    .expect("unmapping failed");
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps every mapped page, and gives the frames back to the frame allocator.
+///
+/// Pages which are not mapped are skipped.
+///
+/// # Errors
+/// Returns an error if a page is part of a larger page.
+pub fn unmap_range<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut impl FrameDeallocator<S>,
+    pages: PageRange<S>,
+) -> Result<(), UnmapError> {
+    for page in pages {
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Unmaps every mapped page, keeping the frames, which are owned by someone else.
+///
+/// Pages which are not mapped are skipped.
+///
+/// # Errors
+/// Returns an error if a page is part of a larger page.
+pub fn unmap_physical_range<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    pages: PageRange<S>,
+) -> Result<(), UnmapError> {
+    for page in pages {
+        match mapper.unmap(page) {
+            Ok((_, flush)) => flush.flush(),
+            Err(UnmapError::PageNotMapped) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the size of the largest page which maps `addr` to `phys` and fits
+/// into `size` bytes.
+#[must_use]
+pub fn largest_page_size(addr: VirtAddr, phys: PhysAddr, size: u64) -> u64 {
+    let fits = |page_size: u64| {
+        addr.is_aligned(page_size) && phys.is_aligned(page_size) && size >= page_size
+    };
+    if has_1gib_pages() && fits(Size1GiB::SIZE) {
+        Size1GiB::SIZE
+    } else if fits(Size2MiB::SIZE) {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    }
+}
+
+/// Maps `size` bytes starting at `start` to the physically contiguous memory
+/// starting at `phys`, with the given flags and `PRESENT`, using the largest
+/// pages for which both addresses are aligned.
+///
+/// Like with `map_physical_range`, the memory is not owned by the mapping.
+/// If a page cannot be mapped, the pages mapped so far are unmapped again.
+///
+/// # Errors
+/// Returns an error if a page is mapped already or a page table cannot be
+/// allocated. Errors of huge pages are reported for the 4 KiB frame at their start.
+///
+/// # Panics
+/// Panics if `start`, `phys` or `size` is not aligned to 4 KiB.
+pub fn map_physical_huge(
+    mapper: &mut impl HugePageMapper,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    start: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(
+        start.is_aligned(Size4KiB::SIZE)
+            && phys.is_aligned(Size4KiB::SIZE)
+            && size % Size4KiB::SIZE == 0,
+        "range is not aligned to the page size"
+    );
+
+    let flags = flags | PageTableFlags::PRESENT;
+    let mut offset = 0;
+    while offset < size {
+        let (addr, phys) = (start + offset, phys + offset);
+        let page_size = largest_page_size(addr, phys, size - offset);
+        let result = match page_size {
+            Size1GiB::SIZE => map_page::<Size1GiB>(mapper, frame_allocator, addr, phys, flags),
+            Size2MiB::SIZE => map_page::<Size2MiB>(mapper, frame_allocator, addr, phys, flags),
+            _ => map_page::<Size4KiB>(mapper, frame_allocator, addr, phys, flags),
+        };
+        if let Err(err) = result {
+            unmap_physical_huge(mapper, start, offset).expect("unmapping failed");
+            return Err(err);
+        }
+        offset += page_size;
+    }
+    Ok(())
+}
+
+/// Unmaps every mapped page of `size` bytes starting at `start`, whatever its
+/// size, keeping the frames, which are owned by someone else.
+///
+/// Pages which are not mapped are skipped. Huge pages which extend beyond the
+/// range are unmapped as a whole.
+///
+/// # Errors
+/// Returns an error if a page table entry points to an invalid frame.
+pub fn unmap_physical_huge(
+    mapper: &mut impl HugePageMapper,
+    start: VirtAddr,
+    size: u64,
+) -> Result<(), UnmapError> {
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        let page_size = match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => {
+                match frame {
+                    MappedFrame::Size4KiB(_) => unmap_page::<Size4KiB>(mapper, addr)?,
+                    MappedFrame::Size2MiB(_) => unmap_page::<Size2MiB>(mapper, addr)?,
+                    MappedFrame::Size1GiB(_) => unmap_page::<Size1GiB>(mapper, addr)?,
+                }
+                frame.size()
+            }
+            TranslateResult::NotMapped => Size4KiB::SIZE,
+            TranslateResult::InvalidFrameAddress(phys) => {
+                return Err(UnmapError::InvalidFrameAddress(phys))
+            }
+        };
+        addr = addr.align_down(page_size) + page_size;
+    }
+    Ok(())
+}
+
+fn map_page<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    addr: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::<S>::containing_address(addr);
+    let frame = PhysFrame::<S>::containing_address(phys);
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::FrameAllocationFailed) => Err(MapToError::FrameAllocationFailed),
+        Err(MapToError::ParentEntryHugePage) => Err(MapToError::ParentEntryHugePage),
+        Err(MapToError::PageAlreadyMapped(frame)) => Err(MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(frame.start_address()),
+        )),
+    }
+}
+
+fn unmap_page<S: PageSize>(mapper: &mut impl Mapper<S>, addr: VirtAddr) -> Result<(), UnmapError> {
+    let (_, flush) = mapper.unmap(Page::<S>::containing_address(addr))?;
+    flush.flush();
+    Ok(())
+}
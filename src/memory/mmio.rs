@@ -0,0 +1,400 @@
+use core::{
+    arch::asm,
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::Msr,
+    },
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        FrameAllocator, PageSize, PageTableFlags, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    has_1gib_pages,
+    kernel_space::{self, KernelRange, Region, SpaceError},
+    HugePageMapper,
+};
+
+const IA32_PAT: u32 = 0x277;
+
+// PAT memory types
+const PAT_UNCACHEABLE: u64 = 0x00;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+const PAT_WRITE_THROUGH: u64 = 0x04;
+const PAT_WRITE_BACK: u64 = 0x06;
+const PAT_UNCACHED_MINUS: u64 = 0x07;
+
+/// The PAT entries, selected by the `PAT`, `NO_CACHE` and `WRITE_THROUGH` bits
+/// of a page table entry in this order.
+///
+/// The power-on layout has write-through in entry 1. Entry 1 is changed to
+/// write-combining, so that it can be selected without the `PAT` bit, which
+/// `x86_64` does not let us set in 4 KiB page table entries.
+const PAT_ENTRIES: [u64; 8] = [
+    PAT_WRITE_BACK,
+    PAT_WRITE_COMBINING,
+    PAT_UNCACHED_MINUS,
+    PAT_UNCACHEABLE,
+    PAT_WRITE_BACK,
+    PAT_WRITE_THROUGH,
+    PAT_UNCACHED_MINUS,
+    PAT_UNCACHEABLE,
+];
+
+/// Whether the PAT has been programmed with `PAT_ENTRIES`.
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The caching of a mapping of device memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal memory, e.g. a buffer shared with a device which snoops the caches.
+    WriteBack,
+    /// Writes are buffered and combined but never cached, e.g. for a frame buffer.
+    ///
+    /// Falls back to `Uncached` if the CPU has no PAT.
+    WriteCombining,
+    /// Every access goes to the device in program order, e.g. for control registers.
+    Uncached,
+}
+
+impl CacheMode {
+    /// Returns the page table flags selecting the PAT entry of this mode.
+    #[must_use]
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteCombining if PAT_ENABLED.load(Ordering::Relaxed) => {
+                PageTableFlags::WRITE_THROUGH
+            }
+            CacheMode::WriteCombining | CacheMode::Uncached => {
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            }
+        }
+    }
+}
+
+/// Programs the PAT of the calling CPU with `PAT_ENTRIES`.
+///
+/// Must be called on every CPU before it accesses a write-combining mapping,
+/// since mappings are shared between all CPUs.
+pub fn init_pat() {
+    let has_pat = unsafe { core::arch::x86_64::__cpuid(1) }.edx & (1 << 16) != 0;
+    if !has_pat {
+        return;
+    }
+
+    let value = PAT_ENTRIES
+        .iter()
+        .enumerate()
+        .fold(0, |value, (index, &entry)| value | entry << (index * 8));
+
+    // the caches must not hold lines of the old memory types while the PAT
+    // changes, see the Intel SDM, volume 3, section 11.12.4
+    interrupts::without_interrupts(|| unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write(cr0 | Cr0Flags::CACHE_DISABLE);
+        asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+
+        Msr::new(IA32_PAT).write(value);
+
+        asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+        Cr0::write(cr0);
+    });
+    PAT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Reads the PAT of the calling CPU.
+#[must_use]
+pub fn read_pat() -> u64 {
+    unsafe { Msr::new(IA32_PAT).read() }
+}
+
+#[derive(Debug)]
+pub enum MmioError {
+    /// The kernel page table has not been handed over by `init_demand_paging`.
+    NotInitialized,
+    /// The MMIO region of the kernel address space has no room for the mapping.
+    AddressSpace(SpaceError),
+    MappingFailed(MapToError<Size4KiB>),
+    UnmappingFailed(UnmapError),
+}
+
+impl From<SpaceError> for MmioError {
+    fn from(err: SpaceError) -> Self {
+        MmioError::AddressSpace(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for MmioError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        MmioError::MappingFailed(err)
+    }
+}
+
+impl From<UnmapError> for MmioError {
+    fn from(err: UnmapError) -> Self {
+        MmioError::UnmappingFailed(err)
+    }
+}
+
+/// A physical range of device memory mapped into the MMIO region of the
+/// kernel address space by `ioremap`.
+///
+/// The mapping stays until it is given to `iounmap`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IoMapping {
+    range: KernelRange,
+    addr: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    cache_mode: CacheMode,
+}
+
+impl IoMapping {
+    /// Returns the virtual address of the start of the physical range.
+    #[must_use]
+    pub fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    #[must_use]
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    #[must_use]
+    pub fn cache_mode(&self) -> CacheMode {
+        self.cache_mode
+    }
+
+    #[must_use]
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.addr.as_mut_ptr()
+    }
+
+    /// Returns the start and size of the mapped pages.
+    fn pages(&self) -> (VirtAddr, u64) {
+        let start = self.addr.align_down(Size4KiB::SIZE);
+        (
+            start,
+            x86_64::align_up(self.addr - start + self.size, Size4KiB::SIZE),
+        )
+    }
+}
+
+/// Maps `size` bytes of physical memory starting at `phys` with the given
+/// caching, using the kernel page table.
+///
+/// # Errors
+/// Returns an error if the kernel page table has not been handed over yet,
+/// or if the range cannot be mapped.
+///
+/// # Safety
+/// The caller must guarantee that the range is device memory, or memory which
+/// is not used by anything else, since writes through the mapping bypass
+/// Rust's aliasing rules. Mapping memory with a caching different from other
+/// mappings of the same memory, e.g. the complete physical memory mapping,
+/// must be avoided for memory which is accessed through both.
+pub unsafe fn ioremap(
+    phys: PhysAddr,
+    size: u64,
+    cache_mode: CacheMode,
+) -> Result<IoMapping, MmioError> {
+    super::with_kernel_memory(|mapper, frame_allocator| {
+        ioremap_with(mapper, frame_allocator, phys, size, cache_mode)
+    })
+    .ok_or(MmioError::NotInitialized)?
+}
+
+/// Like `ioremap`, but with the given page table, e.g. before the kernel page
+/// table is handed over.
+///
+/// Large ranges are mapped with huge pages where possible, for which the
+/// virtual address is chosen to have the same offset into a huge page as `phys`.
+///
+/// # Errors
+/// Returns an error if the range cannot be mapped.
+///
+/// # Safety
+/// See `ioremap`.
+pub unsafe fn ioremap_with(
+    mapper: &mut impl HugePageMapper,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys: PhysAddr,
+    size: u64,
+    cache_mode: CacheMode,
+) -> Result<IoMapping, MmioError> {
+    let phys_start = phys.align_down(Size4KiB::SIZE);
+    let mapped_size = x86_64::align_up(phys - phys_start + size, Size4KiB::SIZE);
+
+    // skip the part of the range in front of `phys_start` within the largest
+    // page the range is large enough for, so that they are aligned alike
+    let align = [Size1GiB::SIZE, Size2MiB::SIZE]
+        .into_iter()
+        .filter(|&page_size| page_size != Size1GiB::SIZE || has_1gib_pages())
+        .find(|&page_size| mapped_size >= page_size)
+        .unwrap_or(Size4KiB::SIZE);
+    let skew = phys_start.as_u64() & (align - 1);
+    let range = kernel_space::allocate(Region::Mmio, skew + mapped_size, align)?;
+    let start = range.start() + skew;
+
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache_mode.flags();
+    if let Err(err) = kernel_space::map_physical_huge(
+        mapper,
+        frame_allocator,
+        start,
+        phys_start,
+        mapped_size,
+        flags,
+    ) {
+        kernel_space::free(range)?;
+        return Err(err.into());
+    }
+
+    Ok(IoMapping {
+        range,
+        addr: start + (phys - phys_start),
+        phys,
+        size,
+        cache_mode,
+    })
+}
+
+/// Unmaps a mapping made by `ioremap`, using the kernel page table.
+///
+/// # Errors
+/// Returns an error if the kernel page table has not been handed over yet,
+/// or if the mapping cannot be unmapped.
+pub fn iounmap(mapping: IoMapping) -> Result<(), MmioError> {
+    super::with_kernel_memory(|mapper, _| iounmap_with(mapper, mapping))
+        .ok_or(MmioError::NotInitialized)?
+}
+
+/// Like `iounmap`, but with the given page table.
+///
+/// # Errors
+/// Returns an error if the mapping cannot be unmapped.
+pub fn iounmap_with(mapper: &mut impl HugePageMapper, mapping: IoMapping) -> Result<(), MmioError> {
+    let (start, size) = mapping.pages();
+    kernel_space::unmap_physical_huge(mapper, start, size)?;
+    kernel_space::free(mapping.range)?;
+    Ok(())
+}
+
+/// Device registers laid out like `T`, mapped with `ioremap`.
+///
+/// `T` is meant to be a `#[repr(C)]` struct of `Volatile` fields, like the
+/// `TextBuffer` of `vga_buffer`, so that every register access is volatile:
+///
+/// ```ignore
+/// #[repr(C)]
+/// struct Registers {
+///     control: Volatile<u32>,
+///     status: Volatile<u32>,
+/// }
+///
+/// let mut registers = unsafe { RegisterBlock::<Registers>::map(phys, CacheMode::Uncached) }?;
+/// registers.control.write(1);
+/// ```
+pub struct RegisterBlock<T> {
+    mapping: IoMapping,
+    registers: PhantomData<T>,
+}
+
+impl<T> RegisterBlock<T> {
+    /// Maps the registers at `phys` with the given caching, using the kernel page table.
+    ///
+    /// # Errors
+    /// Returns an error if the registers cannot be mapped.
+    ///
+    /// # Safety
+    /// The caller must guarantee that `phys` holds registers laid out like
+    /// `T`, which are not accessed through any other mapping.
+    ///
+    /// # Panics
+    /// Panics if `phys` is not aligned for `T`.
+    pub unsafe fn map(phys: PhysAddr, cache_mode: CacheMode) -> Result<Self, MmioError> {
+        Ok(Self::new(ioremap(phys, size_of::<T>() as u64, cache_mode)?))
+    }
+
+    /// Like `map`, but with the given page table.
+    ///
+    /// # Errors
+    /// Returns an error if the registers cannot be mapped.
+    ///
+    /// # Safety
+    /// See `map`.
+    ///
+    /// # Panics
+    /// Panics if `phys` is not aligned for `T`.
+    pub unsafe fn map_with(
+        mapper: &mut impl HugePageMapper,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        phys: PhysAddr,
+        cache_mode: CacheMode,
+    ) -> Result<Self, MmioError> {
+        let mapping = ioremap_with(
+            mapper,
+            frame_allocator,
+            phys,
+            size_of::<T>() as u64,
+            cache_mode,
+        )?;
+        Ok(Self::new(mapping))
+    }
+
+    unsafe fn new(mapping: IoMapping) -> Self {
+        assert!(
+            mapping.phys_addr().is_aligned(align_of::<T>() as u64),
+            "registers are not aligned"
+        );
+        Self {
+            mapping,
+            registers: PhantomData,
+        }
+    }
+
+    #[must_use]
+    pub fn mapping(&self) -> &IoMapping {
+        &self.mapping
+    }
+
+    /// Unmaps the registers, using the kernel page table.
+    ///
+    /// # Errors
+    /// Returns an error if the registers cannot be unmapped.
+    pub fn unmap(self) -> Result<(), MmioError> {
+        iounmap(self.mapping)
+    }
+}
+
+impl<T> Deref for RegisterBlock<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mapping.as_mut_ptr::<T>() }
+    }
+}
+
+impl<T> DerefMut for RegisterBlock<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mapping.as_mut_ptr::<T>() }
+    }
+}
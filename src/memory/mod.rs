@@ -0,0 +1,139 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use frame::BitmapFrameAllocator;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, PageTable, PhysFrame, Size1GiB, Size2MiB,
+        Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+pub mod address_space;
+pub mod frame;
+pub mod kernel_space;
+pub mod mmio;
+pub mod protection;
+pub mod stack;
+pub mod user_copy;
+pub mod vma;
+
+/// The virtual address at which the bootloader mapped the complete physical memory.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The level 4 frame of the page table set up by the bootloader, which kernel threads run on.
+static KERNEL_LEVEL_4_FRAME: AtomicU64 = AtomicU64::new(0);
+
+/// The kernel page table and frame allocator, once handed over by `init_demand_paging`.
+static KERNEL_MEMORY: Mutex<Option<(OffsetPageTable<'static>, BitmapFrameAllocator)>> =
+    Mutex::new(None);
+
+/// Initialize a new `OffsetPageTable`.
+///
+/// # Safety
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to the virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be called only once
+/// to avoid aliasing `&mut`.
+#[must_use]
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (level_4_frame, _) = Cr3::read();
+    KERNEL_LEVEL_4_FRAME.store(level_4_frame.start_address().as_u64(), Ordering::Relaxed);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+/// Returns `true` if `init` has been called.
+#[must_use]
+pub fn is_initialized() -> bool {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) != 0
+}
+
+/// Returns the level 4 frame of the kernel page table, or `None` before `init`.
+///
+/// Every `address_space::AddressSpace` shares the kernel part of it.
+#[must_use]
+pub fn kernel_level_4_frame() -> Option<PhysFrame> {
+    match KERNEL_LEVEL_4_FRAME.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+/// Returns the virtual address through which the given physical address can be
+/// accessed in the complete physical memory mapping.
+///
+/// # Panics
+/// Panics if called before `init`.
+#[must_use]
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    assert_ne!(offset, 0, "physical memory mapping is not initialized");
+    VirtAddr::new(offset + addr.as_u64())
+}
+
+/// Hands the kernel page table and frame allocator over to the page fault
+/// handler, which maps pages of the areas registered in `vma` on demand.
+pub fn init_demand_paging(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) {
+    interrupts::without_interrupts(|| {
+        *KERNEL_MEMORY.lock() = Some((mapper, frame_allocator));
+    });
+}
+
+/// Runs the given function with the kernel page table and frame allocator,
+/// or returns `None` if they have not been handed over by `init_demand_paging`.
+///
+/// Interrupts are disabled while the function runs, so that the page fault
+/// handler never waits for a preempted thread holding the lock.
+/// The function must not allocate on the heap, since growing the heap needs
+/// the kernel page table as well.
+pub fn with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let (mapper, frame_allocator) = kernel_memory.as_mut()?;
+        Some(f(mapper, frame_allocator))
+    })
+}
+
+/// A page table which can map pages of every size, like `OffsetPageTable`.
+pub trait HugePageMapper:
+    Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB> + Translate
+{
+}
+
+impl<T> HugePageMapper for T where
+    T: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB> + Translate
+{
+}
+
+/// Returns `true` if the CPU supports 1 GiB pages; 2 MiB pages are always supported.
+#[must_use]
+pub fn has_1gib_pages() -> bool {
+    // bit 26 of EDX of the extended function 0x8000_0001 is the `Page1GB` flag
+    unsafe { core::arch::x86_64::__cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
+
+/// A `FrameAllocator` which always fails to allocate a new physical frame.
+pub struct EmptyFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        None
+    }
+}
@@ -0,0 +1,230 @@
+use core::{arch::x86_64::__cpuid, mem::size_of, ptr::addr_of};
+
+use bootloader::bootinfo::MemoryMap;
+use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::{FlagUpdateError, MappedFrame, TranslateResult},
+        Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PageTableIndex, Size1GiB,
+        Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::phys_to_virt;
+use crate::elf::{self, Elf, ElfError, ProgramHeader};
+
+extern "C" {
+    /// The ELF header of the kernel, defined by the linker at the start of the
+    /// first segment.
+    static __ehdr_start: u8;
+}
+
+/// The protection features of the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protections {
+    /// Pages can be marked `NO_EXECUTE` (EFER.NXE).
+    pub no_execute: bool,
+    /// Read-only pages cannot be written by the kernel either (CR0.WP).
+    pub write_protect: bool,
+    /// The kernel cannot execute user pages (CR4.SMEP).
+    pub smep: bool,
+    /// The kernel cannot access user pages unless RFLAGS.AC is set (CR4.SMAP).
+    pub smap: bool,
+}
+
+/// Returns the protection features supported by the CPU.
+#[must_use]
+pub fn supported() -> Protections {
+    let extended = unsafe { __cpuid(0x8000_0001) };
+    let structured = if unsafe { __cpuid(0) }.eax >= 7 {
+        unsafe { __cpuid(7) }.ebx
+    } else {
+        0
+    };
+    Protections {
+        no_execute: extended.edx & (1 << 20) != 0,
+        write_protect: true,
+        smep: structured & (1 << 7) != 0,
+        smap: structured & (1 << 20) != 0,
+    }
+}
+
+/// Returns the protection features enabled on the calling CPU.
+#[must_use]
+pub fn enabled() -> Protections {
+    let cr4 = Cr4::read();
+    Protections {
+        no_execute: Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE),
+        write_protect: Cr0::read().contains(Cr0Flags::WRITE_PROTECT),
+        smep: cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+        smap: cr4.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+    }
+}
+
+/// Enables every protection feature supported by the calling CPU, and returns
+/// the enabled ones.
+///
+/// Must be called on every CPU, since the features are enabled per CPU.
+pub fn enable() -> Protections {
+    let supported = supported();
+    unsafe {
+        if supported.no_execute {
+            Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+        }
+        Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+        Cr4::update(|flags| {
+            flags.set(
+                Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION,
+                supported.smep,
+            );
+            flags.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, supported.smap);
+        });
+    }
+    enabled()
+}
+
+#[derive(Debug)]
+pub enum ProtectionError {
+    /// The ELF header of the kernel cannot be found.
+    Elf(ElfError),
+    /// A segment of the kernel starting at the given address is both writable
+    /// and executable.
+    WritableAndExecutable(VirtAddr),
+    UpdateFailed(FlagUpdateError),
+}
+
+impl From<ElfError> for ProtectionError {
+    fn from(err: ElfError) -> Self {
+        ProtectionError::Elf(err)
+    }
+}
+
+impl From<FlagUpdateError> for ProtectionError {
+    fn from(err: FlagUpdateError) -> Self {
+        ProtectionError::UpdateFailed(err)
+    }
+}
+
+/// Returns the ELF file of the kernel, which is mapped up to the end of its
+/// program header table.
+fn kernel_elf() -> Result<Elf<'static>, ElfError> {
+    let start = unsafe { addr_of!(__ehdr_start) };
+    let header = unsafe { start.cast::<elf::Header>().read_unaligned() };
+    if header.ident[..4] != elf::MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    let end = header.program_headers_end().ok_or(ElfError::Truncated)?;
+    let len = (end as usize).max(size_of::<elf::Header>());
+    Elf::parse(unsafe { core::slice::from_raw_parts(start, len) })
+}
+
+/// Returns the loaded segments of the kernel.
+///
+/// # Errors
+/// Returns an error if the ELF header of the kernel cannot be found.
+pub fn kernel_segments() -> Result<impl Iterator<Item = ProgramHeader>, ProtectionError> {
+    Ok(kernel_elf()?
+        .program_headers()
+        .filter(ProgramHeader::is_load))
+}
+
+/// Remaps the kernel with W^X permissions: every segment of the kernel is
+/// remapped according to its ELF flags, so that code is read-only and data is
+/// not executable, and the complete physical memory mapping is made not
+/// executable, except for the level 4 entries it shares with the kernel.
+///
+/// Must be called after `enable`, since `NO_EXECUTE` requires EFER.NXE.
+///
+/// # Errors
+/// Returns an error if the ELF header of the kernel cannot be found, if a
+/// segment is both writable and executable, or if a page of a segment is not
+/// mapped. No page is changed if a segment is both writable and executable.
+pub fn remap_kernel(
+    mapper: &mut OffsetPageTable,
+    memory_map: &MemoryMap,
+) -> Result<(), ProtectionError> {
+    if let Some(segment) = kernel_segments()?.find(|s| s.is_writable() && s.is_executable()) {
+        return Err(ProtectionError::WritableAndExecutable(segment.start()));
+    }
+    let no_execute = enabled().no_execute;
+
+    for segment in kernel_segments()? {
+        let mut flags = PageTableFlags::empty();
+        flags.set(PageTableFlags::WRITABLE, segment.is_writable());
+        flags.set(
+            PageTableFlags::NO_EXECUTE,
+            no_execute && !segment.is_executable(),
+        );
+        protect(mapper, segment.start(), segment.end(), flags)?;
+    }
+
+    if no_execute {
+        // setting `NO_EXECUTE` in a level 4 entry applies to everything it maps
+        let physical_memory_end = memory_map
+            .iter()
+            .map(|region| region.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let first = phys_to_virt(PhysAddr::new(0)).p4_index();
+        let last = phys_to_virt(PhysAddr::new(physical_memory_end.max(1) - 1)).p4_index();
+        for index in u16::from(first)..=u16::from(last) {
+            let index = PageTableIndex::new(index);
+            if holds_kernel(index)? {
+                continue;
+            }
+            let entry = &mut mapper.level_4_table()[index];
+            if !entry.is_unused() {
+                entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+            }
+        }
+        tlb::flush_all();
+    }
+    Ok(())
+}
+
+/// Returns `true` if a segment of the kernel is mapped through the given level 4 entry.
+fn holds_kernel(index: PageTableIndex) -> Result<bool, ProtectionError> {
+    Ok(kernel_segments()?.any(|segment| {
+        segment.start().p4_index() <= index && index <= (segment.end() - 1u64).p4_index()
+    }))
+}
+
+/// Sets the `WRITABLE` and `NO_EXECUTE` flags of every page overlapping the
+/// range to those of `flags`, keeping the other flags, whatever the page size.
+fn protect(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    let mask = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut addr = start.align_down(Size4KiB::SIZE);
+    while addr < end {
+        let (frame, old) = match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, flags, .. } => (frame, flags),
+            _ => return Err(FlagUpdateError::PageNotMapped),
+        };
+        let new = (old - mask) | (flags & mask);
+        match frame {
+            MappedFrame::Size4KiB(_) => update_flags::<Size4KiB>(mapper, addr, new)?,
+            MappedFrame::Size2MiB(_) => update_flags::<Size2MiB>(mapper, addr, new)?,
+            MappedFrame::Size1GiB(_) => update_flags::<Size1GiB>(mapper, addr, new)?,
+        }
+        addr = addr.align_down(frame.size()) + frame.size();
+    }
+    Ok(())
+}
+
+fn update_flags<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    addr: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    unsafe { mapper.update_flags(Page::<S>::containing_address(addr), flags) }?.flush();
+    Ok(())
+}
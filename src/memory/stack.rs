@@ -0,0 +1,235 @@
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        page::PageRange,
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{
+    kernel_space::{self, KernelRange, Region, SpaceError},
+    with_kernel_memory,
+};
+use crate::thread::ThreadId;
+
+/// The number of unmapped pages below every kernel stack.
+pub const GUARD_PAGES: u64 = 1;
+
+/// The maximum number of kernel stacks that can be allocated at the same time.
+const MAX_STACKS: usize = 128;
+
+/// The allocated stacks, in no particular order.
+///
+/// Lookups happen in the page fault handler, which must not allocate, so the
+/// registry has a fixed capacity instead of living on the heap.
+static STACKS: Mutex<[Option<KernelStack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+/// What a kernel stack is used for, reported when it overflows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackOwner {
+    /// The stack of a spawned kernel thread.
+    Thread(ThreadId),
+    /// The stack on which the given CPU handles double faults.
+    DoubleFault { cpu: usize },
+    /// The stack on which the given CPU handles page faults.
+    PageFault { cpu: usize },
+    /// The stack on which the given application processor boots and idles.
+    Boot { cpu: usize },
+}
+
+/// A kernel stack in the `Stacks` region, with `GUARD_PAGES` unmapped pages
+/// below it, so that an overflow faults instead of corrupting other memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    range: KernelRange,
+    owner: StackOwner,
+}
+
+impl KernelStack {
+    #[must_use]
+    pub fn owner(&self) -> StackOwner {
+        self.owner
+    }
+
+    /// Returns the lowest mapped address of the stack.
+    #[must_use]
+    pub fn bottom(&self) -> VirtAddr {
+        self.range.start() + GUARD_PAGES * Size4KiB::SIZE
+    }
+
+    /// Returns the first address above the stack, which is the initial stack pointer.
+    #[must_use]
+    pub fn top(&self) -> VirtAddr {
+        self.range.end()
+    }
+
+    /// Returns the usable size of the stack, without the guard pages.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.top() - self.bottom()
+    }
+
+    /// Returns `true` if the given address lies in the guard pages of the stack.
+    #[must_use]
+    pub fn is_guard(&self, addr: VirtAddr) -> bool {
+        self.range.start() <= addr && addr < self.bottom()
+    }
+
+    fn pages(&self) -> PageRange<Size4KiB> {
+        Page::range(
+            Page::containing_address(self.bottom()),
+            Page::containing_address(self.top()),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum StackError {
+    /// No kernel page table has been handed over with `init_demand_paging`.
+    NotInitialized,
+    /// `MAX_STACKS` stacks are allocated already.
+    TooManyStacks,
+    AddressSpace(SpaceError),
+    MappingFailed(MapToError<Size4KiB>),
+    UnmappingFailed(UnmapError),
+}
+
+impl From<SpaceError> for StackError {
+    fn from(err: SpaceError) -> Self {
+        StackError::AddressSpace(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for StackError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        StackError::MappingFailed(err)
+    }
+}
+
+impl From<UnmapError> for StackError {
+    fn from(err: UnmapError) -> Self {
+        StackError::UnmappingFailed(err)
+    }
+}
+
+/// Allocates a stack of at least `size` bytes for the given owner, using the
+/// kernel page table handed over with `init_demand_paging`.
+///
+/// Does not allocate on the heap.
+///
+/// # Errors
+/// Returns an error if the kernel page table has not been handed over, or the
+/// stack could not be allocated or mapped.
+pub fn allocate(size: u64, owner: StackOwner) -> Result<KernelStack, StackError> {
+    with_kernel_memory(|mapper, frame_allocator| {
+        allocate_with(mapper, frame_allocator, size, owner)
+    })
+    .unwrap_or(Err(StackError::NotInitialized))
+}
+
+/// Allocates a stack of at least `size` bytes for the given owner, and maps
+/// it with the given page table, leaving its guard pages unmapped.
+///
+/// # Errors
+/// Returns an error if the `Stacks` region has no room for the stack, the
+/// registry is full or a frame cannot be allocated.
+pub fn allocate_with(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    size: u64,
+    owner: StackOwner,
+) -> Result<KernelStack, StackError> {
+    let guard_size = GUARD_PAGES * Size4KiB::SIZE;
+    let range = kernel_space::allocate(Region::Stacks, guard_size + size, Size4KiB::SIZE)?;
+    let stack = KernelStack { range, owner };
+
+    let result = register(stack).and_then(|()| {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        kernel_space::map_range(mapper, frame_allocator, stack.pages(), flags).map_err(|err| {
+            unregister(stack);
+            err.into()
+        })
+    });
+    if let Err(err) = result {
+        kernel_space::free(range).expect("range has just been allocated");
+        return Err(err);
+    }
+    Ok(stack)
+}
+
+/// Unmaps and frees a stack allocated with `allocate`.
+///
+/// The stack must not be in use anymore. Does not allocate on the heap.
+///
+/// # Errors
+/// Returns an error if the kernel page table has not been handed over, or the
+/// stack is not allocated.
+pub fn free(stack: KernelStack) -> Result<(), StackError> {
+    with_kernel_memory(|mapper, frame_allocator| free_with(mapper, frame_allocator, stack))
+        .unwrap_or(Err(StackError::NotInitialized))
+}
+
+/// Unmaps and frees a stack allocated with `allocate_with`.
+///
+/// # Errors
+/// Returns an error if the stack is not allocated.
+pub fn free_with(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    stack: KernelStack,
+) -> Result<(), StackError> {
+    kernel_space::unmap_range(mapper, frame_allocator, stack.pages())?;
+    unregister(stack);
+    kernel_space::free(stack.range)?;
+    Ok(())
+}
+
+/// Returns the stack whose guard pages contain the given address, i.e. the
+/// stack which overflowed if a page fault occurred at the address.
+#[must_use]
+pub fn find_guard(addr: VirtAddr) -> Option<KernelStack> {
+    interrupts::without_interrupts(|| {
+        STACKS
+            .lock()
+            .iter()
+            .flatten()
+            .find(|stack| stack.is_guard(addr))
+            .copied()
+    })
+}
+
+/// Returns the stack containing the given address, not counting its guard pages.
+#[must_use]
+pub fn find(addr: VirtAddr) -> Option<KernelStack> {
+    interrupts::without_interrupts(|| {
+        STACKS
+            .lock()
+            .iter()
+            .flatten()
+            .find(|stack| stack.bottom() <= addr && addr < stack.top())
+            .copied()
+    })
+}
+
+fn register(stack: KernelStack) -> Result<(), StackError> {
+    interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let slot = stacks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(StackError::TooManyStacks)?;
+        *slot = Some(stack);
+        Ok(())
+    })
+}
+
+fn unregister(stack: KernelStack) {
+    interrupts::without_interrupts(|| {
+        if let Some(slot) = STACKS.lock().iter_mut().find(|slot| **slot == Some(stack)) {
+            *slot = None;
+        }
+    });
+}
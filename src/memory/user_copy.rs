@@ -0,0 +1,172 @@
+use core::ptr::addr_of;
+
+use x86_64::{
+    registers::control::{Cr4, Cr4Flags},
+    VirtAddr,
+};
+
+use super::address_space::{is_user_range, USER_END};
+
+// Copies between the kernel and the part of user mode of the address space
+// loaded in CR3. The instructions which access user mode are listed by
+// `fixups`, and the page fault handler resumes a fault on one of them at its
+// fixup instead of halting, so that a bad pointer of a program fails the copy.
+//
+// `user_copy(dst, src, len)` returns the number of bytes not copied, which
+// `rep movsb` keeps in RCX when it faults.
+//
+// `user_strncpy(dst, src, max)` copies bytes up to and including the first
+// zero byte, but at most `max` of them, and returns the number of bytes
+// copied before the zero byte. If it faults, it returns the complement of
+// the offset at which it did.
+core::arch::global_asm!(
+    ".global user_copy",
+    "user_copy:",
+    "mov rcx, rdx",
+    ".global user_copy_access",
+    "user_copy_access:",
+    "rep movsb",
+    ".global user_copy_fixup",
+    "user_copy_fixup:",
+    "mov rax, rcx",
+    "ret",
+    ".global user_strncpy",
+    "user_strncpy:",
+    "xor eax, eax",
+    "2:",
+    "cmp rax, rdx",
+    "je 3f",
+    ".global user_strncpy_access",
+    "user_strncpy_access:",
+    "movzx ecx, byte ptr [rsi + rax]",
+    "mov [rdi + rax], cl",
+    "test cl, cl",
+    "jz 3f",
+    "inc rax",
+    "jmp 2b",
+    "3:",
+    "ret",
+    ".global user_strncpy_fixup",
+    "user_strncpy_fixup:",
+    "not rax",
+    "ret",
+);
+
+extern "C" {
+    fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn user_strncpy(dst: *mut u8, src: *const u8, max: usize) -> usize;
+    static user_copy_access: u8;
+    static user_copy_fixup: u8;
+    static user_strncpy_access: u8;
+    static user_strncpy_fixup: u8;
+}
+
+/// The instructions which may fault on an address of user mode, with the
+/// instruction at which a fault on them is resumed.
+fn fixups() -> [(VirtAddr, VirtAddr); 2] {
+    let addr = |symbol: *const u8| VirtAddr::from_ptr(symbol);
+    unsafe {
+        [
+            (
+                addr(addr_of!(user_copy_access)),
+                addr(addr_of!(user_copy_fixup)),
+            ),
+            (
+                addr(addr_of!(user_strncpy_access)),
+                addr(addr_of!(user_strncpy_fixup)),
+            ),
+        ]
+    }
+}
+
+/// Returns the instruction at which a page fault of the kernel at the given
+/// instruction is resumed, if it was caused by a copy from or to user mode.
+///
+/// Called by the page fault handler, so it must not allocate.
+pub(crate) fn fixup(instruction_pointer: VirtAddr) -> Option<VirtAddr> {
+    fixups()
+        .into_iter()
+        .find(|&(access, _)| access == instruction_pointer)
+        .map(|(_, fixup)| fixup)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    /// The range does not lie in the part of user mode.
+    NotUserRange,
+    /// The given address of user mode is not mapped, or does not allow the access.
+    Fault(VirtAddr),
+}
+
+/// Copies `dst.len()` bytes from the given address of user mode to `dst`.
+///
+/// # Errors
+/// Returns an error if the range does not lie in the part of user mode, or
+/// the copy faulted, in which case `dst` holds the bytes before the fault.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserCopyError> {
+    if !is_user_range(src, dst.len() as u64) {
+        return Err(UserCopyError::NotUserRange);
+    }
+    let not_copied =
+        with_user_access(|| unsafe { user_copy(dst.as_mut_ptr(), src.as_ptr(), dst.len()) });
+    match not_copied {
+        0 => Ok(()),
+        _ => Err(UserCopyError::Fault(src + (dst.len() - not_copied))),
+    }
+}
+
+/// Copies `src` to the given address of user mode.
+///
+/// Copy-on-write pages are copied by the page fault handler as if user mode
+/// wrote them.
+///
+/// # Errors
+/// Returns an error if the range does not lie in the part of user mode, or
+/// the copy faulted, in which case the bytes before the fault have been copied.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserCopyError> {
+    if !is_user_range(dst, src.len() as u64) {
+        return Err(UserCopyError::NotUserRange);
+    }
+    let not_copied =
+        with_user_access(|| unsafe { user_copy(dst.as_mut_ptr(), src.as_ptr(), src.len()) });
+    match not_copied {
+        0 => Ok(()),
+        _ => Err(UserCopyError::Fault(dst + (src.len() - not_copied))),
+    }
+}
+
+/// Copies the zero-terminated string at the given address of user mode to
+/// `dst`, and returns its length without the zero byte.
+///
+/// Like `strncpy_from_user` of Linux, the copy stops at the end of `dst` or
+/// of the part of user mode, in which case the string is not terminated and
+/// its length is that of the copied part.
+///
+/// # Errors
+/// Returns an error if the address does not lie in the part of user mode, or
+/// the copy faulted before the end of the string.
+pub fn strncpy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<usize, UserCopyError> {
+    if !is_user_range(src, 0) {
+        return Err(UserCopyError::NotUserRange);
+    }
+    let max = dst.len().min((USER_END - src.as_u64()) as usize);
+    let copied = with_user_access(|| unsafe { user_strncpy(dst.as_mut_ptr(), src.as_ptr(), max) });
+    if copied > max {
+        return Err(UserCopyError::Fault(src + !copied));
+    }
+    Ok(copied)
+}
+
+/// Runs the given function with accesses of the kernel to user pages allowed,
+/// which SMAP denies otherwise.
+fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+    if smap {
+        unsafe { core::arch::asm!("stac", options(nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { core::arch::asm!("clac", options(nostack)) };
+    }
+    result
+}
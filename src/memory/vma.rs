@@ -0,0 +1,236 @@
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MapToError, TranslateResult},
+            FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+        },
+    },
+    VirtAddr,
+};
+
+use super::{
+    address_space, phys_to_virt,
+    stack::{self, StackOwner},
+    with_kernel_memory,
+};
+
+/// The maximum number of virtual memory areas that can be registered at the same time.
+const MAX_AREAS: usize = 64;
+
+/// The registered areas, in no particular order.
+///
+/// Lookups happen in the page fault handler, which must not allocate: the
+/// faulting code might hold the allocator lock, or be the allocator itself
+/// touching a lazily mapped heap page. Therefore the registry has a fixed
+/// capacity instead of living on the heap.
+static AREAS: Mutex<[Option<VirtualMemoryArea>; MAX_AREAS]> = Mutex::new([None; MAX_AREAS]);
+
+/// How the pages of a virtual memory area are backed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaKind {
+    /// Pages are backed by zeroed frames, which are allocated and mapped on first access.
+    Anonymous,
+    /// Pages must never be accessed, e.g. guard pages below a stack.
+    Guard,
+}
+
+/// A page-aligned range of virtual addresses whose page faults are resolved
+/// by the page fault handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualMemoryArea {
+    start: VirtAddr,
+    end: VirtAddr,
+    /// The flags of the pages mapped on demand, without `PRESENT`.
+    pub flags: PageTableFlags,
+    pub kind: AreaKind,
+}
+
+impl VirtualMemoryArea {
+    /// Creates an area covering the given number of pages starting at `start`.
+    ///
+    /// # Panics
+    /// Panics if `start` is not page-aligned.
+    #[must_use]
+    pub fn new(start: VirtAddr, pages: u64, flags: PageTableFlags, kind: AreaKind) -> Self {
+        assert!(
+            start.is_aligned(Page::<Size4KiB>::SIZE),
+            "area is not page-aligned"
+        );
+        Self {
+            start,
+            end: start + pages * Page::<Size4KiB>::SIZE,
+            flags,
+            kind,
+        }
+    }
+
+    #[must_use]
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Returns the first address after the area.
+    #[must_use]
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    #[must_use]
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn overlaps(&self, other: &Self) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    /// Returns `true` if the given access is allowed by the flags of the area.
+    fn permits(&self, error_code: PageFaultErrorCode) -> bool {
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !self.flags.contains(PageTableFlags::WRITABLE)
+        {
+            return false;
+        }
+        if error_code.contains(PageFaultErrorCode::USER_MODE)
+            && !self.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+        {
+            return false;
+        }
+        !(error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && self.flags.contains(PageTableFlags::NO_EXECUTE))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaError {
+    /// The area overlaps with an area which is already registered.
+    Overlapping(VirtualMemoryArea),
+    /// `MAX_AREAS` areas are already registered.
+    TooManyAreas,
+}
+
+/// Why a page fault could not be resolved.
+#[derive(Debug)]
+pub enum FaultError {
+    /// The address does not belong to any registered area.
+    Unmapped,
+    /// The address belongs to a guard area.
+    GuardPage,
+    /// The address lies in the guard pages below the given kernel stack, which overflowed.
+    StackOverflow(StackOwner),
+    /// The access is not allowed by the flags of the area, or the page is
+    /// already present.
+    AccessViolation,
+    /// No kernel page table has been handed over with `init_demand_paging`.
+    NotInitialized,
+    /// Mapping the page failed, e.g. because physical memory is exhausted.
+    MappingFailed(MapToError<Size4KiB>),
+}
+
+/// Registers an area, so that page faults in it are resolved according to its kind.
+///
+/// # Errors
+/// Returns an error if the area overlaps with a registered one or the registry is full.
+pub fn register(area: VirtualMemoryArea) -> Result<(), AreaError> {
+    interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        if let Some(other) = areas.iter().flatten().find(|other| other.overlaps(&area)) {
+            return Err(AreaError::Overlapping(*other));
+        }
+        let slot = areas
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(AreaError::TooManyAreas)?;
+        *slot = Some(area);
+        Ok(())
+    })
+}
+
+/// Removes the area starting at the given address from the registry.
+///
+/// Pages which have already been mapped stay mapped; unmapping them is up to the caller.
+pub fn unregister(start: VirtAddr) -> Option<VirtualMemoryArea> {
+    interrupts::without_interrupts(|| {
+        AREAS
+            .lock()
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |area| area.start == start))
+            .and_then(Option::take)
+    })
+}
+
+/// Returns the registered area containing the given address.
+#[must_use]
+pub fn find(addr: VirtAddr) -> Option<VirtualMemoryArea> {
+    interrupts::without_interrupts(|| {
+        AREAS
+            .lock()
+            .iter()
+            .flatten()
+            .find(|area| area.contains(addr))
+            .copied()
+    })
+}
+
+/// Resolves a page fault at the given address by mapping a zeroed frame, if
+/// the address belongs to an anonymous area and the access is allowed, or by
+/// copying a copy-on-write page of user mode.
+///
+/// Called by the page fault handler, so it must not allocate.
+pub(crate) fn handle_page_fault(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), FaultError> {
+    if address_space::is_user_range(addr, 1)
+        && error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        return address_space::resolve_copy_on_write(addr, error_code);
+    }
+    let Some(area) = find(addr) else { return Err(outside_areas(addr)) };
+    match area.kind {
+        AreaKind::Guard => return Err(FaultError::GuardPage),
+        AreaKind::Anonymous => {}
+    }
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) || !area.permits(error_code) {
+        return Err(FaultError::AccessViolation);
+    }
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    with_kernel_memory(|mapper, frame_allocator| {
+        // another CPU might have resolved a fault on the same page in the meantime
+        if let TranslateResult::Mapped { .. } = mapper.translate(page.start_address()) {
+            return Ok(());
+        }
+
+        let frame: PhysFrame = frame_allocator
+            .allocate_frame()
+            .ok_or(FaultError::MappingFailed(MapToError::FrameAllocationFailed))?;
+        unsafe {
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, Page::<Size4KiB>::SIZE as usize);
+            mapper
+                .map_to(
+                    page,
+                    frame,
+                    area.flags | PageTableFlags::PRESENT,
+                    frame_allocator,
+                )
+                .map_err(FaultError::MappingFailed)?
+                .flush();
+        }
+        Ok(())
+    })
+    .unwrap_or(Err(FaultError::NotInitialized))
+}
+
+/// Returns why a fault at an address outside of every registered area cannot be resolved.
+fn outside_areas(addr: VirtAddr) -> FaultError {
+    match stack::find_guard(addr) {
+        Some(stack) => FaultError::StackOverflow(stack.owner()),
+        None => FaultError::Unmapped,
+    }
+}
@@ -0,0 +1,207 @@
+use core::{
+    arch::asm,
+    mem::size_of,
+    ptr::addr_of,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::{
+    registers::model_specific::GsBase,
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{PageSize, PageTableFlags, Size4KiB},
+        tss::TaskStateSegment,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    apic,
+    memory::{
+        self,
+        kernel_space::{self, Region},
+    },
+};
+
+/// The offset of `PerCpu::kernel_stack`, which the system call entry loads from `gs:[8]`.
+const KERNEL_STACK_OFFSET: usize = 8;
+/// The offset of `PerCpu::user_stack`, which the system call entry uses as `gs:[16]`.
+const USER_STACK_OFFSET: usize = 16;
+
+/// Data owned by a single CPU, reachable through its GS base.
+///
+/// While the CPU runs in user mode, GS holds the base of user mode and the
+/// kernel's base is kept in the `KernelGsBase` MSR, from which every entry
+/// into the kernel restores it with `swapgs`.
+#[derive(Debug)]
+#[repr(C)]
+pub struct PerCpu {
+    /// Points to this structure itself, so it can be loaded from `gs:[0]`.
+    self_ptr: *const PerCpu,
+    /// The stack on which system calls are handled, see `gdt::set_kernel_stack`.
+    kernel_stack: AtomicU64,
+    /// The stack pointer of user mode while a system call switches stacks.
+    user_stack: AtomicU64,
+    /// The TSS loaded on this CPU.
+    tss: AtomicPtr<TaskStateSegment>,
+    /// Sequential id of the CPU, where the bootstrap processor is 0.
+    pub id: usize,
+    pub apic_id: u8,
+}
+
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    const fn new(id: usize, apic_id: u8) -> Self {
+        Self {
+            self_ptr: core::ptr::null(),
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            tss: AtomicPtr::new(core::ptr::null_mut()),
+            id,
+            apic_id,
+        }
+    }
+
+    pub(crate) fn tss(&self) -> *mut TaskStateSegment {
+        self.tss.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_tss(&self, tss: *mut TaskStateSegment) {
+        self.tss.store(tss, Ordering::SeqCst);
+    }
+
+    pub(crate) fn kernel_stack(&self) -> VirtAddr {
+        VirtAddr::new(self.kernel_stack.load(Ordering::SeqCst))
+    }
+
+    pub(crate) fn set_kernel_stack(&self, top: VirtAddr) {
+        self.kernel_stack.store(top.as_u64(), Ordering::SeqCst);
+    }
+}
+
+static mut BOOTSTRAP_CPU: PerCpu = PerCpu::new(0, 0);
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn install(cpu: &'static mut PerCpu) {
+    let ptr: *const PerCpu = cpu;
+    cpu.self_ptr = ptr;
+    debug_assert_eq!(
+        addr_of!(cpu.kernel_stack) as usize - ptr as usize,
+        KERNEL_STACK_OFFSET
+    );
+    debug_assert_eq!(
+        addr_of!(cpu.user_stack) as usize - ptr as usize,
+        USER_STACK_OFFSET
+    );
+    GsBase::write(VirtAddr::from_ptr(ptr));
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Sets up the per-CPU data area of the bootstrap processor.
+///
+/// Does not allocate, so it can be called before the kernel heap exists.
+/// Must be called only once.
+pub fn init() {
+    unsafe {
+        BOOTSTRAP_CPU.apic_id = apic::local_apic_id();
+        install(&mut *core::ptr::addr_of_mut!(BOOTSTRAP_CPU));
+    }
+}
+
+/// Sets up the per-CPU data area of the calling application processor.
+///
+/// The area is mapped in the `PerCpu` region of the kernel address space,
+/// not allocated on the heap. Must be called only once per CPU.
+///
+/// # Panics
+/// Panics if the area cannot be allocated or mapped.
+pub fn init_ap(id: usize) {
+    let range = kernel_space::allocate(Region::PerCpu, size_of::<PerCpu>() as u64, Size4KiB::SIZE)
+        .expect("failed to allocate a per-CPU data area");
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        kernel_space::map_range(mapper, frame_allocator, range.pages(), flags)
+    })
+    .expect("kernel page table has not been handed over")
+    .expect("failed to map a per-CPU data area");
+
+    let ptr = range.start().as_mut_ptr::<PerCpu>();
+    unsafe {
+        ptr.write(PerCpu::new(id, apic::local_apic_id()));
+        install(&mut *ptr);
+    }
+}
+
+/// Returns the per-CPU data of the calling CPU.
+///
+/// Must not be called before the per-CPU data area of the calling CPU has
+/// been set up with `init` or `init_ap`.
+#[must_use]
+pub fn current() -> &'static PerCpu {
+    let ptr: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, preserves_flags, readonly));
+        &*ptr
+    }
+}
+
+/// Returns the per-CPU data of the calling CPU, or `None` if its per-CPU data
+/// area has not been set up yet.
+#[must_use]
+pub fn try_current() -> Option<&'static PerCpu> {
+    if GsBase::read().is_null() {
+        None
+    } else {
+        Some(current())
+    }
+}
+
+/// Switches GS to the per-CPU data while an interrupt which arrived in user
+/// mode is handled, and back to the base of user mode when dropped.
+///
+/// Handlers of interrupts which can arrive in user mode must hold it before
+/// using per-CPU data, unless their entry stub swaps GS itself.
+pub struct KernelGs {
+    from_user: bool,
+}
+
+impl KernelGs {
+    #[must_use]
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let from_user = stack_frame.code_segment & 0b11 != 0;
+        if from_user {
+            unsafe { swapgs() };
+        }
+        Self { from_user }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.from_user {
+            unsafe { swapgs() };
+        }
+    }
+}
+
+/// Exchanges the GS base with the `KernelGsBase` MSR.
+///
+/// # Safety
+/// Must only be called on transitions between user mode and the kernel.
+pub unsafe fn swapgs() {
+    asm!("swapgs", options(nostack, preserves_flags));
+}
+
+/// Returns the number of CPUs whose per-CPU data area has been set up.
+#[must_use]
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+/// Returns `true` if the calling CPU is the bootstrap processor.
+#[must_use]
+pub fn is_bootstrap_cpu() -> bool {
+    current().id == 0
+}
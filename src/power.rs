@@ -0,0 +1,203 @@
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    structures::DescriptorTablePointer,
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi::{self, fadt::Fadt, GenericAddress},
+    halt, memory,
+};
+
+/// SCI_EN bit of the PM1 control register, set when the machine is in ACPI mode.
+const PM1_CONTROL_SCI_ENABLE: u16 = 1 << 0;
+/// SLP_EN bit of the PM1 control register, which triggers the transition into the sleep state.
+const PM1_CONTROL_SLEEP_ENABLE: u16 = 1 << 13;
+
+const KEYBOARD_CONTROLLER_STATUS_PORT: u16 = 0x64;
+const KEYBOARD_CONTROLLER_COMMAND_PORT: u16 = 0x64;
+/// Pulses the CPU reset line of the keyboard controller.
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xfe;
+
+/// The values of the SLP_TYPa and SLP_TYPb fields for entering a sleep state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u16,
+    pub b: u16,
+}
+
+/// Powers the machine off through the ACPI soft-off (S5) state.
+///
+/// Halts the CPU if the machine could not be powered off, e.g. because it
+/// has no ACPI tables.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    if let (Some(fadt), Some(sleep_type)) = (Fadt::find(), s5_sleep_type()) {
+        enable_acpi_mode(&fadt);
+
+        let mut pm1a_control = Port::<u16>::new(fadt.pm1a_control_block());
+        unsafe {
+            let value = pm1a_control.read() & !(0b111 << 10);
+            pm1a_control.write(value | (sleep_type.a & 0b111) << 10 | PM1_CONTROL_SLEEP_ENABLE);
+        }
+        if let Some(port) = fadt.pm1b_control_block() {
+            let mut pm1b_control = Port::<u16>::new(port);
+            unsafe {
+                let value = pm1b_control.read() & !(0b111 << 10);
+                pm1b_control.write(value | (sleep_type.b & 0b111) << 10 | PM1_CONTROL_SLEEP_ENABLE);
+            }
+        }
+    }
+
+    halt();
+}
+
+/// Resets the machine.
+///
+/// Tries the ACPI reset register first, then the reset line of the keyboard
+/// controller, and finally forces a triple fault.
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    if let Some((register, value)) = Fadt::find().and_then(|fadt| fadt.reset_register()) {
+        write_register(register, value);
+    }
+
+    let mut status = Port::<u8>::new(KEYBOARD_CONTROLLER_STATUS_PORT);
+    let mut command = Port::<u8>::new(KEYBOARD_CONTROLLER_COMMAND_PORT);
+    unsafe {
+        // wait until the input buffer of the controller is empty
+        while status.read() & 0b10 != 0 {
+            core::hint::spin_loop();
+        }
+        command.write(KEYBOARD_CONTROLLER_RESET);
+    }
+
+    triple_fault();
+}
+
+/// Returns the sleep type values for the soft-off state, as defined by the
+/// `\_S5` object in the DSDT.
+#[must_use]
+pub fn s5_sleep_type() -> Option<SleepType> {
+    let dsdt = unsafe { acpi::table_at(Fadt::find()?.dsdt_address()) };
+    if &dsdt.signature != b"DSDT" || !dsdt.is_valid() {
+        return None;
+    }
+
+    let aml = unsafe {
+        core::slice::from_raw_parts(
+            (dsdt as *const acpi::SdtHeader).cast::<u8>(),
+            dsdt.length as usize,
+        )
+    };
+    // `_S5_` is defined with a NameOp, optionally with a root prefix
+    let start = (1..aml.len().saturating_sub(3))
+        .find(|&i| &aml[i..i + 4] == b"_S5_" && matches!(aml[i - 1], 0x08 | b'\\'))?;
+    parse_s5_package(&aml[start + 4..])
+}
+
+/// Parses the AML package following the `_S5_` name, which looks like:
+///
+/// `PackageOp PkgLength NumElements SLP_TYPa SLP_TYPb ...`
+///
+/// where each sleep type is either a `BytePrefix` followed by the value, or a
+/// `ZeroOp`/`OneOp` constant.
+fn parse_s5_package(aml: &[u8]) -> Option<SleepType> {
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0a;
+
+    let (&op, rest) = aml.split_first()?;
+    if op != PACKAGE_OP {
+        return None;
+    }
+    // the two high bits of the lead byte give the number of following PkgLength bytes
+    let pkg_length_bytes = usize::from(rest.first()? >> 6) + 1;
+    let mut rest = rest.get(pkg_length_bytes + 1..)?; // skip PkgLength and NumElements
+
+    let mut next_value = || -> Option<u16> {
+        let (&byte, tail) = rest.split_first()?;
+        if byte == BYTE_PREFIX {
+            let (&value, tail) = tail.split_first()?;
+            rest = tail;
+            Some(u16::from(value))
+        } else {
+            rest = tail;
+            Some(u16::from(byte))
+        }
+    };
+
+    let a = next_value()?;
+    let b = next_value()?;
+    Some(SleepType { a, b })
+}
+
+/// Switches the machine into ACPI mode, if it is not already in it.
+fn enable_acpi_mode(fadt: &Fadt) {
+    let mut pm1a_control = Port::<u16>::new(fadt.pm1a_control_block());
+    if unsafe { pm1a_control.read() } & PM1_CONTROL_SCI_ENABLE != 0 {
+        return;
+    }
+    let Some(smi_command) = fadt.smi_command_port() else { return };
+
+    unsafe {
+        Port::<u8>::new(smi_command).write(fadt.acpi_enable());
+        // the transition may take a while; give up eventually and try anyway
+        for _ in 0..1_000_000 {
+            if pm1a_control.read() & PM1_CONTROL_SCI_ENABLE != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+fn write_register(register: GenericAddress, value: u8) {
+    let address = register.address;
+    match register.address_space {
+        GenericAddress::SYSTEM_IO => unsafe {
+            Port::<u8>::new(address as u16).write(value);
+        },
+        GenericAddress::SYSTEM_MEMORY => unsafe {
+            memory::phys_to_virt(PhysAddr::new(address))
+                .as_mut_ptr::<u8>()
+                .write_volatile(value);
+        },
+        _ => {} // e.g. PCI configuration space, which is not supported
+    }
+}
+
+/// Resets the CPU by raising an exception with an empty IDT.
+fn triple_fault() -> ! {
+    let empty_idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty_idt);
+    }
+    x86_64::instructions::interrupts::int3();
+
+    halt();
+}
+
+#[test_case]
+fn parse_s5_package_with_byte_prefix() {
+    // Package (0x04) { 0x05, 0x05, Zero, Zero }
+    let aml = [0x12, 0x0a, 0x04, 0x0a, 0x05, 0x0a, 0x05, 0x00, 0x00];
+    assert_eq!(parse_s5_package(&aml), Some(SleepType { a: 5, b: 5 }));
+}
+
+#[test_case]
+fn parse_s5_package_with_constants() {
+    // Package (0x02) { Zero, One }
+    let aml = [0x12, 0x04, 0x02, 0x00, 0x01];
+    assert_eq!(parse_s5_package(&aml), Some(SleepType { a: 0, b: 1 }));
+}
+
+#[test_case]
+fn parse_s5_package_rejects_other_objects() {
+    assert_eq!(parse_s5_package(&[0x0a, 0x05]), None);
+    assert_eq!(parse_s5_package(&[0x12]), None);
+}
@@ -0,0 +1,181 @@
+use core::{
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    memory::{
+        address_space::{AddressSpace, AddressSpaceError, DuplicateMode},
+        with_kernel_memory,
+    },
+    thread,
+    user::{self, Exit},
+};
+
+/// The start of the area in which `map_anonymous` places mappings.
+pub const MMAP_START: u64 = 0x_2000_0000_0000;
+/// The end of the area in which `map_anonymous` places mappings.
+pub const MMAP_END: u64 = 0x_3000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    #[must_use]
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// A program in user mode with an address space of its own, which shares the
+/// kernel part of the kernel page table.
+///
+/// A process runs on the thread which calls `run`, and its address space is
+/// loaded whenever that thread is scheduled. Dropping the process gives the
+/// frames of its part of user mode back, including its page tables.
+#[derive(Debug)]
+pub struct Process {
+    id: ProcessId,
+    parent: Option<ProcessId>,
+    address_space: AddressSpace,
+    /// The start of the next mapping made by `map_anonymous`.
+    mmap_next: u64,
+}
+
+impl Process {
+    /// Creates a process with an empty part of user mode.
+    ///
+    /// # Errors
+    /// Returns an error if the address space could not be created.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        Ok(Self {
+            id: ProcessId::new(),
+            parent: None,
+            address_space: AddressSpace::new()?,
+            mmap_next: MMAP_START,
+        })
+    }
+
+    #[must_use]
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    /// Returns the process this one was forked from.
+    #[must_use]
+    pub fn parent(&self) -> Option<ProcessId> {
+        self.parent
+    }
+
+    #[must_use]
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    pub fn address_space_mut(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    /// Creates a child process with a duplicate of the part of user mode of
+    /// this one, see `AddressSpace::duplicate`.
+    ///
+    /// # Errors
+    /// Returns an error if the address space could not be duplicated.
+    pub fn fork(&mut self, mode: DuplicateMode) -> Result<Self, AddressSpaceError> {
+        Ok(Self {
+            id: ProcessId::new(),
+            parent: Some(self.id),
+            address_space: self.address_space.duplicate(mode)?,
+            mmap_next: self.mmap_next,
+        })
+    }
+
+    /// Maps zeroed pages covering `size` bytes with the given flags in the area
+    /// between `MMAP_START` and `MMAP_END`, and returns their start.
+    ///
+    /// The addresses of unmapped ranges are not reused.
+    ///
+    /// # Errors
+    /// Returns an error if the area has no room left, or fewer frames are free
+    /// than the pages need.
+    pub fn map_anonymous(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<VirtAddr, AddressSpaceError> {
+        let end = size
+            .checked_add(Size4KiB::SIZE - 1)
+            .and_then(|size| {
+                self.mmap_next
+                    .checked_add(x86_64::align_down(size, Size4KiB::SIZE))
+            })
+            .filter(|&end| end <= MMAP_END)
+            .ok_or(AddressSpaceError::Exhausted)?;
+        let start = VirtAddr::new(self.mmap_next);
+        let free_frames = with_kernel_memory(|_, frame_allocator| frame_allocator.stats().free)
+            .ok_or(AddressSpaceError::NotInitialized)?;
+        if (end - self.mmap_next) / Size4KiB::SIZE > free_frames as u64 {
+            return Err(MapToError::FrameAllocationFailed.into());
+        }
+        self.address_space.map_user(start, size, flags)?;
+        self.mmap_next = end;
+        Ok(start)
+    }
+
+    /// Unmaps the pages of the given range, which must lie in the area of
+    /// `map_anonymous`.
+    ///
+    /// # Errors
+    /// Returns an error if the range does not lie in the area.
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        let in_area = start.as_u64() >= MMAP_START
+            && start
+                .as_u64()
+                .checked_add(size)
+                .map_or(false, |end| end <= MMAP_END);
+        if !in_area {
+            return Err(AddressSpaceError::NotUserRange);
+        }
+        self.address_space.unmap_user(start, size)
+    }
+
+    /// Runs the process in user mode on the calling thread, starting at the
+    /// given instruction and stack pointer, until it exits or faults.
+    ///
+    /// Meanwhile, system calls made by the process find it with `with_current`,
+    /// so no reference to it is held while it runs.
+    pub fn run(&mut self, entry: VirtAddr, stack_pointer: VirtAddr) -> Exit {
+        let level_4_frame = self.address_space.level_4_frame();
+        thread::set_process(Some(RunningProcess(NonNull::from(self))));
+        let exit = user::enter(level_4_frame, entry, stack_pointer);
+        thread::set_process(None);
+        exit
+    }
+}
+
+/// The process a thread runs, recorded by `Process::run`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RunningProcess(NonNull<Process>);
+
+// the process is only accessed on behalf of the thread running it
+unsafe impl Send for RunningProcess {}
+
+/// Runs the given function with the process run by the calling thread, or
+/// returns `None` if it does not run one.
+///
+/// Used by system calls, while `Process::run` waits for the process to leave
+/// user mode.
+pub(crate) fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let RunningProcess(process) = thread::process()?;
+    Some(f(unsafe { &mut *process.as_ptr() }))
+}
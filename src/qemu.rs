@@ -6,6 +6,10 @@ pub enum ExitCode {
     Failed = 0x11,
 }
 
+/// Exits QEMU through the `isa-debug-exit` device, which is only attached
+/// when running the test harness.
+///
+/// Use `power::shutdown` to power off the machine in any other configuration.
 #[allow(dead_code)]
 pub fn exit(exit_code: ExitCode) -> ! {
     use x86_64::instructions::port::Port;
@@ -0,0 +1,385 @@
+use core::{
+    ptr::addr_of,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+        Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    acpi::madt::{Entry, Madt},
+    apic,
+    gdt::{self, InterruptStacks},
+    interrupt,
+    memory::{
+        self,
+        frame::BitmapFrameAllocator,
+        stack::{StackError, StackOwner},
+    },
+    percpu, syscall, thread,
+};
+
+/// How long to wait for an application processor to come online.
+const STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The number of application processors which have finished their startup.
+static ONLINE_APPLICATION_PROCESSORS: AtomicUsize = AtomicUsize::new(0);
+
+/// The function run by application processors once they are online.
+static APPLICATION_PROCESSOR_MAIN: Once<&'static (dyn Fn() -> ! + Sync)> = Once::new();
+
+/// The interrupt stacks of the application processor being started, which are
+/// allocated by the bootstrap processor since it owns the page table meanwhile.
+static NEXT_INTERRUPT_STACKS: Mutex<Option<InterruptStacks>> = Mutex::new(None);
+
+// The trampoline is copied to a page below 1 MiB, where application processors
+// start executing in real mode with CS set to the page. It switches directly
+// into long mode with the kernel page table and calls `ap_main` on the stack
+// stored in its data area, which `Trampoline::prepare` fills in.
+core::arch::global_asm!(
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [AP_TRAMPOLINE_GDTR_OFFSET]",
+    "mov eax, cr4",
+    "or eax, 1 << 5", // PAE
+    "mov cr4, eax",
+    "mov eax, [AP_TRAMPOLINE_CR3_OFFSET]",
+    "mov cr3, eax",
+    "mov ecx, 0xc0000080", // EFER
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)", // long mode, no-execute
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, (1 << 31) | (1 << 0)", // paging, protected mode
+    "mov cr0, eax",
+    // far jump into the 64-bit code segment, with the target patched at runtime
+    ".byte 0x66, 0xea",
+    "ap_trampoline_long_mode_target:",
+    ".long 0",
+    ".word 0x08",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor ax, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov rsp, [rip + ap_trampoline_stack]",
+    "mov rdi, [rip + ap_trampoline_argument]",
+    "call [rip + ap_trampoline_entry]",
+    "2:",
+    "hlt",
+    "jmp 2b",
+    ".align 8",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00af9a000000ffff", // 64-bit code
+    ".quad 0x00cf92000000ffff", // data
+    "ap_trampoline_gdtr:",
+    ".word 3 * 8 - 1",
+    ".long 0", // patched with the physical address of the GDT
+    ".align 8",
+    "ap_trampoline_cr3:",
+    ".quad 0",
+    "ap_trampoline_stack:",
+    ".quad 0",
+    "ap_trampoline_entry:",
+    ".quad 0",
+    "ap_trampoline_argument:",
+    ".quad 0",
+    "ap_trampoline_end:",
+    ".set AP_TRAMPOLINE_GDTR_OFFSET, ap_trampoline_gdtr - ap_trampoline_start",
+    ".set AP_TRAMPOLINE_CR3_OFFSET, ap_trampoline_cr3 - ap_trampoline_start",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode_target: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_argument: u8;
+    static ap_trampoline_end: u8;
+}
+
+#[derive(Debug)]
+pub enum SmpError {
+    /// The local APIC is not in use, so no inter-processor interrupts can be sent.
+    ApicDisabled,
+    /// No frame below 1 MiB is free for the trampoline.
+    NoLowMemory,
+    MappingFailed(MapToError<Size4KiB>),
+    UnmappingFailed(UnmapError),
+    /// The stacks of an application processor could not be allocated.
+    StackAllocationFailed(StackError),
+}
+
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        SmpError::MappingFailed(err)
+    }
+}
+
+impl From<UnmapError> for SmpError {
+    fn from(err: UnmapError) -> Self {
+        SmpError::UnmappingFailed(err)
+    }
+}
+
+impl From<StackError> for SmpError {
+    fn from(err: StackError) -> Self {
+        SmpError::StackAllocationFailed(err)
+    }
+}
+
+/// The page below 1 MiB into which the trampoline has been copied.
+struct Trampoline {
+    frame: PhysFrame<Size4KiB>,
+}
+
+impl Trampoline {
+    /// Returns the offset of the given trampoline symbol from its start.
+    fn offset(symbol: &u8) -> u64 {
+        symbol as *const u8 as u64 - unsafe { addr_of!(ap_trampoline_start) } as u64
+    }
+
+    /// Returns the virtual address through which the kernel accesses the given
+    /// trampoline symbol in the copy.
+    fn field(&self, symbol: &u8) -> VirtAddr {
+        memory::phys_to_virt(self.frame.start_address()) + Self::offset(symbol)
+    }
+
+    fn physical_address(&self, symbol: &u8) -> PhysAddr {
+        self.frame.start_address() + Self::offset(symbol)
+    }
+
+    /// Copies the trampoline into the given frame and patches its absolute addresses.
+    fn install(frame: PhysFrame<Size4KiB>) -> Self {
+        let trampoline = Self { frame };
+        unsafe {
+            let start = addr_of!(ap_trampoline_start);
+            let length = addr_of!(ap_trampoline_end) as usize - start as usize;
+            core::ptr::copy_nonoverlapping(
+                start,
+                trampoline.field(&*start).as_mut_ptr::<u8>(),
+                length,
+            );
+
+            let long_mode = trampoline.physical_address(&*addr_of!(ap_trampoline_long_mode));
+            let gdt = trampoline.physical_address(&*addr_of!(ap_trampoline_gdt));
+            trampoline
+                .field(&*addr_of!(ap_trampoline_long_mode_target))
+                .as_mut_ptr::<u32>()
+                .write_unaligned(long_mode.as_u64() as u32);
+            trampoline
+                .field(&*addr_of!(ap_trampoline_gdtr))
+                .as_mut_ptr::<u8>()
+                .add(2)
+                .cast::<u32>()
+                .write_unaligned(gdt.as_u64() as u32);
+            trampoline
+                .field(&*addr_of!(ap_trampoline_cr3))
+                .as_mut_ptr::<u64>()
+                .write(Cr3::read().0.start_address().as_u64());
+        }
+        trampoline
+    }
+
+    /// Sets the stack, entry function and its argument for the next processor to start.
+    fn prepare(&self, stack_end: VirtAddr, entry: extern "C" fn(u64) -> !, argument: u64) {
+        unsafe {
+            self.field(&*addr_of!(ap_trampoline_stack))
+                .as_mut_ptr::<u64>()
+                .write_volatile(stack_end.as_u64());
+            self.field(&*addr_of!(ap_trampoline_entry))
+                .as_mut_ptr::<u64>()
+                .write_volatile(entry as usize as u64);
+            self.field(&*addr_of!(ap_trampoline_argument))
+                .as_mut_ptr::<u64>()
+                .write_volatile(argument);
+        }
+    }
+}
+
+/// Starts all application processors listed in the MADT, and returns the
+/// number of CPUs which are online, including the bootstrap processor.
+///
+/// Requires the kernel heap and the APIC, and must be called with interrupts
+/// enabled. The real mode trampoline is placed in a free frame below 1 MiB,
+/// which is freed again once every started processor has come online.
+///
+/// # Errors
+/// Returns an error if the APIC is not in use or the trampoline could not be
+/// set up, in which case only the bootstrap processor is running, or if the
+/// stacks of a processor could not be allocated, in which case the processors
+/// started before keep running.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<usize, SmpError> {
+    let madt = Madt::find()
+        .filter(|_| apic::is_enabled())
+        .ok_or(SmpError::ApicDisabled)?;
+
+    let frames = frame_allocator
+        .allocate_contiguous_below(1, 1, PhysAddr::new(0x10_0000))
+        .ok_or(SmpError::NoLowMemory)?;
+    let frame = frames.start;
+    // the trampoline keeps executing at its physical address after enabling paging
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let newly_mapped = match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => false,
+        Err(err) => {
+            unsafe { frame_allocator.deallocate_contiguous(frames) };
+            return Err(err.into());
+        }
+    };
+    // `protection::remap_kernel` may have made the level 4 entry of the page not executable
+    let level_4_entry = &mut mapper.level_4_table()[page.p4_index()];
+    let level_4_flags = level_4_entry.flags();
+    level_4_entry.set_flags(level_4_flags - PageTableFlags::NO_EXECUTE);
+    tlb::flush_all();
+
+    let trampoline = Trampoline::install(frame);
+    let bootstrap_apic_id = apic::local_apic_id();
+    let mut next_cpu_id = 1;
+    let mut all_online = true;
+    let mut result = Ok(());
+
+    for entry in madt.entries() {
+        let Entry::LocalApic { apic_id, flags, .. } = entry else { continue };
+        // processors which are neither enabled nor online capable cannot be started
+        if apic_id == bootstrap_apic_id || flags & 0b11 == 0 {
+            continue;
+        }
+
+        // the stacks of a processor which does not come online are never freed
+        let stack_end = match allocate_stacks(mapper, frame_allocator, next_cpu_id as usize) {
+            Ok(stack_end) => stack_end,
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        };
+        trampoline.prepare(stack_end, ap_main, next_cpu_id);
+
+        let online = ONLINE_APPLICATION_PROCESSORS.load(Ordering::SeqCst);
+        apic::start_application_processor(apic_id, (frame.start_address().as_u64() >> 12) as u8);
+
+        let start = crate::time::uptime();
+        while ONLINE_APPLICATION_PROCESSORS.load(Ordering::SeqCst) == online
+            && crate::time::uptime() - start < STARTUP_TIMEOUT
+        {
+            x86_64::instructions::hlt();
+        }
+        if ONLINE_APPLICATION_PROCESSORS.load(Ordering::SeqCst) > online {
+            next_cpu_id += 1;
+        } else {
+            all_online = false;
+        }
+    }
+
+    mapper.level_4_table()[page.p4_index()].set_flags(level_4_flags);
+    tlb::flush_all();
+    if newly_mapped {
+        mapper.unmap(page)?.1.flush();
+    }
+    // a processor which did not come online in time might still run the trampoline
+    if all_online {
+        unsafe { frame_allocator.deallocate_contiguous(frames) };
+    }
+
+    result?;
+    Ok(1 + ONLINE_APPLICATION_PROCESSORS.load(Ordering::SeqCst))
+}
+
+/// Allocates the boot stack and interrupt stacks of the given application
+/// processor, and returns the top of the boot stack.
+fn allocate_stacks(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    cpu: usize,
+) -> Result<VirtAddr, StackError> {
+    let stack = memory::stack::allocate_with(
+        mapper,
+        frame_allocator,
+        thread::STACK_SIZE as u64,
+        StackOwner::Boot { cpu },
+    )?;
+    let interrupt_stacks = InterruptStacks::allocate(mapper, frame_allocator, cpu)?;
+    *NEXT_INTERRUPT_STACKS.lock() = Some(interrupt_stacks);
+    Ok(stack.top())
+}
+
+/// Returns the number of CPUs which are online, including the bootstrap processor.
+#[must_use]
+pub fn online_cpus() -> usize {
+    1 + ONLINE_APPLICATION_PROCESSORS.load(Ordering::SeqCst)
+}
+
+/// Lets all application processors run the given function, which never returns.
+///
+/// Processors which come online later also run the function. Must be called
+/// only once.
+pub fn run_on_application_processors(main: &'static (dyn Fn() -> ! + Sync)) {
+    assert!(
+        APPLICATION_PROCESSOR_MAIN.get().is_none(),
+        "application processors are already running"
+    );
+    APPLICATION_PROCESSOR_MAIN.call_once(|| main);
+    interrupt::wake_other_cpus();
+}
+
+/// Entry point of application processors, called by the trampoline.
+extern "C" fn ap_main(cpu_id: u64) -> ! {
+    let interrupt_stacks = NEXT_INTERRUPT_STACKS
+        .lock()
+        .take()
+        .expect("interrupt stacks are allocated before startup");
+    percpu::init_ap(cpu_id as usize);
+    gdt::init_ap(interrupt_stacks);
+    memory::protection::enable();
+    memory::mmio::init_pat();
+    syscall::init();
+    interrupt::init_idt();
+    apic::init_ap();
+
+    ONLINE_APPLICATION_PROCESSORS.fetch_add(1, Ordering::SeqCst);
+
+    // idle until there is something to run, checking with interrupts disabled
+    // so that the wakeup interrupt cannot arrive between the check and `hlt`
+    loop {
+        interrupts::disable();
+        if let Some(main) = APPLICATION_PROCESSOR_MAIN.get() {
+            interrupts::enable();
+            main();
+        }
+        interrupts::enable_and_hlt();
+    }
+}
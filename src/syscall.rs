@@ -0,0 +1,306 @@
+use core::time::Duration;
+
+use alloc::{string::String, vec};
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    gdt,
+    memory::user_copy,
+    print,
+    process::{self, Process},
+    serial_print,
+    task::keyboard,
+    thread, time, user,
+};
+
+// System calls, by number. Each returns a non-negative result, or the negated
+// error code on failure.
+
+/// `exit(code) -> !`: terminates the calling program with the given exit code.
+pub const SYS_EXIT: u64 = 0;
+/// `write(fd, buf, len) -> written`: writes up to `MAX_IO_SIZE` bytes of the
+/// buffer to `STDOUT`, which is the screen, or `STDERR`, which is the serial port.
+pub const SYS_WRITE: u64 = 1;
+/// `read(fd, buf, len) -> read`: reads up to `len` bytes of typed text from
+/// `STDIN` to the buffer, waiting until at least one byte is available.
+pub const SYS_READ: u64 = 2;
+/// `getpid() -> pid`: returns the id of the calling process.
+pub const SYS_GETPID: u64 = 3;
+/// `yield() -> 0`: gives up the remaining time slice of the calling thread.
+pub const SYS_YIELD: u64 = 4;
+/// `sleep(milliseconds) -> 0`: waits until at least the given time has elapsed.
+pub const SYS_SLEEP: u64 = 5;
+/// `mmap(addr, len, prot, flags, fd, offset) -> addr`: maps zeroed pages
+/// covering `len` bytes, at most `MAX_MMAP_SIZE`. Only private, anonymous
+/// mappings with an offset of 0 are supported; `addr` and `fd` are ignored.
+pub const SYS_MMAP: u64 = 6;
+/// `munmap(addr, len) -> 0`: unmaps the pages of a range mapped by `mmap`.
+pub const SYS_MUNMAP: u64 = 7;
+
+// file descriptors
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+// protection and flags of `mmap`
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+// error codes, as on Linux
+/// A file descriptor is not open for the operation.
+pub const EBADF: i64 = 9;
+/// No memory is left for the mapping.
+pub const ENOMEM: i64 = 12;
+/// A buffer is not accessible by the calling program.
+pub const EFAULT: i64 = 14;
+/// An argument is invalid.
+pub const EINVAL: i64 = 22;
+/// The system call number is unknown.
+pub const ENOSYS: i64 = 38;
+
+/// The maximum number of bytes transferred by a single `read` or `write`.
+pub const MAX_IO_SIZE: u64 = 4096;
+/// The maximum number of bytes mapped by a single `mmap`.
+pub const MAX_MMAP_SIZE: u64 = 64 * 1024 * 1024;
+
+type Handler = fn(&mut Process, [u64; 6]) -> Result<u64, i64>;
+
+/// The handlers of the system calls, indexed by their number.
+const TABLE: [Handler; 8] = [
+    sys_exit, sys_write, sys_read, sys_getpid, sys_yield, sys_sleep, sys_mmap, sys_munmap,
+];
+
+// The entry of the `syscall` instruction. The CPU has saved the instruction
+// pointer of user mode in RCX and its flags in R11, but keeps the stack
+// pointer of user mode, so the entry swaps GS and switches to the kernel
+// stack of the CPU (`PerCpu::kernel_stack` at `gs:[8]`), keeping the stack
+// pointer of user mode in `PerCpu::user_stack` at `gs:[16]` meanwhile.
+//
+// The system call number is passed in RAX and up to six arguments in RDI,
+// RSI, RDX, R10, R8 and R9. The result is returned in RAX; RCX and R11 are
+// clobbered, and every other register is preserved.
+core::arch::global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[16], rsp",
+    "mov rsp, gs:[8]",
+    "push qword ptr gs:[16]",
+    "push r11",
+    "push rcx",
+    "push rax",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r10",
+    "push r8",
+    "push r9",
+    "mov rdi, rsp",
+    "sti",
+    "call {handler}",
+    "cli",
+    "pop r9",
+    "pop r8",
+    "pop r10",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rax",
+    "pop rcx",
+    "pop r11",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    handler = sym syscall_handler,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// The registers of user mode saved by the system call entry. The layout must
+/// match the push order of the entry exactly.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    #[must_use]
+    pub fn number(&self) -> u64 {
+        self.rax
+    }
+
+    #[must_use]
+    pub fn arguments(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+/// Enables the `syscall` instruction on the calling CPU.
+///
+/// Must be called on every CPU, since the MSRs are per CPU.
+///
+/// # Panics
+/// Panics if the segments of the GDT are not laid out as `sysret` expects.
+pub fn init() {
+    Star::write(
+        gdt::USER_CODE_SELECTOR,
+        gdt::USER_DATA_SELECTOR,
+        gdt::KERNEL_CODE_SELECTOR,
+        gdt::KERNEL_DATA_SELECTOR,
+    )
+    .expect("GDT is not laid out for sysret");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // entered with interrupts disabled until the stack is switched
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+}
+
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    let arguments = frame.arguments();
+    let handler = usize::try_from(frame.number())
+        .ok()
+        .and_then(|number| TABLE.get(number));
+    let result = match handler {
+        Some(handler) => process::with_current(|process| handler(process, arguments))
+            .expect("system call without a running process"),
+        None => Err(ENOSYS),
+    };
+    frame.rax = result.unwrap_or_else(error);
+}
+
+/// Encodes an error code as the result of a system call, which is its negation.
+#[allow(clippy::cast_sign_loss)]
+fn error(code: i64) -> u64 {
+    (-code) as u64
+}
+
+/// Returns the given argument as a buffer of the calling program, if user
+/// mode may access all of it.
+fn user_buffer(process: &Process, addr: u64, len: u64, write: bool) -> Result<VirtAddr, i64> {
+    let addr = VirtAddr::try_new(addr).map_err(|_| EFAULT)?;
+    process
+        .address_space()
+        .check_access(addr, len, write)
+        .map_err(|_| EFAULT)?;
+    Ok(addr)
+}
+
+fn sys_exit(_: &mut Process, [code, ..]: [u64; 6]) -> Result<u64, i64> {
+    user::exit(code)
+}
+
+fn sys_write(_: &mut Process, [fd, buf, len, ..]: [u64; 6]) -> Result<u64, i64> {
+    let output: fn(&str) = match fd {
+        STDOUT => |s| print!("{s}"),
+        STDERR => |s| serial_print!("{s}"),
+        _ => return Err(EBADF),
+    };
+    let len = len.min(MAX_IO_SIZE);
+    let buf = VirtAddr::try_new(buf).map_err(|_| EFAULT)?;
+
+    let mut data = vec![0; len as usize];
+    user_copy::copy_from_user(&mut data, buf).map_err(|_| EFAULT)?;
+    output(&String::from_utf8_lossy(&data));
+    Ok(len)
+}
+
+fn sys_read(process: &mut Process, [fd, buf, len, ..]: [u64; 6]) -> Result<u64, i64> {
+    if fd != STDIN {
+        return Err(EBADF);
+    }
+    let len = len.min(MAX_IO_SIZE);
+    // fail before taking input, which a faulting copy would lose
+    let buf = user_buffer(process, buf, len, true)?;
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let mut data = vec![keyboard::wait_input()];
+    while data.len() < len as usize {
+        match keyboard::pop_input() {
+            Some(byte) => data.push(byte),
+            None => break,
+        }
+    }
+    user_copy::copy_to_user(buf, &data).map_err(|_| EFAULT)?;
+    Ok(data.len() as u64)
+}
+
+#[allow(clippy::unnecessary_wraps)] // every handler returns a `Result`
+fn sys_getpid(process: &mut Process, _: [u64; 6]) -> Result<u64, i64> {
+    Ok(process.id().as_u64())
+}
+
+#[allow(clippy::unnecessary_wraps)] // every handler returns a `Result`
+fn sys_yield(_: &mut Process, _: [u64; 6]) -> Result<u64, i64> {
+    thread::yield_now();
+    Ok(0)
+}
+
+#[allow(clippy::unnecessary_wraps)] // every handler returns a `Result`
+fn sys_sleep(_: &mut Process, [milliseconds, ..]: [u64; 6]) -> Result<u64, i64> {
+    let deadline = time::uptime().saturating_add(Duration::from_millis(milliseconds));
+    thread::sleep_until(deadline);
+    Ok(0)
+}
+
+fn sys_mmap(process: &mut Process, [_, len, prot, flags, _, offset]: [u64; 6]) -> Result<u64, i64> {
+    let valid = len > 0
+        && prot != 0
+        && prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) == 0
+        && flags == MAP_PRIVATE | MAP_ANONYMOUS
+        && offset == 0;
+    if !valid {
+        return Err(EINVAL);
+    }
+    if len > MAX_MMAP_SIZE {
+        return Err(ENOMEM);
+    }
+    let mut page_flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let addr = process.map_anonymous(len, page_flags).map_err(|_| ENOMEM)?;
+    Ok(addr.as_u64())
+}
+
+fn sys_munmap(process: &mut Process, [addr, len, ..]: [u64; 6]) -> Result<u64, i64> {
+    let addr = VirtAddr::try_new(addr).map_err(|_| EINVAL)?;
+    if len == 0 || !addr.is_aligned(Size4KiB::SIZE) {
+        return Err(EINVAL);
+    }
+    process.unmap(addr, len).map_err(|_| EINVAL)?;
+    Ok(0)
+}
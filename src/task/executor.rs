@@ -1,10 +1,21 @@
-use core::task::{Context, Poll, Waker};
+use core::{
+    ptr::NonNull,
+    sync::atomic::{self, AtomicUsize, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{collections::BTreeMap, sync::Arc};
 use crossbeam_queue::ArrayQueue;
 use x86_64::instructions::interrupts;
 
 use super::{Task, TaskId};
+use crate::{
+    allocator::slab::{SlabBox, SlabCache},
+    time,
+};
+
+/// The wakers of the tasks of all executors, see `TaskWaker`.
+static WAKERS: SlabCache<TaskWaker> = SlabCache::without_constructor("task_waker");
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
@@ -23,7 +34,7 @@ impl Executor {
     }
 
     pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
+        let task_id = task.id();
         if self.tasks.insert(task_id, task).is_some() {
             panic!("task with same id already exists: this might be a bug.");
         }
@@ -32,6 +43,7 @@ impl Executor {
 
     pub fn run(&mut self) -> ! {
         loop {
+            time::wake_sleepers();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
@@ -71,17 +83,30 @@ impl Default for Executor {
     }
 }
 
+/// The waker of a task, which is allocated from `WAKERS` and freed once its
+/// last clone is dropped, like an `Arc`.
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    references: AtomicUsize,
 }
 
 impl TaskWaker {
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(
+        Self::clone_raw,
+        Self::wake_raw,
+        Self::wake_by_ref_raw,
+        Self::drop_raw,
+    );
+
     fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+        let waker = WAKERS.allocate_with(TaskWaker {
             task_id,
             task_queue,
-        }))
+            references: AtomicUsize::new(1),
+        });
+        let ptr = SlabBox::into_raw(waker).as_ptr();
+        unsafe { Waker::from_raw(RawWaker::new(ptr.cast(), &Self::VTABLE)) }
     }
 
     fn wake_task(&self) {
@@ -89,14 +114,28 @@ impl TaskWaker {
             .push(self.task_id)
             .expect("task queue is full");
     }
-}
 
-impl Wake for TaskWaker {
-    fn wake(self: Arc<Self>) {
-        self.wake_task();
+    unsafe fn clone_raw(ptr: *const ()) -> RawWaker {
+        let waker = &*ptr.cast::<TaskWaker>();
+        waker.references.fetch_add(1, Ordering::Relaxed);
+        RawWaker::new(ptr, &Self::VTABLE)
+    }
+
+    unsafe fn wake_raw(ptr: *const ()) {
+        Self::wake_by_ref_raw(ptr);
+        Self::drop_raw(ptr);
+    }
+
+    unsafe fn wake_by_ref_raw(ptr: *const ()) {
+        (*ptr.cast::<TaskWaker>()).wake_task();
     }
 
-    fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+    unsafe fn drop_raw(ptr: *const ()) {
+        let waker = &*ptr.cast::<TaskWaker>();
+        if waker.references.fetch_sub(1, Ordering::Release) == 1 {
+            atomic::fence(Ordering::Acquire);
+            let ptr = NonNull::new_unchecked(ptr.cast_mut().cast::<TaskWaker>());
+            drop(SlabBox::from_raw(ptr, &WAKERS));
+        }
     }
 }
@@ -5,10 +5,15 @@ use futures_util::{task::AtomicWaker, Stream, StreamExt};
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts::Us104Key, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 
-use crate::{print, println};
+use crate::{
+    print, println,
+    thread::{self, WaitChannel},
+};
 
 lazy_static! {
     pub static ref SCANCODE_QUEUE: ArrayQueue<u8> = ArrayQueue::new(100);
+    /// Typed text, as UTF-8, which programs read from standard input.
+    static ref INPUT_QUEUE: ArrayQueue<u8> = ArrayQueue::new(256);
 }
 
 static WAKER: AtomicWaker = AtomicWaker::new();
@@ -24,6 +29,34 @@ pub(crate) fn add_scancode(scancode: u8) {
     }
 }
 
+/// Appends the given bytes to the text read from standard input, dropping
+/// those which do not fit.
+pub fn push_input(bytes: &[u8]) {
+    for &byte in bytes {
+        if INPUT_QUEUE.push(byte).is_err() {
+            break;
+        }
+    }
+    thread::wake_all(input_channel());
+}
+
+/// Takes the next byte of the text read from standard input.
+#[must_use]
+pub fn pop_input() -> Option<u8> {
+    INPUT_QUEUE.pop()
+}
+
+/// Takes the next byte of the text read from standard input, blocking the
+/// calling thread until one is available.
+pub fn wait_input() -> u8 {
+    thread::wait(input_channel(), pop_input)
+}
+
+/// The channel on which threads wait for standard input.
+fn input_channel() -> WaitChannel {
+    WaitChannel::of(&*INPUT_QUEUE)
+}
+
 pub struct ScancodeStream {
     _private: (),
 }
@@ -70,7 +103,10 @@ pub async fn print_keypress() {
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
             if let Some(key) = keyboard.process_keyevent(key_event) {
                 match key {
-                    DecodedKey::Unicode(char) => print!("{char}"),
+                    DecodedKey::Unicode(char) => {
+                        print!("{char}");
+                        push_input(char.encode_utf8(&mut [0; 4]).as_bytes());
+                    }
                     DecodedKey::RawKey(key) => print!("{key:?}"),
                 }
             }
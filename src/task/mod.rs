@@ -7,25 +7,46 @@ use core::{
 
 use alloc::boxed::Box;
 
+use crate::allocator::slab::{SlabBox, SlabCache};
+
 pub mod executor;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod work_stealing_executor;
+
+/// The records of all tasks, see `Task`.
+static TASKS: SlabCache<TaskRecord> = SlabCache::without_constructor("task");
 
+/// A future which runs to completion in an executor.
+///
+/// The future must be `Send`, since the work-stealing executor may poll a
+/// task on any CPU. The record of the task is allocated from a slab cache,
+/// while the future itself, whose size varies, is boxed.
 pub struct Task {
+    record: SlabBox<TaskRecord>,
+}
+
+struct TaskRecord {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
-            id: TaskId::new(),
-            future: Box::pin(future),
+            record: TASKS.allocate_with(TaskRecord {
+                id: TaskId::new(),
+                future: Box::pin(future),
+            }),
         }
     }
 
+    fn id(&self) -> TaskId {
+        self.record.id
+    }
+
     fn poll(&mut self, cx: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(cx)
+        self.record.future.as_mut().poll(cx)
     }
 }
 
@@ -0,0 +1,155 @@
+use core::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::{sync::Arc, task::Wake, vec::Vec};
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::Task;
+use crate::{interrupt, percpu, smp, time};
+
+const QUEUE_CAPACITY: usize = 100;
+
+/// An executor which runs tasks on multiple CPUs.
+///
+/// Every CPU has its own ready queue. Tasks are pushed onto the queue of the
+/// CPU which spawns or wakes them, and CPUs which run out of tasks steal from
+/// the queues of the other CPUs.
+pub struct WorkStealingExecutor {
+    queues: Vec<ArrayQueue<Arc<TaskCell>>>,
+    /// The number of CPUs which are halted because they found no task to run.
+    idle_cpus: AtomicUsize,
+}
+
+impl WorkStealingExecutor {
+    /// Creates an executor with a ready queue for every CPU which is online.
+    ///
+    /// Should be called after `smp::init`, since CPUs which come online later
+    /// share the ready queues of the others.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            queues: (0..smp::online_cpus())
+                .map(|_| ArrayQueue::new(QUEUE_CAPACITY))
+                .collect(),
+            idle_cpus: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn spawn(&'static self, task: Task) {
+        let cell = Arc::new(TaskCell {
+            executor: self,
+            task: Mutex::new(Some(task)),
+            queued: AtomicBool::new(true),
+        });
+        self.push(cell);
+    }
+
+    /// Runs tasks on the calling CPU. May be called on any number of CPUs at once.
+    ///
+    /// Sleeping tasks are only woken up by CPUs which receive timer
+    /// interrupts, so the bootstrap processor should run the executor as well.
+    pub fn run(&'static self) -> ! {
+        loop {
+            time::wake_sleepers();
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn local_queue(&self) -> usize {
+        percpu::current().id % self.queues.len()
+    }
+
+    /// Pushes a task onto the ready queue of the calling CPU, and wakes up idle
+    /// CPUs so that they can steal it.
+    ///
+    /// Must not block or allocate.
+    fn push(&self, cell: Arc<TaskCell>) {
+        if self.queues[self.local_queue()].push(cell).is_err() {
+            panic!("task queue is full");
+        }
+        if self.idle_cpus.load(Ordering::SeqCst) > 0 {
+            interrupt::wake_other_cpus();
+        }
+    }
+
+    /// Pops a task from the local ready queue, or steals one from another CPU.
+    fn next_task(&self) -> Option<Arc<TaskCell>> {
+        let local = self.local_queue();
+        let count = self.queues.len();
+        (0..count).find_map(|i| self.queues[(local + i) % count].pop())
+    }
+
+    fn run_ready_tasks(&self) {
+        while let Some(cell) = self.next_task() {
+            cell.poll();
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        interrupts::disable();
+        // announce the CPU as idle before checking the queues, so that a task
+        // pushed right after the check sends a wakeup interrupt
+        self.idle_cpus.fetch_add(1, Ordering::SeqCst);
+        if self.queues.iter().all(ArrayQueue::is_empty) {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+        self.idle_cpus.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Default for WorkStealingExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A task shared between the ready queues and its wakers.
+struct TaskCell {
+    executor: &'static WorkStealingExecutor,
+    /// The task, or `None` once it has completed.
+    task: Mutex<Option<Task>>,
+    /// Whether the task is in one of the ready queues.
+    queued: AtomicBool,
+}
+
+impl TaskCell {
+    fn poll(self: Arc<Self>) {
+        let Some(mut task) = self.task.try_lock() else {
+            // woken up while it is being polled on another CPU; try again later
+            self.executor.push(self.clone());
+            return;
+        };
+        let Some(running) = task.as_mut() else { return };
+
+        self.queued.store(false, Ordering::SeqCst);
+        let waker = Waker::from(self.clone());
+        let mut cx = Context::from_waker(&waker);
+        match running.poll(&mut cx) {
+            Poll::Ready(()) => *task = None,
+            Poll::Pending => {}
+        }
+    }
+
+    fn wake_task(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            self.executor.push(self.clone());
+        }
+    }
+}
+
+impl Wake for TaskCell {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
@@ -0,0 +1,101 @@
+/// Register state of a thread that has been switched out.
+///
+/// The general purpose registers are pushed by the entry stub generated with
+/// [`context_switch_entry!`], and the remaining fields form the interrupt
+/// stack frame pushed by the CPU. The layout must match the push order of
+/// the stub exactly.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Context {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // pushed by the CPU on interrupt entry
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Defines an interrupt entry stub which saves the full register state of
+/// the interrupted thread on its stack and calls `$handler` with the stack
+/// pointer of the saved [`Context`].
+///
+/// The handler must have the signature `extern "C" fn(u64) -> u64` and
+/// return the stack pointer of the context to resume, which is either the
+/// one it was given or one previously saved by the scheduler.
+///
+/// If the interrupted context runs in user mode, the stub swaps GS to the
+/// per-CPU data of the kernel, and it swaps GS back if the resumed context
+/// runs in user mode.
+macro_rules! context_switch_entry {
+    ($entry:ident => $handler:path) => {
+        core::arch::global_asm!(
+            concat!(".global ", stringify!($entry)),
+            concat!(stringify!($entry), ":"),
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push rbp",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "test qword ptr [rsp + 128], 3", // privilege level of the saved CS
+            "jz 2f",
+            "swapgs",
+            "2:",
+            "cld",
+            "mov rdi, rsp",
+            "call {handler}",
+            "mov rsp, rax",
+            "test qword ptr [rsp + 128], 3",
+            "jz 3f",
+            "swapgs",
+            "3:",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+            "iretq",
+            handler = sym $handler,
+        );
+
+        extern "C" {
+            fn $entry();
+        }
+    };
+}
+
+pub(crate) use context_switch_entry;
@@ -0,0 +1,241 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use alloc::boxed::Box;
+use x86_64::{
+    instructions::segmentation::{Segment, CS},
+    structures::paging::PhysFrame,
+    VirtAddr,
+};
+
+use self::context::Context;
+use crate::{
+    memory::stack::{self, KernelStack, StackOwner},
+    process::RunningProcess,
+    time,
+};
+
+pub mod context;
+pub mod scheduler;
+
+/// The size of the stack allocated for each spawned thread.
+pub const STACK_SIZE: usize = 4096 * 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// The thread which booted the kernel and runs `kernel_main`.
+    pub const BOOT: ThreadId = ThreadId(0);
+
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Something threads block on until it is signalled with `wake_all`,
+/// identified by the address of a static.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitChannel(usize);
+
+impl WaitChannel {
+    #[must_use]
+    pub fn of<T>(object: &'static T) -> Self {
+        Self((object as *const T) as usize)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Runnable,
+    /// Not scheduled until `wake_all` is called with the channel, or the
+    /// uptime reaches the deadline.
+    Blocked {
+        channel: Option<WaitChannel>,
+        deadline: Option<Duration>,
+    },
+    Exited,
+}
+
+struct Thread {
+    id: ThreadId,
+    state: State,
+    stack_pointer: u64,
+    stack: Option<KernelStack>, // the boot thread runs on the bootloader's stack
+    /// The stack on which the kernel is entered while the thread runs in user mode.
+    kernel_entry_stack: Option<VirtAddr>,
+    /// The level 4 frame of the address space the thread runs in, or `None`
+    /// for the kernel page table.
+    level_4_frame: Option<PhysFrame>,
+    /// The process the thread runs, see `process::with_current`.
+    process: Option<RunningProcess>,
+}
+
+impl Thread {
+    fn new(main: Box<dyn FnOnce() + Send>) -> Self {
+        let id = ThreadId::new();
+        let stack = stack::allocate(STACK_SIZE as u64, StackOwner::Thread(id))
+            .expect("kernel stack allocation failed");
+        let stack_end = stack.top().as_u64();
+
+        // The entry function expects the stack to be aligned as if its
+        // return address has just been pushed.
+        let entry_stack_pointer = x86_64::align_down(stack_end, 16) - 8;
+        let context_ptr =
+            (entry_stack_pointer - core::mem::size_of::<Context>() as u64) as *mut Context;
+        let context = Context {
+            rdi: Box::into_raw(Box::new(main)) as u64,
+            rip: thread_entry as usize as u64,
+            cs: u64::from(CS::get_reg().0),
+            rflags: 0x202, // interrupts enabled
+            rsp: entry_stack_pointer,
+            ss: 0,
+            ..Context::default()
+        };
+        unsafe {
+            context_ptr.write(context);
+        }
+
+        Self {
+            id,
+            state: State::Runnable,
+            stack_pointer: context_ptr as u64,
+            stack: Some(stack),
+            kernel_entry_stack: None,
+            level_4_frame: None,
+            process: None,
+        }
+    }
+
+    fn boot() -> Self {
+        Self {
+            id: ThreadId::BOOT,
+            state: State::Runnable,
+            stack_pointer: 0, // saved on the first switch
+            stack: None,
+            kernel_entry_stack: None,
+            level_4_frame: None,
+            process: None,
+        }
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack.take() {
+            stack::free(stack).expect("freeing the kernel stack failed");
+        }
+    }
+}
+
+extern "C" fn thread_entry(main: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    exit();
+}
+
+/// Spawns a new kernel thread running the given function.
+///
+/// The thread gets its own kernel stack with a guard page below it, so that
+/// an overflow is reported by the page fault handler, and is preempted by the
+/// timer interrupt, so it may run for an arbitrary amount of time without
+/// starving other threads. Threads only run on the bootstrap processor,
+/// which alone runs the timer interrupt.
+///
+/// Requires the kernel page table to be handed over with
+/// `memory::init_demand_paging`, since the stack is mapped with it.
+pub fn spawn(main: impl FnOnce() + Send + 'static) -> ThreadId {
+    let thread = Thread::new(Box::new(main));
+    let thread_id = thread.id;
+    scheduler::spawn(thread);
+    thread_id
+}
+
+/// Returns the id of the calling thread.
+#[must_use]
+pub fn current() -> ThreadId {
+    scheduler::current()
+}
+
+/// Records the stack on which the kernel is entered while the calling thread
+/// runs in user mode, so that it is restored whenever the thread is resumed.
+pub(crate) fn set_kernel_entry_stack(top: Option<VirtAddr>) {
+    scheduler::with_current(|thread| thread.kernel_entry_stack = top);
+}
+
+/// Records the address space the calling thread runs in, so that it is loaded
+/// in CR3 whenever the thread is resumed, or `None` for the kernel page table.
+pub(crate) fn set_level_4_frame(level_4_frame: Option<PhysFrame>) {
+    scheduler::with_current(|thread| thread.level_4_frame = level_4_frame);
+}
+
+/// Records the process the calling thread runs, or `None` once it returns to the kernel.
+pub(crate) fn set_process(process: Option<RunningProcess>) {
+    scheduler::with_current(|thread| thread.process = process);
+}
+
+/// Returns the process the calling thread runs.
+pub(crate) fn process() -> Option<RunningProcess> {
+    scheduler::with_current(|thread| thread.process)
+}
+
+/// Gives up the remaining time slice of the calling thread.
+///
+/// # Panics
+/// Panics if called on another CPU than the bootstrap processor.
+pub fn yield_now() {
+    unsafe {
+        asm!("int 0x81"); // interrupt::InterruptIndex::Yield
+    }
+}
+
+/// Blocks the calling thread until `ready` returns `Some`, which is checked
+/// again whenever `wake_all` is called with the given channel.
+pub fn wait<R>(channel: WaitChannel, ready: impl FnMut() -> Option<R>) -> R {
+    block_until(Some(channel), None, ready)
+}
+
+/// Blocks the calling thread until the uptime reaches the given deadline.
+pub fn sleep_until(deadline: Duration) {
+    block_until(None, Some(deadline), || {
+        (time::uptime() >= deadline).then_some(())
+    });
+}
+
+/// Makes every thread blocked on the given channel runnable again.
+///
+/// Does not allocate, so it may be called from interrupt handlers.
+pub fn wake_all(channel: WaitChannel) {
+    scheduler::wake_all(channel);
+}
+
+fn block_until<R>(
+    channel: Option<WaitChannel>,
+    deadline: Option<Duration>,
+    mut ready: impl FnMut() -> Option<R>,
+) -> R {
+    loop {
+        // blocked before checking, so that a wakeup in between is not lost
+        scheduler::set_current_state(State::Blocked { channel, deadline });
+        if let Some(result) = ready() {
+            scheduler::set_current_state(State::Runnable);
+            return result;
+        }
+        yield_now();
+    }
+}
+
+/// Terminates the calling thread.
+///
+/// # Panics
+/// Panics if called from the boot thread.
+pub fn exit() -> ! {
+    scheduler::mark_current_exited();
+    loop {
+        yield_now();
+    }
+}
@@ -0,0 +1,258 @@
+use core::time::Duration;
+
+use alloc::{boxed::Box, vec::Vec};
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr3, Cr3Flags},
+};
+
+use super::{State, Thread, ThreadId, WaitChannel};
+use crate::{gdt, memory, percpu, time};
+
+/// The maximum number of threads that can be alive at the same time,
+/// including the boot thread.
+const MAX_THREADS: usize = 64;
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+/// A round-robin scheduler which switches between kernel threads.
+///
+/// The scheduler is invoked from interrupt context, where the heap must not
+/// be touched: the interrupted code might hold the allocator lock. The same
+/// holds while `SCHEDULER` is locked, since the lock is taken with interrupts
+/// disabled and a preempted holder of the allocator lock could not run again.
+/// Therefore thread records are boxed before they are handed to
+/// [`spawn`](Scheduler::spawn), and those taken by [`reap`](Scheduler::reap)
+/// are dropped after the lock is released, while switching only moves thread
+/// ids between fixed-capacity queues.
+///
+/// Threads run on the bootstrap processor only: `current`, and the kernel
+/// stack and CR3 switched with it, are not per CPU, so everything acting on
+/// the current thread asserts that it is called there.
+struct Scheduler {
+    /// Never grows beyond its initial capacity of `MAX_THREADS`.
+    threads: Vec<Box<Thread>>,
+    ready_queue: ArrayQueue<ThreadId>,
+    exited_queue: ArrayQueue<ThreadId>,
+    current: ThreadId,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        let mut threads = Vec::with_capacity(MAX_THREADS);
+        threads.push(Box::new(Thread::boot()));
+        Self {
+            threads,
+            ready_queue: ArrayQueue::new(MAX_THREADS),
+            exited_queue: ArrayQueue::new(MAX_THREADS),
+            current: ThreadId::BOOT,
+        }
+    }
+
+    fn spawn(&mut self, thread: Box<Thread>) {
+        let thread_id = thread.id;
+        if self.thread_mut(thread_id).is_some() {
+            panic!("thread with same id already exists: this might be a bug.");
+        }
+        assert!(self.threads.len() < MAX_THREADS, "too many threads");
+        self.threads.push(thread);
+        self.ready_queue
+            .push(thread_id)
+            .expect("too many threads");
+    }
+
+    /// Takes the records of all threads which have exited, whose stacks are
+    /// freed once the caller drops them.
+    fn reap(&mut self) -> [Option<Box<Thread>>; MAX_THREADS] {
+        let mut exited = core::array::from_fn(|_| None);
+        for slot in &mut exited {
+            let Some(thread_id) = self.exited_queue.pop() else { break };
+            let index = self
+                .threads
+                .iter()
+                .position(|thread| thread.id == thread_id)
+                .expect("exited thread is not registered");
+            *slot = Some(self.threads.swap_remove(index));
+        }
+        exited
+    }
+
+    fn thread_mut(&mut self, thread_id: ThreadId) -> Option<&mut Thread> {
+        self.threads
+            .iter_mut()
+            .find(|thread| thread.id == thread_id)
+            .map(|thread| &mut **thread)
+    }
+
+    /// Makes the blocked threads for which `wakes` returns `true` runnable again.
+    fn wake(&mut self, wakes: impl Fn(Option<WaitChannel>, Option<Duration>) -> bool) {
+        for thread in &mut self.threads {
+            let State::Blocked { channel, deadline } = thread.state else { continue };
+            if !wakes(channel, deadline) {
+                continue;
+            }
+            thread.state = State::Runnable;
+            // the current thread is queued once it is switched away from
+            if thread.id != self.current {
+                self.ready_queue
+                    .push(thread.id)
+                    .expect("thread queues are sized to hold every thread");
+            }
+        }
+    }
+
+    /// Saves the context of the current thread and returns the saved stack
+    /// pointer of the next ready thread.
+    fn switch(&mut self, stack_pointer: u64) -> u64 {
+        let now = time::uptime();
+        self.wake(|_, deadline| deadline.map_or(false, |deadline| deadline <= now));
+        let Some(next) = self.ready_queue.pop() else { return stack_pointer };
+
+        let current = self.current;
+        let thread = self
+            .thread_mut(current)
+            .expect("current thread is not registered");
+        thread.stack_pointer = stack_pointer;
+        let queue = match thread.state {
+            State::Runnable => Some(&self.ready_queue),
+            // queued again once woken
+            State::Blocked { .. } => None,
+            State::Exited => Some(&self.exited_queue),
+        };
+        if let Some(queue) = queue {
+            queue
+                .push(current)
+                .expect("thread queues are sized to hold every thread");
+        }
+
+        self.current = next;
+        let next = self
+            .thread_mut(next)
+            .expect("ready thread is not registered");
+        if let Some(top) = next.kernel_entry_stack {
+            gdt::set_kernel_stack(top);
+        }
+        if let Some(level_4_frame) = next.level_4_frame.or_else(memory::kernel_level_4_frame) {
+            if Cr3::read().0 != level_4_frame {
+                unsafe { Cr3::write(level_4_frame, Cr3Flags::empty()) };
+            }
+        }
+        next.stack_pointer
+    }
+}
+
+/// Adds the given thread to the ready queue.
+pub(super) fn spawn(thread: Thread) {
+    let thread = Box::new(thread);
+    create();
+    let exited = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("scheduler has been created");
+        let exited = scheduler.reap();
+        scheduler.spawn(thread);
+        exited
+    });
+    // frees the stacks with the lock released and interrupts enabled again
+    drop(exited);
+}
+
+/// Creates the scheduler unless it exists already, allocating it before the
+/// lock is taken.
+fn create() {
+    if interrupts::without_interrupts(|| SCHEDULER.lock().is_some()) {
+        return;
+    }
+    let mut scheduler = Some(Scheduler::new());
+    interrupts::without_interrupts(|| {
+        let mut current = SCHEDULER.lock();
+        if current.is_none() {
+            *current = scheduler.take();
+        }
+    });
+    // one created on another CPU in the meantime is kept instead
+    drop(scheduler);
+}
+
+/// Panics unless the calling CPU is the bootstrap processor, see `Scheduler`.
+fn assert_bootstrap_cpu() {
+    // the per-CPU data is set up before any CPU but the bootstrap processor runs
+    assert!(
+        percpu::try_current().map_or(true, |cpu| cpu.id == 0),
+        "threads only run on the bootstrap processor"
+    );
+}
+
+/// Returns the id of the currently running thread.
+pub(super) fn current() -> ThreadId {
+    assert_bootstrap_cpu();
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .map_or(ThreadId::BOOT, |scheduler| scheduler.current)
+    })
+}
+
+/// Marks the currently running thread as exited, so it is not scheduled again.
+pub(super) fn mark_current_exited() {
+    assert_bootstrap_cpu();
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("no thread has been spawned");
+        let current = scheduler.current;
+        assert_ne!(current, ThreadId::BOOT, "the boot thread must not exit");
+        if let Some(thread) = scheduler.thread_mut(current) {
+            thread.state = State::Exited;
+        }
+    });
+}
+
+/// Sets the state of the currently running thread, e.g. to block it until
+/// it is woken.
+pub(super) fn set_current_state(state: State) {
+    with_current(|thread| thread.state = state);
+}
+
+/// Makes every thread blocked on the given channel runnable again.
+pub(super) fn wake_all(channel: WaitChannel) {
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.wake(|blocked_on, _| blocked_on == Some(channel));
+        }
+    });
+}
+
+/// Runs the given function with the record of the currently running thread.
+///
+/// Creates the scheduler if no thread has been spawned yet, so that the
+/// record of the boot thread exists.
+pub(super) fn with_current<R>(f: impl FnOnce(&mut Thread) -> R) -> R {
+    assert_bootstrap_cpu();
+    create();
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("scheduler has been created");
+        let current = scheduler.current;
+        let thread = scheduler
+            .thread_mut(current)
+            .expect("current thread is not registered");
+        f(thread)
+    })
+}
+
+/// Switches to the next ready thread.
+///
+/// Called with interrupts disabled from a context switching interrupt entry
+/// with the stack pointer of the saved context of the interrupted thread.
+/// Returns the stack pointer of the context to resume.
+pub(crate) fn schedule(stack_pointer: u64) -> u64 {
+    // only the bootstrap processor runs the timer interrupt, so this is
+    // reached on another CPU by `yield_now`
+    assert_bootstrap_cpu();
+    match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => scheduler.switch(stack_pointer),
+        None => stack_pointer, // no thread has been spawned yet
+    }
+}
@@ -0,0 +1,221 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use alloc::collections::LinkedList;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::apic;
+
+/// The frequency of the oscillator driving the 8253/8254 PIT, in Hz.
+const PIT_BASE_FREQUENCY: u64 = 1_193_182;
+
+const PIT_CHANNEL_0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+
+/// The tick rate configured by `init`, in Hz.
+pub const DEFAULT_TICK_RATE: u32 = 100;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+/// The period of a single timer tick. Defaults to the PIT power-on divisor of 65536.
+static TICK_NANOS: AtomicU64 = AtomicU64::new(65536 * NANOS_PER_SEC / PIT_BASE_FREQUENCY);
+static TICK_RATE: AtomicU32 = AtomicU32::new((PIT_BASE_FREQUENCY / 65536) as u32);
+
+/// The wakers of sleeping tasks, ordered by deadline and id.
+///
+/// The lock is taken with interrupts disabled, where the heap must not be
+/// touched: a preempted thread holding the allocator lock could not run again.
+/// Therefore entries are allocated before they are linked in, and those
+/// unlinked are freed after the lock is released.
+static SLEEPERS: Mutex<LinkedList<Sleeper>> = Mutex::new(LinkedList::new());
+
+struct Sleeper {
+    /// The deadline and id of the `Sleep`.
+    key: (u64, u64),
+    waker: Waker,
+}
+
+pub fn init() {
+    set_tick_rate(DEFAULT_TICK_RATE);
+}
+
+/// Sets the frequency (in Hz) at which the timer interrupt is raised, and
+/// returns the frequency actually achieved.
+///
+/// The timer interrupt is driven by the local APIC timer if the APIC is in
+/// use, otherwise by channel 0 of the PIT, whose achievable frequencies range
+/// from ~19 Hz to ~1.19 MHz. Out of range values are clamped.
+///
+/// # Panics
+/// Panics if `frequency` is zero.
+pub fn set_tick_rate(frequency: u32) -> u32 {
+    assert!(frequency > 0, "tick rate must be positive");
+
+    interrupts::without_interrupts(|| {
+        let (source_frequency, divisor) = if let Some(apic_frequency) = apic::timer_frequency() {
+            let count = (apic_frequency / u64::from(frequency)).clamp(1, u64::from(u32::MAX));
+            apic::set_timer_count(count as u32);
+            (apic_frequency, count)
+        } else {
+            let divisor = (PIT_BASE_FREQUENCY / u64::from(frequency)).clamp(1, 0xffff);
+            set_pit_divisor(divisor as u16);
+            (PIT_BASE_FREQUENCY, divisor)
+        };
+
+        let achieved = u32::try_from(source_frequency / divisor).unwrap_or(u32::MAX);
+        TICK_NANOS.store(divisor * NANOS_PER_SEC / source_frequency, Ordering::Relaxed);
+        TICK_RATE.store(achieved, Ordering::Relaxed);
+        achieved
+    })
+}
+
+/// Returns the frequency (in Hz) at which the timer interrupt is raised.
+#[must_use]
+pub fn tick_rate() -> u32 {
+    TICK_RATE.load(Ordering::Relaxed)
+}
+
+fn set_pit_divisor(divisor: u16) {
+    let [low, high] = divisor.to_le_bytes();
+
+    let mut command = Port::<u8>::new(PIT_COMMAND_PORT);
+    let mut channel_0 = Port::<u8>::new(PIT_CHANNEL_0_PORT);
+    unsafe {
+        command.write(0x34); // channel 0, access lobyte/hibyte, mode 2 (rate generator)
+        channel_0.write(low);
+        channel_0.write(high);
+    }
+}
+
+/// Called by the timer interrupt handler.
+///
+/// Must not block or allocate.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    UPTIME_NANOS.fetch_add(TICK_NANOS.load(Ordering::Relaxed), Ordering::Release);
+}
+
+/// Returns the number of timer interrupts since boot.
+#[must_use]
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time elapsed since the timer was started.
+///
+/// The clock is monotonic and has the resolution of a single timer tick.
+#[must_use]
+pub fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NANOS.load(Ordering::Acquire))
+}
+
+/// Blocks the calling CPU until at least the given duration has elapsed.
+///
+/// Interrupts must be enabled, and the duration is rounded up to whole ticks.
+pub fn busy_wait(duration: Duration) {
+    let start = uptime();
+    while uptime() - start < duration {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Returns a future which completes once the given duration has elapsed.
+#[must_use]
+pub fn sleep(duration: Duration) -> Sleep {
+    let duration = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+    Sleep {
+        deadline: UPTIME_NANOS.load(Ordering::Acquire).saturating_add(duration),
+        id: Sleep::next_id(),
+    }
+}
+
+/// Wakes all tasks whose sleep deadline has passed.
+///
+/// Called by the executor on every iteration of its run loop, so must not
+/// be called from interrupt context.
+pub fn wake_sleepers() {
+    let now = UPTIME_NANOS.load(Ordering::Acquire);
+    let expired = interrupts::without_interrupts(|| {
+        let mut sleepers = SLEEPERS.lock();
+        let count = sleepers
+            .iter()
+            .position(|sleeper| sleeper.key.0 > now)
+            .unwrap_or(sleepers.len());
+        let pending = sleepers.split_off(count);
+        core::mem::replace(&mut *sleepers, pending)
+    });
+
+    for sleeper in expired {
+        sleeper.waker.wake();
+    }
+}
+
+/// Unlinks the entry with the given key, without freeing it.
+fn unlink_sleeper(sleepers: &mut LinkedList<Sleeper>, key: (u64, u64)) -> LinkedList<Sleeper> {
+    let Some(index) = sleepers.iter().position(|sleeper| sleeper.key == key) else {
+        return LinkedList::new();
+    };
+    let mut unlinked = sleepers.split_off(index);
+    let mut rest = unlinked.split_off(1);
+    sleepers.append(&mut rest);
+    unlinked
+}
+
+pub struct Sleep {
+    deadline: u64,
+    id: u64,
+}
+
+impl Sleep {
+    fn next_id() -> u64 {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if UPTIME_NANOS.load(Ordering::Acquire) >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let key = (self.deadline, self.id);
+        let mut entry = LinkedList::new();
+        entry.push_back(Sleeper {
+            key,
+            waker: cx.waker().clone(),
+        });
+        let previous = interrupts::without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            let previous = unlink_sleeper(&mut sleepers, key);
+            let index = sleepers
+                .iter()
+                .position(|sleeper| sleeper.key > key)
+                .unwrap_or(sleepers.len());
+            let mut later = sleepers.split_off(index);
+            sleepers.append(&mut entry);
+            sleepers.append(&mut later);
+            previous
+        });
+        drop(previous);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        let key = (self.deadline, self.id);
+        let unlinked = interrupts::without_interrupts(|| unlink_sleeper(&mut SLEEPERS.lock(), key));
+        drop(unlinked);
+    }
+}
@@ -0,0 +1,232 @@
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+use crate::{
+    gdt,
+    memory::{
+        self,
+        address_space::{AddressSpaceError, USER_END, USER_START},
+    },
+    percpu,
+    process::Process,
+    thread,
+};
+
+/// The address at which the code of a program is loaded.
+pub const CODE_START: u64 = USER_START;
+/// The first address above the stack of a program.
+pub const STACK_TOP: u64 = USER_END;
+/// The size of the stack of a program.
+pub const STACK_SIZE: u64 = 4096 * 4;
+
+/// How a program in user mode ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// The program made the `exit` system call with the given exit code.
+    Exited(u64),
+    /// The program accessed the given address, which it is not allowed to access.
+    PageFault(VirtAddr),
+    /// The program executed an instruction which is not allowed in user mode.
+    GeneralProtectionFault,
+}
+
+/// The two words into which `Exit` is encoded when returned by `user_enter`.
+#[repr(C)]
+struct RawExit {
+    kind: u64,
+    value: u64,
+}
+
+impl Exit {
+    fn into_raw(self) -> RawExit {
+        match self {
+            Exit::Exited(code) => RawExit {
+                kind: 0,
+                value: code,
+            },
+            Exit::PageFault(addr) => RawExit {
+                kind: 1,
+                value: addr.as_u64(),
+            },
+            Exit::GeneralProtectionFault => RawExit { kind: 2, value: 0 },
+        }
+    }
+
+    fn from_raw(raw: &RawExit) -> Self {
+        match raw.kind {
+            0 => Exit::Exited(raw.value),
+            1 => Exit::PageFault(VirtAddr::new(raw.value)),
+            _ => Exit::GeneralProtectionFault,
+        }
+    }
+}
+
+// `user_enter` saves the callee-saved registers and the flags of the kernel,
+// makes the resulting stack pointer the stack on which the kernel is entered
+// from user mode, and enters user mode with `iretq`. `user_return` unwinds
+// that stack again, so that `user_enter` returns to its caller.
+core::arch::global_asm!(
+    ".global user_enter",
+    "user_enter:",
+    "pushfq",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov r12, rdi",
+    "mov r13, rsi",
+    "mov r14, rdx",
+    "mov r15, rcx",
+    "mov rdi, rsp",
+    "call {set_kernel_stack}",
+    "cli",
+    "push r15", // stack segment
+    "push r13", // stack pointer
+    "push 0x202", // flags, with interrupts enabled
+    "push r14", // code segment
+    "push r12", // instruction pointer
+    "swapgs",
+    // do not leak values of the kernel to user mode
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "iretq",
+    ".global user_return",
+    "user_return:",
+    "mov rsp, rdi",
+    "mov rax, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "popfq",
+    "ret",
+    set_kernel_stack = sym set_kernel_stack,
+);
+
+extern "C" {
+    fn user_enter(entry: u64, stack_pointer: u64, code_segment: u64, stack_segment: u64)
+        -> RawExit;
+    fn user_return(kernel_stack: u64, kind: u64, value: u64) -> !;
+}
+
+extern "C" fn set_kernel_stack(top: u64) {
+    let top = VirtAddr::new(top);
+    gdt::set_kernel_stack(top);
+    thread::set_kernel_entry_stack(Some(top));
+}
+
+#[derive(Debug)]
+pub enum UserError {
+    AddressSpace(AddressSpaceError),
+    /// The code does not fit below the stack.
+    CodeTooLarge,
+}
+
+impl From<AddressSpaceError> for UserError {
+    fn from(err: AddressSpaceError) -> Self {
+        UserError::AddressSpace(err)
+    }
+}
+
+/// Runs the given position independent machine code in user mode, in an
+/// address space of its own with a stack of `STACK_SIZE` bytes, and returns
+/// once the program has exited or faulted.
+///
+/// The code is loaded at `CODE_START` and entered at its first byte. It may
+/// not write to its own pages.
+///
+/// # Errors
+/// Returns an error if the address space of the program could not be set up.
+pub fn run(code: &[u8]) -> Result<Exit, UserError> {
+    if code.len() as u64 > STACK_TOP - STACK_SIZE - CODE_START {
+        return Err(UserError::CodeTooLarge);
+    }
+    let mut process = Process::new()?;
+    let address_space = process.address_space_mut();
+    let code_start = VirtAddr::new(CODE_START);
+    address_space.map_user(code_start, code.len() as u64, PageTableFlags::empty())?;
+    address_space.write(code_start, code)?;
+    address_space.map_user(
+        VirtAddr::new(STACK_TOP - STACK_SIZE),
+        STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+
+    Ok(process.run(code_start, VirtAddr::new(STACK_TOP)))
+}
+
+/// Enters user mode in the address space with the given level 4 frame on the
+/// calling thread, and returns once the program has exited or faulted.
+///
+/// The address space is loaded whenever the thread is resumed meanwhile, and
+/// the kernel page table is loaded again before returning. It is not borrowed,
+/// since system calls change it while the program runs.
+pub(crate) fn enter(level_4_frame: PhysFrame, entry: VirtAddr, stack_pointer: VirtAddr) -> Exit {
+    interrupts::without_interrupts(|| {
+        thread::set_level_4_frame(Some(level_4_frame));
+        if Cr3::read().0 != level_4_frame {
+            unsafe { Cr3::write(level_4_frame, Cr3Flags::empty()) };
+        }
+    });
+    let raw = unsafe {
+        user_enter(
+            entry.as_u64(),
+            stack_pointer.as_u64(),
+            u64::from(gdt::USER_CODE_SELECTOR.0),
+            u64::from(gdt::USER_DATA_SELECTOR.0),
+        )
+    };
+    interrupts::without_interrupts(|| {
+        thread::set_kernel_entry_stack(None);
+        thread::set_level_4_frame(None);
+        if let Some(kernel_frame) = memory::kernel_level_4_frame() {
+            unsafe { Cr3::write(kernel_frame, Cr3Flags::empty()) };
+        }
+    });
+
+    Exit::from_raw(&raw)
+}
+
+/// Ends the program running on the calling CPU with the given exit code.
+///
+/// Called by the `exit` system call, with GS of the kernel.
+pub(crate) fn exit(code: u64) -> ! {
+    return_to_kernel(Exit::Exited(code))
+}
+
+/// Ends the program running on the calling CPU after it caused the given exception.
+///
+/// # Safety
+/// Must only be called by the handler of an exception which interrupted user
+/// mode, while GS still holds the base of user mode.
+pub(crate) unsafe fn abort(exit: Exit) -> ! {
+    percpu::swapgs();
+    return_to_kernel(exit)
+}
+
+fn return_to_kernel(exit: Exit) -> ! {
+    let raw = exit.into_raw();
+    let kernel_stack = percpu::current().kernel_stack();
+    unsafe { user_return(kernel_stack.as_u64(), raw.kind, raw.value) }
+}
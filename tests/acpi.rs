@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    acpi::{
+        self,
+        fadt::Fadt,
+        hpet::Hpet,
+        madt::{Entry, Madt},
+    },
+    init, memory, power, test_panic_handler,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+#[test_case]
+fn tables_are_valid() {
+    assert!(acpi::is_present());
+    assert!(acpi::tables().count() > 0);
+    assert!(acpi::tables().all(acpi::SdtHeader::is_valid));
+}
+
+#[test_case]
+fn madt_describes_interrupt_controllers() {
+    let madt = Madt::find().expect("MADT not found");
+    assert!(!madt.local_apic_address().is_null());
+    assert!(madt
+        .entries()
+        .any(|entry| matches!(entry, Entry::LocalApic { .. })));
+    assert!(madt
+        .entries()
+        .any(|entry| matches!(entry, Entry::IoApic { .. })));
+}
+
+#[test_case]
+fn fadt_describes_power_management() {
+    let fadt = Fadt::find().expect("FADT not found");
+    assert_ne!(fadt.pm1a_control_block(), 0);
+    assert_ne!(fadt.pm1a_event_block(), 0);
+
+    let dsdt = unsafe { acpi::table_at(fadt.dsdt_address()) };
+    assert_eq!(&dsdt.signature, b"DSDT");
+    assert!(dsdt.is_valid());
+}
+
+#[test_case]
+fn hpet_describes_timer_block() {
+    let hpet = Hpet::find().expect("HPET table not found");
+    assert!(!hpet.base_address().is_null());
+    assert!(hpet.comparator_count() >= 3);
+}
+
+#[test_case]
+fn dsdt_defines_soft_off_state() {
+    assert!(power::s5_sleep_type().is_some());
+}
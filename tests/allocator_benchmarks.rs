@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator::{
+        self,
+        bench::{self, Workload},
+    },
+    init,
+    memory::{self, frame::BitmapFrameAllocator},
+    test_panic_handler,
+};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+#[test_case]
+fn every_backend_runs_every_workload() {
+    let results = bench::run_all();
+    assert_eq!(results.len(), 5 * Workload::ALL.len());
+
+    for result in &results {
+        assert!(result.operations > 0);
+        assert!(result.fragmentation <= 100);
+        if result.backend != "bump" {
+            // the live blocks of every workload fit into the heap many times
+            assert_eq!(result.failed_allocations, 0, "{result}");
+        }
+    }
+}
+
+#[test_case]
+fn bump_allocator_cannot_reuse_memory_of_live_heaps() {
+    let results = bench::run("bump", allocator::bump::Allocator::new);
+    let random = results
+        .iter()
+        .find(|result| result.workload == Workload::Random)
+        .unwrap();
+    assert!(random.failed_allocations > 0);
+}
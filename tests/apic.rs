@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, apic, init, interrupt,
+    memory::{self, frame::BitmapFrameAllocator},
+    test_panic_handler, time,
+};
+use x86_64::{instructions::hlt, VirtAddr};
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    interrupt::init_apic(&mut mapper, &mut frame_allocator).expect("APIC initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+#[test_case]
+fn apic_replaces_pic() {
+    assert!(apic::is_enabled());
+    assert!(apic::timer_frequency().is_some());
+}
+
+#[test_case]
+fn local_apic_timer_ticks() {
+    let start = time::uptime();
+    let start_ticks = time::ticks();
+    for _ in 0..5 {
+        hlt();
+    }
+    assert!(time::ticks() > start_ticks);
+    assert!(time::uptime() > start);
+}
+
+#[test_case]
+fn local_apic_timer_tick_rate() {
+    let achieved = time::set_tick_rate(1000);
+    assert!((990..=1010).contains(&achieved));
+    assert_eq!(time::tick_rate(), achieved);
+
+    time::set_tick_rate(time::DEFAULT_TICK_RATE);
+}
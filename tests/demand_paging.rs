@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, init,
+    memory::{
+        self,
+        vma::{self, AreaError, AreaKind, VirtualMemoryArea},
+        frame::BitmapFrameAllocator,
+    },
+    test_panic_handler,
+};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+const AREA_START: u64 = 0x_6666_0000_0000; // An arbitrary value
+
+fn writable() -> PageTableFlags {
+    PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+}
+
+#[test_case]
+fn anonymous_pages_are_zeroed_on_first_access() {
+    let start = VirtAddr::new(AREA_START);
+    let area = VirtualMemoryArea::new(start, 4, writable(), AreaKind::Anonymous);
+    vma::register(area).expect("registration failed");
+
+    let buffer = start.as_mut_ptr::<u64>();
+    let len = 4 * 4096 / 8;
+    for i in 0..len {
+        unsafe {
+            assert_eq!(buffer.add(i).read_volatile(), 0);
+            buffer.add(i).write_volatile(i as u64);
+        }
+    }
+    for i in 0..len {
+        assert_eq!(unsafe { buffer.add(i).read_volatile() }, i as u64);
+    }
+
+    vma::unregister(start).expect("area is not registered");
+}
+
+#[test_case]
+fn overlapping_areas_are_rejected() {
+    let start = VirtAddr::new(AREA_START + 0x10_0000);
+    let area = VirtualMemoryArea::new(start, 2, writable(), AreaKind::Anonymous);
+    vma::register(area).expect("registration failed");
+
+    let overlapping = VirtualMemoryArea::new(start + 4096u64, 2, writable(), AreaKind::Guard);
+    assert_eq!(
+        vma::register(overlapping),
+        Err(AreaError::Overlapping(area))
+    );
+    assert_eq!(vma::find(start + 4096u64), Some(area));
+
+    assert_eq!(vma::unregister(start), Some(area));
+    assert_eq!(vma::find(start), None);
+}
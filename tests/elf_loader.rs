@@ -0,0 +1,234 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{mem::size_of, panic::PanicInfo};
+
+use alloc::{string::String, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator,
+    elf::{self, Elf, ElfError},
+    init,
+    loader::{self, LoadError},
+    memory::{self, frame::BitmapFrameAllocator},
+    process::Process,
+    test_panic_handler,
+    user::Exit,
+};
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, PageTableFlags, Translate},
+    VirtAddr,
+};
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+// built from the sources next to them by `tests/programs/build.sh`
+static EXIT: &[u8] = include_bytes!("programs/exit.elf");
+static DATA: &[u8] = include_bytes!("programs/data.elf");
+static ARGS: &[u8] = include_bytes!("programs/args.elf");
+static WRITE_TEXT: &[u8] = include_bytes!("programs/write_text.elf");
+static SHARED_PAGE: &[u8] = include_bytes!("programs/shared_page.elf");
+
+fn entry(data: &[u8]) -> VirtAddr {
+    VirtAddr::new(Elf::parse(data).unwrap().header().entry)
+}
+
+fn flags(process: &mut Process, addr: VirtAddr) -> PageTableFlags {
+    let translated = process
+        .address_space_mut()
+        .with_mapper(|mapper, _| mapper.translate(addr))
+        .unwrap();
+    match translated {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{addr:?} is not mapped"),
+    }
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|_, frame_allocator| frame_allocator.stats().free).unwrap()
+}
+
+#[test_case]
+fn program_exits_with_code() {
+    assert_eq!(loader::run(EXIT, &["exit"], &[]).unwrap(), Exit::Exited(42));
+}
+
+#[test_case]
+fn program_uses_data_and_bss() {
+    assert_eq!(loader::run(DATA, &["data"], &[]).unwrap(), Exit::Exited(42));
+}
+
+#[test_case]
+fn program_gets_arguments_environment_and_auxiliary_vector() {
+    let exit = loader::run(ARGS, &["args", "hello"], &["A=1", "B=2", "C=3"]).unwrap();
+    let expected = 2 | u64::from(b'h') << 8 | 3 << 16 | 1 << 24 | 1 << 25;
+    assert_eq!(exit, Exit::Exited(expected));
+}
+
+#[test_case]
+fn code_is_not_writable() {
+    assert_eq!(
+        loader::run(WRITE_TEXT, &["write_text"], &[]).unwrap(),
+        Exit::PageFault(entry(WRITE_TEXT))
+    );
+}
+
+#[test_case]
+fn segments_are_mapped_with_their_permissions() {
+    let mut process = Process::new().unwrap();
+    let image = loader::load(process.address_space_mut(), DATA, &["data"], &[]).unwrap();
+    assert_eq!(image.entry, entry(DATA));
+    assert_eq!(image.stack_pointer.as_u64() % 16, 0);
+
+    let elf = Elf::parse(DATA).unwrap();
+    for segment in elf.program_headers().filter(elf::ProgramHeader::is_load) {
+        let flags = flags(&mut process, segment.start());
+        assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE));
+        assert_eq!(
+            flags.contains(PageTableFlags::WRITABLE),
+            segment.is_writable()
+        );
+        assert_eq!(
+            flags.contains(PageTableFlags::NO_EXECUTE),
+            !segment.is_executable()
+        );
+
+        // the part beyond the file data is zeroed
+        let mut bss = [0xff; 8];
+        if segment.memory_size >= segment.file_size + 8 {
+            process
+                .address_space_mut()
+                .read(segment.start() + segment.file_size, &mut bss)
+                .unwrap();
+            assert_eq!(bss, [0; 8]);
+        }
+    }
+}
+
+#[test_case]
+fn segments_sharing_a_page_get_both_permissions() {
+    assert_eq!(
+        loader::run(SHARED_PAGE, &["shared_page"], &[]).unwrap(),
+        Exit::Exited(42)
+    );
+
+    let mut process = Process::new().unwrap();
+    loader::load(
+        process.address_space_mut(),
+        SHARED_PAGE,
+        &["shared_page"],
+        &[],
+    )
+    .unwrap();
+    let flags = flags(&mut process, entry(SHARED_PAGE));
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn loaded_programs_free_their_frames() {
+    // the first run may grow the kernel heap
+    assert_eq!(loader::run(DATA, &["data"], &[]).unwrap(), Exit::Exited(42));
+    let before = free_frames();
+    for _ in 0..10 {
+        assert_eq!(loader::run(DATA, &["data"], &[]).unwrap(), Exit::Exited(42));
+    }
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn invalid_files_are_rejected() {
+    let mut data = Vec::from(EXIT);
+    data[..4].copy_from_slice(b"\x7fBAD");
+    assert!(matches!(
+        loader::run(&data, &[], &[]),
+        Err(LoadError::Elf(ElfError::NotElf))
+    ));
+
+    assert!(matches!(
+        loader::run(&EXIT[..32], &[], &[]),
+        Err(LoadError::Elf(ElfError::Truncated))
+    ));
+
+    let mut data = Vec::from(EXIT);
+    data[16..18].copy_from_slice(&3u16.to_le_bytes()); // ET_DYN
+    assert!(matches!(
+        loader::run(&data, &[], &[]),
+        Err(LoadError::NotExecutable)
+    ));
+
+    let mut data = Vec::from(EXIT);
+    data[24..32].copy_from_slice(&0x1000u64.to_le_bytes()); // entry
+    assert!(matches!(
+        loader::run(&data, &[], &[]),
+        Err(LoadError::InvalidEntry(0x1000))
+    ));
+}
+
+#[test_case]
+fn segments_outside_of_the_file_are_rejected() {
+    let header = *Elf::parse(EXIT).unwrap().header();
+    let mut data = Vec::from(EXIT);
+    let offset = header.program_header_offset as usize;
+    for index in 0..usize::from(header.program_header_count) {
+        let entry = offset + index * usize::from(header.program_header_size);
+        // the file offset of the segment
+        let field = entry + 2 * size_of::<u32>();
+        data[field..field + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    }
+    assert!(matches!(
+        loader::run(&data, &[], &[]),
+        Err(LoadError::InvalidSegment(_))
+    ));
+}
+
+#[test_case]
+fn program_header_table_beyond_the_end_of_memory_is_rejected() {
+    let header = *Elf::parse(EXIT).unwrap().header();
+    let table_size = u64::from(header.program_header_count) * u64::from(header.program_header_size);
+    // offsets at which the end of the table overflows, to 0 for the first one
+    for offset in [0u64.wrapping_sub(table_size), u64::MAX] {
+        let mut data = Vec::from(EXIT);
+        data[32..40].copy_from_slice(&offset.to_le_bytes());
+        assert!(matches!(
+            loader::run(&data, &[], &[]),
+            Err(LoadError::Elf(ElfError::Truncated))
+        ));
+    }
+}
+
+#[test_case]
+fn too_long_arguments_are_rejected() {
+    let argument: String = core::iter::repeat('a')
+        .take(loader::MAX_ARGUMENTS_SIZE as usize)
+        .collect();
+    assert!(matches!(
+        loader::run(EXIT, &["exit", argument.as_str()], &[]),
+        Err(LoadError::ArgumentsTooLong)
+    ));
+}
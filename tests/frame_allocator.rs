@@ -0,0 +1,126 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    init,
+    memory::{self, frame::BitmapFrameAllocator},
+    test_panic_handler,
+};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+fn with_frame_allocator(f: impl FnOnce(&mut BitmapFrameAllocator)) {
+    memory::with_kernel_memory(|_, frame_allocator| f(frame_allocator))
+        .expect("memory is not initialized");
+}
+
+#[test_case]
+fn allocated_frames_are_distinct() {
+    with_frame_allocator(|frames| {
+        let a: PhysFrame<Size4KiB> = frames.allocate_frame().unwrap();
+        let b: PhysFrame<Size4KiB> = frames.allocate_frame().unwrap();
+        assert_ne!(a, b);
+        assert_ne!(a.start_address().as_u64(), 0);
+        unsafe {
+            frames.deallocate_frame(a);
+            frames.deallocate_frame(b);
+        }
+    });
+}
+
+#[test_case]
+fn deallocation_updates_stats() {
+    with_frame_allocator(|frames| {
+        let before = frames.stats();
+        let frame: PhysFrame<Size4KiB> = frames.allocate_frame().unwrap();
+        assert_eq!(frames.stats().free, before.free - 1);
+        assert_eq!(frames.stats().used(), before.used() + 1);
+        unsafe {
+            frames.deallocate_frame(frame);
+        }
+        assert_eq!(frames.stats(), before);
+    });
+}
+
+#[test_case]
+fn all_frames_can_be_reused() {
+    with_frame_allocator(|frames| {
+        let free = frames.stats().free;
+        // allocating more frames than there are forces the allocator to reuse freed ones
+        for _ in 0..free + 16 {
+            let frame: PhysFrame<Size4KiB> = frames.allocate_frame().unwrap();
+            unsafe {
+                frames.deallocate_frame(frame);
+            }
+        }
+        assert_eq!(frames.stats().free, free);
+    });
+}
+
+#[test_case]
+fn contiguous_frames_are_aligned() {
+    with_frame_allocator(|frames| {
+        let range = frames.allocate_contiguous(8, 4).unwrap();
+        assert_eq!(range.end - range.start, 8);
+        assert_eq!(range.start.start_address().as_u64() % (4 * 4096), 0);
+        unsafe {
+            frames.deallocate_contiguous(range);
+        }
+    });
+}
+
+#[test_case]
+fn contiguous_frames_lie_below_limit() {
+    with_frame_allocator(|frames| {
+        let limit = PhysAddr::new(0x10_0000);
+        let range = frames.allocate_contiguous_below(1, 1, limit).unwrap();
+        assert!(range.end.start_address() <= limit);
+        let none = frames.allocate_contiguous_below(1, 1, PhysAddr::new(0));
+        assert!(none.is_none());
+        unsafe {
+            frames.deallocate_contiguous(range);
+        }
+    });
+}
+
+#[test_case]
+fn huge_frames_are_aligned() {
+    with_frame_allocator(|frames| {
+        let before = frames.stats();
+        let frame: PhysFrame<Size2MiB> = frames.allocate_frame().unwrap();
+        assert!(frame.start_address().is_aligned(0x20_0000u64));
+        assert_eq!(frames.stats().free, before.free - 512);
+        unsafe {
+            frames.deallocate_frame(frame);
+        }
+        assert_eq!(frames.stats(), before);
+    });
+}
@@ -4,16 +4,30 @@
 #![test_runner(rust_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use core::panic::PanicInfo;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    panic::PanicInfo,
+};
 
-use alloc::{boxed::Box, vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use rust_os::{
-    allocator::{self, KERNEL_HEAP_SIZE}, init,
-    memory::{self, BootInfoFrameAllocator},
+    allocator::{
+        self,
+        linked_list::{self, FitPolicy},
+        HeapRegion, Locked, KERNEL_HEAP_SIZE,
+    },
+    init,
+    memory::{self, frame::BitmapFrameAllocator},
     test_panic_handler,
 };
-use x86_64::VirtAddr;
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        PageSize, Size2MiB, Translate,
+    },
+    VirtAddr,
+};
 
 extern crate alloc;
 
@@ -24,9 +38,10 @@ fn main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
 
     test_main();
 
@@ -75,3 +90,140 @@ fn may_boxes_with_long_lived() {
     }
     assert_eq!(*long_lived, 1);
 }
+
+#[test_case]
+fn heap_grows_beyond_initial_size() {
+    let size = 4 * KERNEL_HEAP_SIZE as usize;
+    let vec = vec![1u8; size];
+    assert_eq!(vec.iter().map(|&x| usize::from(x)).sum::<usize>(), size);
+    assert!(allocator::heap_size() > KERNEL_HEAP_SIZE);
+}
+
+#[test_case]
+fn heap_growth_respects_limit() {
+    let limit = allocator::heap_size_limit();
+    let size = 2 * allocator::heap_size() as usize;
+    allocator::set_heap_size_limit(allocator::heap_size());
+
+    let mut vec = Vec::<u8>::new();
+    assert!(vec.try_reserve(size).is_err());
+
+    allocator::set_heap_size_limit(limit);
+    assert!(vec.try_reserve(size).is_ok());
+}
+
+#[test_case]
+fn heap_grows_by_huge_pages() {
+    let size = 2 * Size2MiB::SIZE as usize;
+    let vec = vec![1u8; size];
+    // the heap starts at a 2 MiB boundary, and grows by 2 MiB pages from the next one on
+    let boundary = (VirtAddr::from_ptr(vec.as_ptr()) + 1u64).align_up(Size2MiB::SIZE);
+    let translated = memory::with_kernel_memory(|mapper, _| mapper.translate(boundary)).unwrap();
+    assert!(matches!(
+        translated,
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size2MiB(_),
+            ..
+        }
+    ));
+}
+
+const TEST_HEAP_SIZE: usize = 16 * 1024;
+
+/// Creates a linked list allocator managing a fresh region of the kernel
+/// heap, which must outlive it.
+fn linked_list_allocator(policy: FitPolicy) -> (HeapRegion, Locked<linked_list::Allocator>) {
+    let mut heap = HeapRegion::new(TEST_HEAP_SIZE);
+    let allocator = Locked::new(linked_list::Allocator::with_policy(policy));
+    unsafe {
+        allocator.lock().init(heap.start(), heap.size());
+    }
+    (heap, allocator)
+}
+
+fn single_free_region(allocator: &Locked<linked_list::Allocator>) -> bool {
+    allocator.lock().free_regions().count() == 1
+}
+
+#[test_case]
+fn linked_list_recovers_from_fragmentation() {
+    for policy in [FitPolicy::FirstFit, FitPolicy::BestFit, FitPolicy::NextFit] {
+        let (_heap, allocator) = linked_list_allocator(policy);
+        let small = Layout::from_size_align(48, 16).unwrap();
+
+        let mut blocks = Vec::new();
+        loop {
+            let ptr = unsafe { allocator.alloc(small) };
+            if ptr.is_null() {
+                break;
+            }
+            blocks.push(ptr);
+        }
+        assert!(blocks.len() > 100);
+
+        // free every other block first, fragmenting the heap into many holes
+        for &ptr in blocks.iter().step_by(2) {
+            unsafe { allocator.dealloc(ptr, small) };
+        }
+        assert!(allocator.lock().free_regions().count() > 1);
+        for &ptr in blocks.iter().skip(1).step_by(2) {
+            unsafe { allocator.dealloc(ptr, small) };
+        }
+        assert!(single_free_region(&allocator));
+
+        let whole = Layout::from_size_align(TEST_HEAP_SIZE, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(whole) };
+        assert!(!ptr.is_null());
+        unsafe { allocator.dealloc(ptr, whole) };
+    }
+}
+
+#[test_case]
+fn linked_list_merges_regions_freed_in_any_order() {
+    let (_heap, allocator) = linked_list_allocator(FitPolicy::FirstFit);
+    let layout = Layout::from_size_align(256, 8).unwrap();
+    let blocks: Vec<_> = (0..4).map(|_| unsafe { allocator.alloc(layout) }).collect();
+
+    for &index in &[2, 0, 3, 1] {
+        unsafe { allocator.dealloc(blocks[index], layout) };
+    }
+    assert!(single_free_region(&allocator));
+}
+
+/// Allocates blocks of the given sizes in order, and frees those marked with
+/// `true`, leaving holes of these sizes between the remaining blocks.
+fn make_holes(
+    allocator: &Locked<linked_list::Allocator>,
+    blocks: &[(usize, bool)],
+) -> Vec<*mut u8> {
+    let ptrs: Vec<_> = blocks
+        .iter()
+        .map(|&(size, _)| unsafe { allocator.alloc(Layout::from_size_align(size, 8).unwrap()) })
+        .collect();
+    for (&ptr, &(size, free)) in ptrs.iter().zip(blocks) {
+        if free {
+            unsafe { allocator.dealloc(ptr, Layout::from_size_align(size, 8).unwrap()) };
+        }
+    }
+    ptrs
+}
+
+#[test_case]
+fn linked_list_fit_policies() {
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    let blocks = [(512, true), (64, false), (128, true), (64, false)];
+
+    let (_first_fit_heap, first_fit) = linked_list_allocator(FitPolicy::FirstFit);
+    let ptrs = make_holes(&first_fit, &blocks);
+    assert_eq!(unsafe { first_fit.alloc(layout) }, ptrs[0]);
+
+    let (_best_fit_heap, best_fit) = linked_list_allocator(FitPolicy::BestFit);
+    let ptrs = make_holes(&best_fit, &blocks);
+    assert_eq!(unsafe { best_fit.alloc(layout) }, ptrs[2]);
+
+    let (_next_fit_heap, next_fit) = linked_list_allocator(FitPolicy::NextFit);
+    let ptrs = make_holes(&next_fit, &blocks);
+    // continues after the last allocation, in the free space at the end
+    let ptr = unsafe { next_fit.alloc(layout) };
+    assert!(ptr > ptrs[3]);
+}
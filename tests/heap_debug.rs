@@ -0,0 +1,157 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    panic::PanicInfo,
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator::{
+        self,
+        debug::{DebugAllocator, HeapError, POISON_BYTE},
+        linked_list, HeapRegion, Locked,
+    },
+    init,
+    memory::{self, frame::BitmapFrameAllocator},
+    test_panic_handler,
+};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+const TEST_HEAP_SIZE: usize = 16 * 1024;
+
+/// Creates a debug allocator which records errors instead of panicking,
+/// managing a region of the kernel heap which must outlive it.
+fn debug_allocator() -> (HeapRegion, DebugAllocator<Locked<linked_list::Allocator>>) {
+    let mut heap = HeapRegion::new(TEST_HEAP_SIZE);
+    let allocator = DebugAllocator::new(Locked::new(linked_list::Allocator::new()));
+    unsafe {
+        allocator.inner().lock().init(heap.start(), heap.size());
+    }
+    allocator.set_panic_on_error(false);
+    (heap, allocator)
+}
+
+fn last_error(allocator: &DebugAllocator<Locked<linked_list::Allocator>>) -> Option<HeapError> {
+    allocator.last_error().map(|report| report.error)
+}
+
+#[test_case]
+fn correct_usage_reports_no_errors() {
+    let (_heap, allocator) = debug_allocator();
+    for align in [1, 8, 64, 4096] {
+        let layout = Layout::from_size_align(100, align).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0);
+        unsafe {
+            ptr.write_bytes(0xff, layout.size());
+            allocator.dealloc(ptr, layout);
+        }
+    }
+    allocator.flush_quarantine();
+    assert_eq!(allocator.error_count(), 0);
+}
+
+#[test_case]
+fn overflow_is_detected() {
+    let (_heap, allocator) = debug_allocator();
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.add(24).write(0);
+        allocator.dealloc(ptr, layout);
+    }
+    assert_eq!(
+        last_error(&allocator),
+        Some(HeapError::Overflow { offset: 24 })
+    );
+}
+
+#[test_case]
+fn underflow_is_detected() {
+    let (_heap, allocator) = debug_allocator();
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.sub(1).write(0);
+        allocator.dealloc(ptr, layout);
+    }
+    assert_eq!(
+        last_error(&allocator),
+        Some(HeapError::Underflow { offset: -1 })
+    );
+}
+
+#[test_case]
+fn double_free_is_detected() {
+    let (_heap, allocator) = debug_allocator();
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        allocator.dealloc(ptr, layout);
+        assert_eq!(allocator.error_count(), 0);
+        allocator.dealloc(ptr, layout);
+    }
+    assert_eq!(last_error(&allocator), Some(HeapError::DoubleFree));
+}
+
+#[test_case]
+fn layout_mismatch_is_detected() {
+    let (_heap, allocator) = debug_allocator();
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        allocator.dealloc(ptr, Layout::from_size_align(16, 8).unwrap());
+    }
+    assert_eq!(
+        last_error(&allocator),
+        Some(HeapError::LayoutMismatch { allocated: layout })
+    );
+}
+
+#[test_case]
+fn freed_memory_is_poisoned() {
+    let (_heap, allocator) = debug_allocator();
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        allocator.dealloc(ptr, layout);
+        let data = core::slice::from_raw_parts(ptr, layout.size());
+        assert!(data.iter().all(|&byte| byte == POISON_BYTE));
+
+        ptr.add(8).write(0);
+    }
+    allocator.flush_quarantine();
+    assert_eq!(
+        last_error(&allocator),
+        Some(HeapError::UseAfterFree { offset: 8 })
+    );
+}
@@ -0,0 +1,146 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    panic::PanicInfo,
+    ptr::addr_of_mut,
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator::{self, linked_list, tracking::TrackingAllocator, Locked},
+    init,
+    memory::{self, frame::BitmapFrameAllocator},
+    test_panic_handler,
+};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+/// The tracker is too large for the stack, so the allocator under test is static.
+static TRACKED: TrackingAllocator<Locked<linked_list::Allocator>> =
+    TrackingAllocator::new(Locked::new(linked_list::Allocator::new()));
+
+const TEST_HEAP_SIZE: usize = 64 * 1024;
+
+/// The memory managed by `TRACKED`.
+static mut TEST_HEAP: [u64; TEST_HEAP_SIZE / 8] = [0; TEST_HEAP_SIZE / 8];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    unsafe {
+        TRACKED
+            .inner()
+            .lock()
+            .init(addr_of_mut!(TEST_HEAP) as u64, TEST_HEAP_SIZE as u64);
+    }
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+fn layout(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+#[test_case]
+fn live_allocations_are_counted() {
+    let before = TRACKED.tracker().stats();
+    let ptr = unsafe { TRACKED.alloc(layout(100)) };
+
+    let stats = TRACKED.tracker().stats();
+    assert_eq!(stats.live_allocations, before.live_allocations + 1);
+    assert_eq!(stats.live_bytes, before.live_bytes + 100);
+    assert_eq!(stats.total_allocations, before.total_allocations + 1);
+
+    unsafe { TRACKED.dealloc(ptr, layout(100)) };
+    assert_eq!(
+        TRACKED.tracker().stats().live_allocations,
+        before.live_allocations
+    );
+}
+
+#[test_case]
+fn snapshots_record_layout_and_caller() {
+    let ptr = unsafe { TRACKED.alloc(layout(48)) };
+    let snapshot = TRACKED.tracker().snapshot();
+    let record = snapshot
+        .allocations
+        .iter()
+        .find(|record| record.ptr == ptr as usize)
+        .expect("allocation is not tracked");
+    assert_eq!(record.layout, layout(48));
+    assert_ne!(record.callers[0], 0);
+    unsafe { TRACKED.dealloc(ptr, layout(48)) };
+}
+
+#[test_case]
+fn diff_shows_allocated_and_freed() {
+    let freed = unsafe { TRACKED.alloc(layout(16)) };
+    let before = TRACKED.tracker().snapshot();
+    let allocated = unsafe { TRACKED.alloc(layout(32)) };
+    unsafe { TRACKED.dealloc(freed, layout(16)) };
+    let after = TRACKED.tracker().snapshot();
+
+    let diff = before.diff(&after);
+    assert_eq!(diff.allocated.len(), 1);
+    assert_eq!(diff.allocated[0].ptr, allocated as usize);
+    assert_eq!(diff.freed.len(), 1);
+    assert_eq!(diff.freed[0].ptr, freed as usize);
+    unsafe { TRACKED.dealloc(allocated, layout(32)) };
+}
+
+#[test_case]
+fn leaks_are_reported() {
+    let before = TRACKED.tracker().snapshot();
+    let leaked = unsafe { TRACKED.alloc(layout(64)) };
+    let temporary = unsafe { TRACKED.alloc(layout(64)) };
+    unsafe { TRACKED.dealloc(temporary, layout(64)) };
+
+    assert_eq!(TRACKED.tracker().report_leaks(&before), 1);
+    unsafe { TRACKED.dealloc(leaked, layout(64)) };
+    assert_eq!(TRACKED.tracker().report_leaks(&before), 0);
+}
+
+#[test_case]
+fn many_allocations_keep_the_table_consistent() {
+    let before = TRACKED.tracker().stats();
+    let ptrs: alloc::vec::Vec<_> = (0..512)
+        .map(|_| unsafe { TRACKED.alloc(layout(16)) })
+        .collect();
+    // free in an order unrelated to the hash table layout
+    for &ptr in ptrs.iter().step_by(3).chain(ptrs.iter().skip(1).step_by(3)) {
+        unsafe { TRACKED.dealloc(ptr, layout(16)) };
+    }
+    for &ptr in ptrs.iter().skip(2).step_by(3) {
+        let snapshot = TRACKED.tracker().snapshot();
+        assert!(snapshot
+            .allocations
+            .iter()
+            .any(|record| record.ptr == ptr as usize));
+        unsafe { TRACKED.dealloc(ptr, layout(16)) };
+    }
+    assert_eq!(
+        TRACKED.tracker().stats().live_allocations,
+        before.live_allocations
+    );
+}
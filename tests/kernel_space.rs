@@ -0,0 +1,224 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    init,
+    memory::{
+        self,
+        frame::BitmapFrameAllocator,
+        kernel_space::{self, Region, SpaceError},
+    },
+    test_panic_handler,
+};
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+        Translate,
+    },
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+#[test_case]
+fn ranges_do_not_overlap() {
+    let a = kernel_space::allocate(Region::Stacks, 3 * 4096, 4096).unwrap();
+    let b = kernel_space::allocate(Region::Stacks, 100, 4096).unwrap();
+    assert_eq!(b.size(), 4096);
+    assert!(a.end() <= b.start() || b.end() <= a.start());
+    assert_eq!(Region::containing(a.start()), Some(Region::Stacks));
+    assert_eq!(kernel_space::find(b.start() + 8u64), Some(b));
+
+    kernel_space::free(a).unwrap();
+    kernel_space::free(b).unwrap();
+    assert_eq!(kernel_space::free(a), Err(SpaceError::NotAllocated));
+}
+
+#[test_case]
+fn freed_ranges_are_reused() {
+    let a = kernel_space::allocate(Region::PerCpu, 4096, 4096).unwrap();
+    kernel_space::free(a).unwrap();
+    let b = kernel_space::allocate(Region::PerCpu, 4096, 4096).unwrap();
+    assert_eq!(a.start(), b.start());
+    kernel_space::free(b).unwrap();
+}
+
+#[test_case]
+fn ranges_are_aligned() {
+    let a = kernel_space::allocate(Region::Stacks, 4096, 4096).unwrap();
+    let b = kernel_space::allocate(Region::Stacks, Size2MiB::SIZE, Size2MiB::SIZE).unwrap();
+    assert!(b.start().is_aligned(Size2MiB::SIZE));
+    kernel_space::free(a).unwrap();
+    kernel_space::free(b).unwrap();
+}
+
+#[test_case]
+fn regions_run_out_of_space() {
+    let size = Region::Mmio.end() - Region::Mmio.start();
+    assert_eq!(
+        kernel_space::allocate(Region::Mmio, size + 1, 4096),
+        Err(SpaceError::OutOfSpace)
+    );
+}
+
+#[test_case]
+fn unmapping_frees_frames() {
+    let range = kernel_space::allocate(Region::Stacks, 4 * 4096, 4096).unwrap();
+    memory::with_kernel_memory(|mapper, frames| {
+        let pages = range.pages::<Size4KiB>();
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        kernel_space::map_range(mapper, frames, pages, flags).unwrap();
+        let mapped = frames.stats();
+        for page in pages {
+            assert!(mapper.translate_addr(page.start_address()).is_some());
+        }
+
+        kernel_space::unmap_range(mapper, frames, pages).unwrap();
+        assert_eq!(frames.stats().free, mapped.free + 4);
+        assert!(mapper.translate_addr(range.start()).is_none());
+    })
+    .unwrap();
+    kernel_space::free(range).unwrap();
+}
+
+#[test_case]
+fn mapped_pages_are_zeroed() {
+    let range = kernel_space::allocate(Region::Stacks, 4096, 4096).unwrap();
+    memory::with_kernel_memory(|mapper, frames| {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        kernel_space::map_range(mapper, frames, range.pages::<Size4KiB>(), flags).unwrap();
+    })
+    .unwrap();
+
+    let words = range.start().as_ptr::<u64>();
+    assert!((0..512).all(|i| unsafe { words.add(i).read_volatile() } == 0));
+
+    memory::with_kernel_memory(|mapper, frames| {
+        kernel_space::unmap_range(mapper, frames, range.pages::<Size4KiB>()).unwrap();
+    })
+    .unwrap();
+    kernel_space::free(range).unwrap();
+}
+
+#[test_case]
+fn physical_ranges_keep_their_frames() {
+    let range = kernel_space::allocate(Region::Mmio, 4096, 4096).unwrap();
+    memory::with_kernel_memory(|mapper, frames| {
+        let frame: PhysFrame = frames.allocate_frame().unwrap();
+        let pages = range.pages::<Size4KiB>();
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        kernel_space::map_physical_range(mapper, frames, pages, frame, flags).unwrap();
+        assert_eq!(
+            mapper.translate_addr(range.start() + 8u64),
+            Some(frame.start_address() + 8u64)
+        );
+
+        let mapped = frames.stats();
+        kernel_space::unmap_physical_range(mapper, pages).unwrap();
+        assert_eq!(frames.stats(), mapped);
+        assert!(mapper.translate_addr(range.start()).is_none());
+        unsafe { frames.deallocate_frame(frame) };
+    })
+    .unwrap();
+    kernel_space::free(range).unwrap();
+}
+
+/// Returns the size of the page mapping the given address.
+fn frame_size(mapper: &impl Translate, addr: VirtAddr) -> Option<u64> {
+    match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, .. } => Some(frame.size()),
+        _ => None,
+    }
+}
+
+#[test_case]
+fn physical_ranges_use_huge_pages_where_aligned() {
+    let range = kernel_space::allocate(Region::Mmio, 2 * Size2MiB::SIZE, Size2MiB::SIZE).unwrap();
+    memory::with_kernel_memory(|mapper, frames| {
+        let frame: PhysFrame<Size2MiB> = frames.allocate_frame().unwrap();
+        let size = Size2MiB::SIZE + 4096;
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        kernel_space::map_physical_huge(
+            mapper,
+            frames,
+            range.start(),
+            frame.start_address(),
+            size,
+            flags,
+        )
+        .unwrap();
+
+        assert_eq!(
+            frame_size(mapper, range.start() + 8u64),
+            Some(Size2MiB::SIZE)
+        );
+        assert_eq!(
+            frame_size(mapper, range.start() + Size2MiB::SIZE),
+            Some(Size4KiB::SIZE)
+        );
+        assert!(matches!(
+            mapper.translate(range.start()),
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(mapped),
+                ..
+            } if mapped == frame
+        ));
+
+        kernel_space::unmap_physical_huge(mapper, range.start(), size).unwrap();
+        assert_eq!(frame_size(mapper, range.start()), None);
+        assert_eq!(frame_size(mapper, range.start() + Size2MiB::SIZE), None);
+        unsafe { frames.deallocate_frame(frame) };
+    })
+    .unwrap();
+    kernel_space::free(range).unwrap();
+}
+
+#[test_case]
+fn unaligned_physical_ranges_use_small_pages() {
+    let range = kernel_space::allocate(Region::Mmio, 2 * Size2MiB::SIZE, Size2MiB::SIZE).unwrap();
+    memory::with_kernel_memory(|mapper, frames| {
+        let frame: PhysFrame<Size2MiB> = frames.allocate_frame().unwrap();
+        // the virtual address is aligned to 2 MiB, but the physical one is not
+        let phys = frame.start_address() + 4096u64;
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let size = Size2MiB::SIZE - 4096;
+        kernel_space::map_physical_huge(mapper, frames, range.start(), phys, size, flags).unwrap();
+        assert!(matches!(
+            mapper.translate(range.start()),
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                ..
+            }
+        ));
+
+        kernel_space::unmap_physical_huge(mapper, range.start(), size).unwrap();
+        unsafe { frames.deallocate_frame(frame) };
+    })
+    .unwrap();
+    kernel_space::free(range).unwrap();
+}
@@ -0,0 +1,146 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    init,
+    memory::{
+        self,
+        frame::BitmapFrameAllocator,
+        mmio::{self, CacheMode, RegisterBlock},
+    },
+    test_panic_handler,
+};
+use volatile::Volatile;
+use x86_64::{
+    structures::paging::{
+        mapper::TranslateResult, FrameAllocator, FrameDeallocator, PageTableFlags, PhysFrame,
+        Translate,
+    },
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+#[repr(C)]
+struct Registers {
+    control: Volatile<u32>,
+    status: Volatile<u32>,
+    data: Volatile<u64>,
+}
+
+fn allocate_frame() -> PhysFrame {
+    memory::with_kernel_memory(|_, frames| frames.allocate_frame())
+        .unwrap()
+        .unwrap()
+}
+
+fn deallocate_frame(frame: PhysFrame) {
+    memory::with_kernel_memory(|_, frames| unsafe { frames.deallocate_frame(frame) }).unwrap();
+}
+
+fn flags_of(addr: VirtAddr) -> PageTableFlags {
+    memory::with_kernel_memory(|mapper, _| match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{addr:?} is not mapped"),
+    })
+    .unwrap()
+}
+
+#[test_case]
+fn pat_has_write_combining_entry() {
+    // WB, WC, UC-, UC, WB, WT, UC-, UC
+    assert_eq!(mmio::read_pat(), 0x0007_0406_0007_0106);
+}
+
+#[test_case]
+fn cache_modes_select_pat_entries() {
+    let frame = allocate_frame();
+    let cache_bits = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    for (cache_mode, expected) in [
+        (CacheMode::WriteBack, PageTableFlags::empty()),
+        (CacheMode::WriteCombining, PageTableFlags::WRITE_THROUGH),
+        (CacheMode::Uncached, cache_bits),
+    ] {
+        let mapping = unsafe { mmio::ioremap(frame.start_address(), 4096, cache_mode) }.unwrap();
+        let flags = flags_of(mapping.addr());
+        assert_eq!(flags & cache_bits, expected);
+        assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+        mmio::iounmap(mapping).unwrap();
+    }
+    deallocate_frame(frame);
+}
+
+#[test_case]
+fn writes_reach_physical_memory() {
+    let frame = allocate_frame();
+    let phys = frame.start_address() + 0x10u64;
+    let mapping = unsafe { mmio::ioremap(phys, 8, CacheMode::Uncached) }.unwrap();
+    assert_eq!(mapping.addr().as_u64() % 4096, 0x10);
+
+    unsafe { mapping.as_mut_ptr::<u64>().write_volatile(0xdead_beef) };
+    let value = unsafe { memory::phys_to_virt(phys).as_ptr::<u64>().read_volatile() };
+    assert_eq!(value, 0xdead_beef);
+
+    mmio::iounmap(mapping).unwrap();
+    deallocate_frame(frame);
+}
+
+#[test_case]
+fn unaligned_ranges_span_pages() {
+    let frame = allocate_frame();
+    let phys = frame.start_address() + 4090u64;
+    let mapping = unsafe { mmio::ioremap(phys, 16, CacheMode::Uncached) }.unwrap();
+    let last = mapping.addr() + 15u64;
+    let translated = memory::with_kernel_memory(|mapper, _| mapper.translate_addr(last)).unwrap();
+    assert_eq!(translated, Some(phys + 15u64));
+
+    let addr = mapping.addr();
+    mmio::iounmap(mapping).unwrap();
+    let translated = memory::with_kernel_memory(|mapper, _| mapper.translate_addr(addr)).unwrap();
+    assert_eq!(translated, None);
+    deallocate_frame(frame);
+}
+
+#[test_case]
+fn register_blocks_access_registers() {
+    let frame = allocate_frame();
+    let mut registers =
+        unsafe { RegisterBlock::<Registers>::map(frame.start_address(), CacheMode::Uncached) }
+            .unwrap();
+    registers.control.write(1);
+    registers.status.write(2);
+    registers.data.write(3);
+    assert_eq!(registers.status.read(), 2);
+
+    let words = memory::phys_to_virt(frame.start_address()).as_ptr::<u32>();
+    assert_eq!(unsafe { words.read_volatile() }, 1);
+    assert_eq!(unsafe { words.add(1).read_volatile() }, 2);
+    assert_eq!(unsafe { words.add(2).cast::<u64>().read_volatile() }, 3);
+
+    registers.unmap().unwrap();
+    deallocate_frame(frame);
+}
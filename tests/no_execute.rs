@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)] // enable x86-interrupt ABI
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use rust_os::{
+    allocator, gdt,
+    memory::{self, frame::BitmapFrameAllocator},
+    qemu, serial_print, serial_println, test_panic_handler,
+};
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+extern crate alloc;
+
+/// The address of the heap memory which is executed.
+static TARGET: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH;
+    if error_code.contains(expected) && Cr2::read().as_u64() == TARGET.load(Ordering::SeqCst) {
+        serial_println!("[ok]");
+        qemu::exit(qemu::ExitCode::Success)
+    }
+    serial_println!("[failed]");
+    serial_println!("Unexpected page fault: {error_code:?}");
+    qemu::exit(qemu::ExitCode::Failed)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("no_execute::executing_heap_memory_faults...\t");
+
+    gdt::init();
+    memory::protection::enable();
+    TEST_IDT.load();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::protection::remap_kernel(&mut mapper, &boot_info.memory_map)
+        .expect("remapping the kernel failed");
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    // a `ret` instruction
+    let code: &'static mut [u8; 16] = Box::leak(Box::new([0xc3; 16]));
+    TARGET.store(code.as_ptr() as u64, Ordering::SeqCst);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+
+    panic!("Execution continued after executing heap memory");
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    test_panic_handler(info)
+}
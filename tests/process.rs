@@ -0,0 +1,236 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, init,
+    memory::{self, address_space::DuplicateMode, frame::BitmapFrameAllocator},
+    process::{Process, MMAP_START},
+    test_panic_handler, thread,
+    user::{self, Exit},
+};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+/// The page holding the value the programs work on, which doubles as their stack.
+const DATA: u64 = user::CODE_START + 0x10_0000;
+const DATA_PAGES: u64 = 16;
+
+/// Increments the value at `DATA` and exits with the result.
+fn increment() -> [u8; 23] {
+    let mut code = [0; 23];
+    code[..2].copy_from_slice(&[0x48, 0xb8]); // mov rax, DATA
+    code[2..10].copy_from_slice(&DATA.to_le_bytes());
+    code[10..].copy_from_slice(&[
+        0x48, 0x8b, 0x38, // mov rdi, [rax]
+        0x48, 0xff, 0xc7, // inc rdi
+        0x48, 0x89, 0x38, // mov [rax], rdi
+        0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+        0x0f, 0x05, // syscall
+    ]);
+    code
+}
+
+/// Spins for a while, then exits with the value at `DATA`.
+fn spin_and_read() -> [u8; 24] {
+    let mut code = [0; 24];
+    code[..2].copy_from_slice(&[0x48, 0xb8]); // mov rax, DATA
+    code[2..10].copy_from_slice(&DATA.to_le_bytes());
+    code[10..].copy_from_slice(&[
+        0xb9, 0x00, 0x00, 0x00, 0x10, // mov ecx, 0x1000_0000
+        0xff, 0xc9, // dec ecx
+        0x75, 0xfc, // jnz -4
+        0x48, 0x8b, 0x38, // mov rdi, [rax]
+        0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+        0x0f, 0x05, // syscall
+    ]);
+    code
+}
+
+/// Creates a process with the given code at `CODE_START`, and `DATA_PAGES`
+/// writable pages at `DATA` starting with the given value.
+fn process(code: &[u8], value: u64) -> Process {
+    let mut process = Process::new().unwrap();
+    let address_space = process.address_space_mut();
+    let code_start = VirtAddr::new(user::CODE_START);
+    address_space
+        .map_user(code_start, code.len() as u64, PageTableFlags::empty())
+        .unwrap();
+    address_space.write(code_start, code).unwrap();
+    address_space
+        .map_user(
+            VirtAddr::new(DATA),
+            DATA_PAGES * 4096,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .unwrap();
+    address_space
+        .write(VirtAddr::new(DATA), &value.to_le_bytes())
+        .unwrap();
+    process
+}
+
+fn run(process: &mut Process) -> Exit {
+    process.run(
+        VirtAddr::new(user::CODE_START),
+        VirtAddr::new(DATA + DATA_PAGES * 4096),
+    )
+}
+
+fn read_value(process: &mut Process) -> u64 {
+    let mut value = [0; 8];
+    process
+        .address_space_mut()
+        .read(VirtAddr::new(DATA), &mut value)
+        .unwrap();
+    u64::from_le_bytes(value)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|_, frame_allocator| frame_allocator.stats().free).unwrap()
+}
+
+#[test_case]
+fn forked_process_has_parent() {
+    let mut parent = process(&increment(), 0);
+    let child = parent.fork(DuplicateMode::Copy).unwrap();
+    assert_ne!(child.id(), parent.id());
+    assert_eq!(child.parent(), Some(parent.id()));
+    assert_eq!(parent.parent(), None);
+}
+
+#[test_case]
+fn copied_process_is_independent() {
+    let mut parent = process(&increment(), 41);
+    let mut child = parent.fork(DuplicateMode::Copy).unwrap();
+    assert_eq!(read_value(&mut child), 41);
+
+    assert_eq!(run(&mut child), Exit::Exited(42));
+    assert_eq!(read_value(&mut parent), 41);
+    assert_eq!(run(&mut parent), Exit::Exited(42));
+}
+
+#[test_case]
+fn copy_on_write_shares_frames() {
+    let mut parent = process(&increment(), 0);
+
+    let before = free_frames();
+    let copy = parent.fork(DuplicateMode::Copy).unwrap();
+    let copied = before - free_frames();
+    drop(copy);
+    assert_eq!(free_frames(), before);
+
+    let shared = parent.fork(DuplicateMode::CopyOnWrite).unwrap();
+    let used = before - free_frames();
+    assert!(copied > DATA_PAGES as usize);
+    assert!(used < DATA_PAGES as usize, "{used} frames used by the fork");
+    drop(shared);
+}
+
+#[test_case]
+fn copy_on_write_pages_are_copied_on_write() {
+    let mut parent = process(&increment(), 41);
+    let mut child = parent.fork(DuplicateMode::CopyOnWrite).unwrap();
+
+    // written in user mode, which faults on the shared page
+    assert_eq!(run(&mut child), Exit::Exited(42));
+    assert_eq!(read_value(&mut parent), 41);
+
+    // written by the kernel, which copies the page first
+    parent
+        .address_space_mut()
+        .write(VirtAddr::new(DATA), &1u64.to_le_bytes())
+        .unwrap();
+    assert_eq!(read_value(&mut child), 42);
+    assert_eq!(run(&mut parent), Exit::Exited(2));
+    assert_eq!(run(&mut child), Exit::Exited(43));
+}
+
+#[test_case]
+fn exited_processes_free_their_frames() {
+    let before = free_frames();
+    let mut parent = process(&increment(), 0);
+    let mut child = parent.fork(DuplicateMode::CopyOnWrite).unwrap();
+    assert_eq!(run(&mut child), Exit::Exited(1));
+    assert_eq!(run(&mut parent), Exit::Exited(1));
+    drop(parent);
+    drop(child);
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn mapping_more_than_the_free_frames_fails() {
+    let mut process = Process::new().unwrap();
+    let before = free_frames();
+    let size = (before as u64 + 1) * 4096;
+    assert!(process
+        .map_anonymous(size, PageTableFlags::NO_EXECUTE)
+        .is_err());
+    assert_eq!(free_frames(), before);
+
+    // mapped in several chunks
+    let start = process
+        .map_anonymous(1024 * 4096, PageTableFlags::NO_EXECUTE)
+        .unwrap();
+    assert_eq!(start.as_u64(), MMAP_START);
+    drop(process);
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn address_space_is_switched_with_thread() {
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    static EXIT_CODE: AtomicU64 = AtomicU64::new(0);
+
+    // both programs read the same address, and are preempted while spinning
+    thread::spawn(|| {
+        let mut process = process(&spin_and_read(), 2);
+        STARTED.store(true, Ordering::SeqCst);
+        let code = match run(&mut process) {
+            Exit::Exited(code) => code,
+            exit => panic!("program did not exit: {exit:?}"),
+        };
+        EXIT_CODE.store(code, Ordering::SeqCst);
+    });
+    while !STARTED.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+
+    let mut process = process(&spin_and_read(), 1);
+    assert_eq!(run(&mut process), Exit::Exited(1));
+    while EXIT_CODE.load(Ordering::SeqCst) == 0 {
+        thread::yield_now();
+    }
+    assert_eq!(EXIT_CODE.load(Ordering::SeqCst), 2);
+}
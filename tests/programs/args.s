@@ -0,0 +1,51 @@
+# Inspects the initial stack, and exits with
+#   bits 0..8:   argc
+#   bits 8..16:  the first character of argv[1]
+#   bits 16..24: the number of environment variables
+#   bit 24:      set if the AT_PAGESZ entry of the auxiliary vector is 4096
+#   bit 25:      set if the stack pointer is 16-byte aligned
+
+    .intel_syntax noprefix
+    .globl _start
+
+    .text
+_start:
+    mov rcx, [rsp]              # argc
+    mov rdi, rcx
+    mov rsi, [rsp + 16]         # argv[1]
+    movzx eax, byte ptr [rsi]
+    shl eax, 8
+    or rdi, rax
+
+    lea rsi, [rsp + rcx * 8 + 16]   # envp
+    xor edx, edx
+1:
+    cmp qword ptr [rsi + rdx * 8], 0
+    je 2f
+    inc edx
+    jmp 1b
+2:
+    lea rsi, [rsi + rdx * 8 + 8]    # auxv
+    shl edx, 16
+    or rdi, rdx
+
+3:
+    mov rax, [rsi]
+    test rax, rax               # AT_NULL
+    jz 5f
+    cmp rax, 6                  # AT_PAGESZ
+    jne 4f
+    cmp qword ptr [rsi + 8], 4096
+    jne 4f
+    bts rdi, 24
+4:
+    add rsi, 16
+    jmp 3b
+
+5:
+    test rsp, 15
+    jnz 6f
+    bts rdi, 25
+6:
+    xor eax, eax                # SYS_EXIT
+    syscall
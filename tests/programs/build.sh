@@ -0,0 +1,17 @@
+#!/bin/sh
+# Assembles the test programs embedded by `tests/elf_loader.rs` into static
+# ELF executables linked at the start of the user part of the address space,
+# using the linker script of the same name if there is one.
+set -e
+cd "$(dirname "$0")"
+for source in *.s; do
+    name="${source%.s}"
+    as --64 -o "$name.o" "$source"
+    script=""
+    if [ -f "$name.ld" ]; then
+        script="-T $name.ld"
+    fi
+    ld $script -static -nostdlib --build-id=none -z noexecstack -z separate-code \
+        -z max-page-size=0x1000 -Ttext-segment=0x100000000000 -o "$name.elf" "$name.o"
+    rm "$name.o"
+done
@@ -0,0 +1,24 @@
+# Updates a variable in `.data` and one in `.bss`, and exits with the sum of
+# both, which is 42.
+
+    .intel_syntax noprefix
+    .globl _start
+
+    .data
+initialized:
+    .quad 40
+
+    .bss
+zeroed:
+    .quad 0
+
+    .text
+_start:
+    mov rax, [rip + initialized]
+    add rax, [rip + zeroed]
+    mov qword ptr [rip + zeroed], 2
+    add rax, [rip + zeroed]
+    mov [rip + initialized], rax
+    mov rdi, [rip + initialized]
+    xor eax, eax            # SYS_EXIT
+    syscall
@@ -0,0 +1,10 @@
+# Exits with code 42.
+
+    .intel_syntax noprefix
+    .globl _start
+
+    .text
+_start:
+    mov edi, 42
+    xor eax, eax            # SYS_EXIT
+    syscall
@@ -0,0 +1,16 @@
+# Keeps its code and data in the same page, as two segments with different
+# permissions, and exits with the updated data, which is 42.
+
+    .intel_syntax noprefix
+    .globl _start
+
+    .data
+value:
+    .quad 40
+
+    .text
+_start:
+    add qword ptr [rip + value], 2
+    mov rdi, [rip + value]
+    xor eax, eax            # SYS_EXIT
+    syscall
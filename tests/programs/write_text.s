@@ -0,0 +1,12 @@
+# Writes to its own code, which is mapped read-only.
+
+    .intel_syntax noprefix
+    .globl _start
+
+    .text
+_start:
+    lea rax, [rip + _start]
+    mov byte ptr [rax], 0x90
+    mov edi, 1
+    xor eax, eax            # SYS_EXIT
+    syscall
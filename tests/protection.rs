@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, init,
+    memory::{self, frame::BitmapFrameAllocator, protection},
+    test_panic_handler,
+};
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, PageTableFlags, Translate},
+    PhysAddr, VirtAddr,
+};
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    protection::remap_kernel(&mut mapper, &boot_info.memory_map)
+        .expect("remapping the kernel failed");
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+static DATA: AtomicU64 = AtomicU64::new(0);
+static READ_ONLY_DATA: [u64; 4] = [1, 2, 3, 4];
+
+fn flags_of(addr: VirtAddr) -> PageTableFlags {
+    memory::with_kernel_memory(|mapper, _| match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{addr:?} is not mapped"),
+    })
+    .unwrap()
+}
+
+#[test_case]
+fn supported_protections_are_enabled() {
+    let supported = protection::supported();
+    let enabled = protection::enabled();
+    assert!(enabled.no_execute);
+    assert!(enabled.write_protect);
+    assert_eq!(enabled.smep, supported.smep);
+    assert_eq!(enabled.smap, supported.smap);
+}
+
+#[test_case]
+fn kernel_segments_are_not_writable_and_executable() {
+    let segments = protection::kernel_segments().unwrap();
+    let mut count = 0;
+    for segment in segments {
+        assert!(!(segment.is_writable() && segment.is_executable()));
+        count += 1;
+    }
+    assert!(count >= 2);
+}
+
+#[test_case]
+fn code_is_read_only() {
+    let flags = flags_of(VirtAddr::new(flags_of as usize as u64));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn data_is_not_executable() {
+    DATA.store(1, Ordering::Relaxed);
+    let flags = flags_of(VirtAddr::from_ptr(&DATA));
+    assert!(flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+    let flags = flags_of(VirtAddr::from_ptr(&READ_ONLY_DATA));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn heap_is_not_executable() {
+    let value = Box::new(42u64);
+    let flags = flags_of(VirtAddr::from_ptr(&*value));
+    assert!(flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn physical_memory_mapping_is_not_executable() {
+    let addr = memory::phys_to_virt(PhysAddr::new(0x10_0000));
+    let flags =
+        memory::with_kernel_memory(|mapper, _| mapper.level_4_table()[addr.p4_index()].flags())
+            .unwrap();
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+}
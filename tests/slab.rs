@@ -0,0 +1,157 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator::{
+        self,
+        slab::{self, SlabCache},
+    },
+    init,
+    memory::{self, frame::BitmapFrameAllocator},
+    task::Task,
+    test_panic_handler,
+};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Node {
+    value: u64,
+    children: [u64; 7],
+}
+
+fn new_node() -> Node {
+    Node {
+        value: 42,
+        children: [0; 7],
+    }
+}
+
+#[test_case]
+fn objects_are_constructed() {
+    static CACHE: SlabCache<Node> = SlabCache::new("constructed", new_node);
+
+    let node = CACHE.allocate();
+    assert_eq!(*node, new_node());
+
+    let mut other = CACHE.allocate_with(Node {
+        value: 1,
+        children: [1; 7],
+    });
+    other.value += 1;
+    assert_eq!(other.value, 2);
+    assert_ne!(&*node as *const Node, &*other as *const Node);
+}
+
+#[test_case]
+fn slabs_grow_and_are_released() {
+    static CACHE: SlabCache<Node> = SlabCache::new("released", new_node);
+
+    let objects_per_slab = CACHE.stats().objects_per_slab;
+    assert!(objects_per_slab > 1);
+
+    let nodes: Vec<_> = (0..3 * objects_per_slab)
+        .map(|_| CACHE.allocate())
+        .collect();
+    let stats = CACHE.stats();
+    assert_eq!(stats.slabs, 3);
+    assert_eq!(stats.objects_in_use, 3 * objects_per_slab);
+    for node in &nodes {
+        assert_eq!(
+            &**node as *const Node as usize % core::mem::align_of::<Node>(),
+            0
+        );
+    }
+
+    drop(nodes);
+    let stats = CACHE.stats();
+    // one empty slab is kept for the next allocation
+    assert_eq!(stats.slabs, 1);
+    assert_eq!(stats.released_slabs, 2);
+    assert_eq!(stats.objects_in_use, 0);
+
+    assert_eq!(CACHE.shrink(), 1);
+    assert_eq!(CACHE.stats().slabs, 0);
+}
+
+#[test_case]
+fn stats_are_reported_per_cache() {
+    static CACHE: SlabCache<u64> = SlabCache::new("reported", u64::default);
+
+    let _value = CACHE.allocate();
+    let mut found = None;
+    slab::for_each_cache(|stats| {
+        if stats.name == "reported" {
+            found = Some(*stats);
+        }
+    });
+    assert_eq!(found, Some(CACHE.stats()));
+    assert_eq!(found.unwrap().objects_in_use, 1);
+}
+
+#[test_case]
+fn page_sized_objects_share_larger_slabs() {
+    #[repr(align(4096))]
+    struct Page([u8; 4096]);
+
+    static CACHE: SlabCache<Page> = SlabCache::without_constructor("pages");
+    let stats = CACHE.stats();
+    assert!(stats.slab_size > 4096);
+    assert!(stats.objects_per_slab > 1);
+
+    let pages: Vec<_> = (0..=stats.objects_per_slab)
+        .map(|_| unsafe { CACHE.allocate_zeroed() })
+        .collect();
+    assert_eq!(CACHE.stats().slabs, 2);
+    for page in &pages {
+        assert_eq!(&**page as *const Page as usize % 4096, 0);
+        assert!(page.0.iter().all(|&byte| byte == 0));
+    }
+}
+
+#[test_case]
+fn tasks_are_allocated_from_their_cache() {
+    let in_use = || {
+        let mut in_use = None;
+        slab::for_each_cache(|stats| {
+            if stats.name == "task" {
+                in_use = Some(stats.objects_in_use);
+            }
+        });
+        in_use.unwrap_or(0)
+    };
+    let before = in_use();
+    let task = Task::new(async {});
+    assert_eq!(in_use(), before + 1);
+    drop(task);
+    assert_eq!(in_use(), before);
+}
@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, apic, init, interrupt,
+    memory::{self, frame::BitmapFrameAllocator},
+    percpu, smp, test_panic_handler,
+};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    interrupt::init_apic(&mut mapper, &mut frame_allocator).expect("APIC initialization failed");
+    smp::init(&mut mapper, &mut frame_allocator).expect("SMP initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+#[test_case]
+fn all_processors_online() {
+    // QEMU is started with `-smp 4`
+    assert_eq!(smp::online_cpus(), 4);
+    assert_eq!(percpu::cpu_count(), 4);
+}
+
+#[test_case]
+fn bootstrap_processor_data() {
+    let cpu = percpu::current();
+    assert!(percpu::is_bootstrap_cpu());
+    assert_eq!(cpu.id, 0);
+    assert_eq!(cpu.apic_id, apic::local_apic_id());
+}
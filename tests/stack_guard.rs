@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+
+use core::{arch::asm, fmt::Write, panic::PanicInfo};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    gdt, interrupt,
+    memory::{
+        self,
+        frame::BitmapFrameAllocator,
+        stack::{self, StackOwner},
+    },
+    qemu, serial_print, serial_println,
+    thread::ThreadId,
+};
+use x86_64::VirtAddr;
+
+/// The owner of the stack which is overflowed.
+const OWNER: StackOwner = StackOwner::Thread(ThreadId::BOOT);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_guard::overflow_is_reported...\t");
+
+    gdt::init();
+    memory::protection::enable();
+    interrupt::init_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    let stack = stack::allocate(4096 * 4, OWNER).expect("stack allocation failed");
+    unsafe {
+        asm!(
+            "mov rsp, {}",
+            "call {}",
+            in(reg) stack.top().as_u64(),
+            sym overflow,
+            options(noreturn),
+        );
+    }
+}
+
+extern "C" fn overflow() -> ! {
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // push return address to the stack
+    volatile::Volatile::new(0).read(); // disable tail call optimization
+}
+
+/// A fixed-size buffer keeping the start of a formatted message.
+struct Message {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl Message {
+    fn new() -> Self {
+        Self {
+            bytes: [0; 256],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn contains(&self, other: &Message) -> bool {
+        let needle = other.as_bytes();
+        self.as_bytes()
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut report = Message::new();
+    let mut expected = Message::new();
+    let _ = write!(report, "{info}");
+    let _ = write!(expected, "EXCEPTION: KERNEL STACK OVERFLOW in {OWNER:?}");
+    if report.contains(&expected) {
+        serial_println!("[ok]");
+        qemu::exit(qemu::ExitCode::Success)
+    }
+    serial_println!("[failed]\n");
+    serial_println!("Error: {info}\n");
+    qemu::exit(qemu::ExitCode::Failed)
+}
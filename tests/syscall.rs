@@ -0,0 +1,280 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{panic::PanicInfo, time::Duration};
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, init,
+    memory::{self, frame::BitmapFrameAllocator},
+    process::{Process, MMAP_START},
+    syscall::{self, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE},
+    task::keyboard,
+    test_panic_handler, time,
+    user::{self, Exit},
+};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+/// A writable page for the buffers of the programs, which doubles as their stack.
+const DATA: u64 = user::CODE_START + 0x10_0000;
+/// A page which the programs may read but not write.
+const READ_ONLY: u64 = DATA + 0x10_0000;
+
+static SECRET: [u8; 4] = *b"key\n";
+
+/// Makes the given system call, then exits with its result.
+fn syscall_program(number: u64, arguments: &[u64]) -> Vec<u8> {
+    // mov rdi/rsi/rdx/r10/r8/r9, imm64
+    const MOVES: [[u8; 2]; 6] = [
+        [0x48, 0xbf],
+        [0x48, 0xbe],
+        [0x48, 0xba],
+        [0x49, 0xba],
+        [0x49, 0xb8],
+        [0x49, 0xb9],
+    ];
+    let mut code = [0x48, 0xb8].to_vec(); // mov rax, number
+    code.extend_from_slice(&number.to_le_bytes());
+    for (mov, argument) in MOVES.iter().zip(arguments) {
+        code.extend_from_slice(mov);
+        code.extend_from_slice(&argument.to_le_bytes());
+    }
+    code.extend_from_slice(&[
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+        0x0f, 0x05, // syscall
+    ]);
+    code
+}
+
+/// Creates a process with the given code at `CODE_START`, a writable page at
+/// `DATA` and a read-only page at `READ_ONLY`, both starting with the given data.
+fn process(code: &[u8], data: &[u8]) -> Process {
+    let mut process = Process::new().unwrap();
+    let address_space = process.address_space_mut();
+    let code_start = VirtAddr::new(user::CODE_START);
+    address_space
+        .map_user(code_start, code.len() as u64, PageTableFlags::empty())
+        .unwrap();
+    address_space.write(code_start, code).unwrap();
+    for (start, flags) in [
+        (DATA, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE),
+        (READ_ONLY, PageTableFlags::NO_EXECUTE),
+    ] {
+        let start = VirtAddr::new(start);
+        address_space.map_user(start, 4096, flags).unwrap();
+        address_space.write(start, data).unwrap();
+    }
+    process
+}
+
+fn run(process: &mut Process) -> Exit {
+    process.run(VirtAddr::new(user::CODE_START), VirtAddr::new(DATA + 4096))
+}
+
+/// Runs the given system call in a new process with the given data, and
+/// returns its result.
+fn call(number: u64, arguments: &[u64], data: &[u8]) -> u64 {
+    let mut process = process(&syscall_program(number, arguments), data);
+    match run(&mut process) {
+        Exit::Exited(result) => result,
+        exit => panic!("program did not exit: {exit:?}"),
+    }
+}
+
+fn error(code: i64) -> u64 {
+    0u64.wrapping_sub(code as u64)
+}
+
+#[test_case]
+fn write_returns_length() {
+    let arguments = [syscall::STDOUT, DATA, 6];
+    assert_eq!(call(syscall::SYS_WRITE, &arguments, b"hello\n"), 6);
+    let arguments = [syscall::STDERR, READ_ONLY, 0];
+    assert_eq!(call(syscall::SYS_WRITE, &arguments, b""), 0);
+}
+
+#[test_case]
+fn write_to_unknown_descriptor_fails() {
+    let arguments = [syscall::STDIN, DATA, 6];
+    let result = call(syscall::SYS_WRITE, &arguments, b"hello\n");
+    assert_eq!(result, error(syscall::EBADF));
+}
+
+#[test_case]
+fn write_of_inaccessible_buffer_fails() {
+    let secret = SECRET.as_ptr() as u64;
+    for (buf, len) in [
+        (DATA + 4096, 1),
+        (DATA + 4000, 200),
+        (secret, SECRET.len() as u64),
+        (0x8000_0000_0000, 1),
+    ] {
+        let arguments = [syscall::STDOUT, buf, len];
+        let result = call(syscall::SYS_WRITE, &arguments, b"");
+        assert_eq!(result, error(syscall::EFAULT), "buffer at {buf:#x}");
+    }
+}
+
+#[test_case]
+fn read_returns_typed_text() {
+    keyboard::push_input(b"hi");
+    let mut process = process(
+        &syscall_program(syscall::SYS_READ, &[syscall::STDIN, DATA, 16]),
+        &[0; 16],
+    );
+    assert_eq!(run(&mut process), Exit::Exited(2));
+
+    let mut data = [0; 3];
+    process
+        .address_space_mut()
+        .read(VirtAddr::new(DATA), &mut data)
+        .unwrap();
+    assert_eq!(&data, b"hi\0");
+}
+
+#[test_case]
+fn read_into_read_only_buffer_fails() {
+    let arguments = [syscall::STDIN, READ_ONLY, 16];
+    let result = call(syscall::SYS_READ, &arguments, b"");
+    assert_eq!(result, error(syscall::EFAULT));
+}
+
+#[test_case]
+fn getpid_returns_process_id() {
+    let mut process = process(&syscall_program(syscall::SYS_GETPID, &[]), b"");
+    let id = process.id().as_u64();
+    assert_eq!(run(&mut process), Exit::Exited(id));
+}
+
+#[test_case]
+fn yield_returns_zero() {
+    assert_eq!(call(syscall::SYS_YIELD, &[], b""), 0);
+}
+
+#[test_case]
+fn sleep_waits() {
+    let start = time::uptime();
+    assert_eq!(call(syscall::SYS_SLEEP, &[50], b""), 0);
+    assert!(time::uptime() - start >= Duration::from_millis(50));
+}
+
+#[test_case]
+fn mmap_maps_zeroed_writable_memory() {
+    let prot = PROT_READ | PROT_WRITE;
+    let arguments = [0, 8192, prot, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0];
+    let mut code = syscall_program(syscall::SYS_MMAP, &arguments);
+    // exit with the second word of the second page after incrementing it,
+    // instead of with the address
+    code.truncate(code.len() - 7);
+    code.extend_from_slice(&[
+        0x48, 0xff, 0x80, 0x08, 0x10, 0x00, 0x00, // inc qword ptr [rax + 0x1008]
+        0x48, 0x8b, 0xb8, 0x08, 0x10, 0x00, 0x00, // mov rdi, [rax + 0x1008]
+        0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+        0x0f, 0x05, // syscall
+    ]);
+    let mut process = process(&code, b"");
+    assert_eq!(run(&mut process), Exit::Exited(1));
+
+    let mut value = [0; 8];
+    process
+        .address_space_mut()
+        .read(VirtAddr::new(MMAP_START + 0x1008), &mut value)
+        .unwrap();
+    assert_eq!(u64::from_le_bytes(value), 1);
+}
+
+#[test_case]
+fn mmap_returns_distinct_ranges() {
+    let arguments = [0, 1, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0];
+    let mut process = process(&syscall_program(syscall::SYS_MMAP, &arguments), b"");
+    assert_eq!(run(&mut process), Exit::Exited(MMAP_START));
+    assert_eq!(run(&mut process), Exit::Exited(MMAP_START + 4096));
+}
+
+#[test_case]
+fn mmap_with_invalid_arguments_fails() {
+    let anonymous = MAP_PRIVATE | MAP_ANONYMOUS;
+    for arguments in [
+        [0, 0, PROT_READ, anonymous, 0, 0],
+        [0, 4096, 0, anonymous, 0, 0],
+        [0, 4096, 0x8, anonymous, 0, 0],
+        [0, 4096, PROT_READ, MAP_PRIVATE, 0, 0],
+        [0, 4096, PROT_READ, anonymous, 0, 4096],
+    ] {
+        let result = call(syscall::SYS_MMAP, &arguments, b"");
+        assert_eq!(result, error(syscall::EINVAL), "{arguments:?}");
+    }
+    for len in [syscall::MAX_MMAP_SIZE + 1, u64::MAX] {
+        let arguments = [0, len, PROT_READ, anonymous, 0, 0];
+        let result = call(syscall::SYS_MMAP, &arguments, b"");
+        assert_eq!(result, error(syscall::ENOMEM), "{len:#x}");
+    }
+}
+
+#[test_case]
+fn munmap_unmaps_pages() {
+    let arguments = [0, 4096, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0];
+    let mut process = process(&syscall_program(syscall::SYS_MMAP, &arguments), b"");
+    assert_eq!(run(&mut process), Exit::Exited(MMAP_START));
+
+    let mut code = syscall_program(syscall::SYS_MUNMAP, &[MMAP_START, 4096]);
+    code.truncate(code.len() - 7);
+    code.extend_from_slice(&[0x48, 0xb8]); // mov rax, MMAP_START
+    code.extend_from_slice(&MMAP_START.to_le_bytes());
+    code.extend_from_slice(&[
+        0x48, 0x8b, 0x38, // mov rdi, [rax]
+        0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+        0x0f, 0x05, // syscall
+    ]);
+    process
+        .address_space_mut()
+        .write(VirtAddr::new(user::CODE_START), &code)
+        .unwrap();
+    assert_eq!(
+        run(&mut process),
+        Exit::PageFault(VirtAddr::new(MMAP_START))
+    );
+}
+
+#[test_case]
+fn munmap_with_invalid_arguments_fails() {
+    for arguments in [
+        [MMAP_START, 0],
+        [MMAP_START + 1, 4096],
+        [DATA, 4096],
+        [0x8000_0000_0000, 4096],
+    ] {
+        let result = call(syscall::SYS_MUNMAP, &arguments, b"");
+        assert_eq!(result, error(syscall::EINVAL), "{arguments:?}");
+    }
+}
@@ -0,0 +1,197 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, init,
+    memory::{
+        self,
+        frame::BitmapFrameAllocator,
+        stack::{self, StackOwner},
+    },
+    test_panic_handler,
+    thread::{self, ThreadId, WaitChannel},
+    time,
+};
+use x86_64::{instructions::hlt, structures::paging::Translate, VirtAddr};
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+#[test_case]
+fn boot_thread_id() {
+    assert_eq!(thread::current(), ThreadId::BOOT);
+}
+
+#[test_case]
+fn spawned_thread_runs_to_completion() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let id = thread::spawn(|| {
+        assert_ne!(thread::current(), ThreadId::BOOT);
+        DONE.store(true, Ordering::SeqCst);
+    });
+    assert_ne!(id, ThreadId::BOOT);
+
+    while !DONE.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn spawned_thread_runs_on_guarded_stack() {
+    static STACK_ADDR: AtomicU64 = AtomicU64::new(0);
+
+    let id = thread::spawn(|| {
+        let local = 0u64;
+        STACK_ADDR.store(VirtAddr::from_ptr(&local).as_u64(), Ordering::SeqCst);
+    });
+    while STACK_ADDR.load(Ordering::SeqCst) == 0 {
+        thread::yield_now();
+    }
+
+    // exited threads are only reaped when the next thread is spawned
+    let kernel_stack = stack::find(VirtAddr::new(STACK_ADDR.load(Ordering::SeqCst)))
+        .expect("thread does not run on a kernel stack");
+    assert_eq!(kernel_stack.owner(), StackOwner::Thread(id));
+    assert_eq!(kernel_stack.size(), thread::STACK_SIZE as u64);
+    assert_eq!(
+        stack::find_guard(kernel_stack.bottom() - 1u64),
+        Some(kernel_stack)
+    );
+    let translated =
+        memory::with_kernel_memory(|mapper, _| mapper.translate_addr(kernel_stack.bottom() - 1u64))
+            .unwrap();
+    assert_eq!(translated, None);
+}
+
+#[test_case]
+fn runaway_thread_is_preempted() {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    // the thread never yields, so only the timer interrupt can switch away from it
+    thread::spawn(|| {
+        while !STOP.load(Ordering::SeqCst) {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+
+    // busy-wait without yielding, so the thread only runs if we are preempted
+    while COUNTER.load(Ordering::SeqCst) == 0 {
+        core::hint::spin_loop();
+    }
+
+    STOP.store(true, Ordering::SeqCst);
+}
+
+#[test_case]
+fn many_short_lived_threads() {
+    static FINISHED: AtomicU64 = AtomicU64::new(0);
+
+    for _ in 0..100 {
+        let before = FINISHED.load(Ordering::SeqCst);
+        thread::spawn(|| {
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        });
+        while FINISHED.load(Ordering::SeqCst) == before {
+            thread::yield_now();
+        }
+    }
+}
+
+#[test_case]
+fn spawning_while_another_thread_allocates() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static FINISHED: AtomicU64 = AtomicU64::new(0);
+
+    // preempted while holding the heap lock from time to time
+    thread::spawn(|| {
+        while !STOP.load(Ordering::SeqCst) {
+            drop(alloc::vec![0u8; 1024]);
+        }
+    });
+
+    for _ in 0..100 {
+        let before = FINISHED.load(Ordering::SeqCst);
+        thread::spawn(|| {
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        });
+        while FINISHED.load(Ordering::SeqCst) == before {
+            thread::yield_now();
+        }
+    }
+    STOP.store(true, Ordering::SeqCst);
+}
+
+#[test_case]
+fn sleeping_thread_is_woken_at_deadline() {
+    static WOKEN_AT: AtomicU64 = AtomicU64::new(0);
+
+    let deadline = time::uptime() + Duration::from_millis(50);
+    thread::spawn(move || {
+        thread::sleep_until(deadline);
+        WOKEN_AT.store(time::uptime().as_nanos() as u64, Ordering::SeqCst);
+    });
+    while WOKEN_AT.load(Ordering::SeqCst) == 0 {
+        hlt();
+    }
+    assert!(Duration::from_nanos(WOKEN_AT.load(Ordering::SeqCst)) >= deadline);
+}
+
+#[test_case]
+fn blocked_thread_is_not_scheduled_until_woken() {
+    static READY: AtomicBool = AtomicBool::new(false);
+    static CHECKS: AtomicU64 = AtomicU64::new(0);
+    static WOKEN: AtomicBool = AtomicBool::new(false);
+
+    let channel = WaitChannel::of(&READY);
+    thread::spawn(move || {
+        thread::wait(channel, || {
+            CHECKS.fetch_add(1, Ordering::SeqCst);
+            READY.load(Ordering::SeqCst).then_some(())
+        });
+        WOKEN.store(true, Ordering::SeqCst);
+    });
+
+    // many time slices pass, in which the thread does not run again
+    time::busy_wait(Duration::from_millis(50));
+    assert_eq!(CHECKS.load(Ordering::SeqCst), 1);
+
+    READY.store(true, Ordering::SeqCst);
+    thread::wake_all(channel);
+    while !WOKEN.load(Ordering::SeqCst) {
+        hlt();
+    }
+    assert_eq!(CHECKS.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,135 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, sync::Arc, task::Wake};
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, init,
+    memory::{self, frame::BitmapFrameAllocator},
+    test_panic_handler, time,
+};
+use x86_64::{instructions::hlt, VirtAddr};
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+struct FlagWaker(AtomicBool);
+
+impl Wake for FlagWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn uptime_advances() {
+    let start = time::uptime();
+    let start_ticks = time::ticks();
+    for _ in 0..5 {
+        hlt();
+    }
+    assert!(time::uptime() > start);
+    assert!(time::ticks() > start_ticks);
+}
+
+#[test_case]
+fn set_tick_rate() {
+    assert_eq!(time::set_tick_rate(1000), 1000);
+
+    let start = time::uptime();
+    let start_ticks = time::ticks();
+    while time::ticks() < start_ticks + 10 {
+        hlt();
+    }
+    assert!(time::uptime() - start >= Duration::from_millis(9));
+
+    time::set_tick_rate(time::DEFAULT_TICK_RATE);
+}
+
+#[test_case]
+fn sleep_wakes_after_deadline() {
+    let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let duration = Duration::from_millis(50);
+    let start = time::uptime();
+    let mut sleep = Box::pin(time::sleep(duration));
+    assert_eq!(sleep.as_mut().poll(&mut cx), Poll::Pending);
+
+    while !flag.0.load(Ordering::SeqCst) {
+        hlt();
+        time::wake_sleepers();
+    }
+
+    assert!(time::uptime() - start >= duration);
+    assert_eq!(sleep.as_mut().poll(&mut cx), Poll::Ready(()));
+}
+
+#[test_case]
+fn zero_sleep_is_ready() {
+    let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+    let waker = Waker::from(flag);
+    let mut cx = Context::from_waker(&waker);
+
+    let mut sleep = Box::pin(time::sleep(Duration::ZERO));
+    assert_eq!(sleep.as_mut().poll(&mut cx), Poll::Ready(()));
+}
+
+#[test_case]
+fn only_expired_sleeps_are_woken() {
+    let flags: [_; 3] = core::array::from_fn(|_| Arc::new(FlagWaker(AtomicBool::new(false))));
+    let wakers = flags.clone().map(Waker::from);
+
+    let mut dropped = Box::pin(time::sleep(Duration::from_millis(10)));
+    let mut short = Box::pin(time::sleep(Duration::from_millis(20)));
+    let mut long = Box::pin(time::sleep(Duration::from_secs(10)));
+    for (sleep, waker) in [&mut dropped, &mut short, &mut long]
+        .into_iter()
+        .zip(&wakers)
+    {
+        let mut cx = Context::from_waker(waker);
+        // a repeated poll replaces the waker instead of adding another one
+        assert_eq!(sleep.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(sleep.as_mut().poll(&mut cx), Poll::Pending);
+    }
+    drop(dropped);
+
+    while !flags[1].0.load(Ordering::SeqCst) {
+        hlt();
+        time::wake_sleepers();
+    }
+    assert!(!flags[0].0.load(Ordering::SeqCst));
+    assert!(!flags[2].0.load(Ordering::SeqCst));
+}
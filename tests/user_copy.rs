@@ -0,0 +1,227 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, init,
+    memory::{
+        self,
+        address_space::{AddressSpace, DuplicateMode, USER_START},
+        frame::BitmapFrameAllocator,
+        user_copy::{self, UserCopyError},
+    },
+    test_panic_handler,
+};
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+/// A writable page, followed by an unmapped one.
+const WRITABLE: u64 = USER_START;
+/// A read-only page, followed by an unmapped one.
+const READ_ONLY: u64 = USER_START + 0x10_0000;
+
+static SECRET: [u8; 4] = *b"key\0";
+
+/// Creates an address space with the pages `WRITABLE` and `READ_ONLY`, both
+/// ending with the given data.
+fn address_space(data: &[u8]) -> AddressSpace {
+    let mut address_space = AddressSpace::new().unwrap();
+    for (start, flags) in [
+        (
+            WRITABLE,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        ),
+        (READ_ONLY, PageTableFlags::NO_EXECUTE),
+    ] {
+        address_space
+            .map_user(VirtAddr::new(start), 4096, flags)
+            .unwrap();
+        let end = start + 4096 - data.len() as u64;
+        address_space.write(VirtAddr::new(end), data).unwrap();
+    }
+    address_space
+}
+
+/// Runs the given function with the given address space loaded in CR3.
+fn with_active<R>(address_space: &AddressSpace, f: impl FnOnce() -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let previous = unsafe { address_space.activate() };
+        let result = f();
+        unsafe { Cr3::write(previous, Cr3Flags::empty()) };
+        result
+    })
+}
+
+fn end_of(page: u64, len: usize) -> VirtAddr {
+    VirtAddr::new(page + 4096 - len as u64)
+}
+
+#[test_case]
+fn copy_from_user_reads_user_memory() {
+    let address_space = address_space(b"hello");
+    let mut buf = [0; 5];
+    let result = with_active(&address_space, || {
+        user_copy::copy_from_user(&mut buf, end_of(READ_ONLY, 5))
+    });
+    assert_eq!(result, Ok(()));
+    assert_eq!(&buf, b"hello");
+}
+
+#[test_case]
+fn copy_from_user_fails_at_unmapped_page() {
+    let address_space = address_space(b"hello");
+    let mut buf = [0; 8];
+    let result = with_active(&address_space, || {
+        user_copy::copy_from_user(&mut buf, end_of(WRITABLE, 5))
+    });
+    assert_eq!(
+        result,
+        Err(UserCopyError::Fault(VirtAddr::new(WRITABLE + 4096)))
+    );
+    assert_eq!(&buf, b"hello\0\0\0");
+}
+
+#[test_case]
+fn copy_to_user_writes_user_memory() {
+    let mut address_space = address_space(b"");
+    let result = with_active(&address_space, || {
+        user_copy::copy_to_user(end_of(WRITABLE, 5), b"hello")
+    });
+    assert_eq!(result, Ok(()));
+
+    let mut buf = [0; 5];
+    address_space.read(end_of(WRITABLE, 5), &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test_case]
+fn copy_to_user_fails_on_read_only_page() {
+    let mut address_space = address_space(b"hello");
+    let result = with_active(&address_space, || {
+        user_copy::copy_to_user(end_of(READ_ONLY, 5), b"HELLO")
+    });
+    assert_eq!(result, Err(UserCopyError::Fault(end_of(READ_ONLY, 5))));
+
+    let mut buf = [0; 5];
+    address_space.read(end_of(READ_ONLY, 5), &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test_case]
+fn copy_to_user_copies_copy_on_write_pages() {
+    let mut parent = address_space(b"hello");
+    let mut child = parent.duplicate(DuplicateMode::CopyOnWrite).unwrap();
+    let result = with_active(&child, || {
+        user_copy::copy_to_user(end_of(WRITABLE, 5), b"HELLO")
+    });
+    assert_eq!(result, Ok(()));
+
+    let mut buf = [0; 5];
+    child.read(end_of(WRITABLE, 5), &mut buf).unwrap();
+    assert_eq!(&buf, b"HELLO");
+    parent.read(end_of(WRITABLE, 5), &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test_case]
+fn kernel_memory_is_not_user_range() {
+    let address_space = address_space(b"");
+    let secret = VirtAddr::from_ptr(SECRET.as_ptr());
+    let mut buf = [0; 4];
+    with_active(&address_space, || {
+        assert_eq!(
+            user_copy::copy_from_user(&mut buf, secret),
+            Err(UserCopyError::NotUserRange)
+        );
+        assert_eq!(
+            user_copy::copy_to_user(secret, b"lock"),
+            Err(UserCopyError::NotUserRange)
+        );
+        assert_eq!(
+            user_copy::strncpy_from_user(&mut buf, secret),
+            Err(UserCopyError::NotUserRange)
+        );
+    });
+    assert_eq!(buf, [0; 4]);
+    assert_eq!(&SECRET, b"key\0");
+}
+
+#[test_case]
+fn strncpy_from_user_copies_string() {
+    let address_space = address_space(b"hello\0");
+    let mut buf = [0xff; 8];
+    let result = with_active(&address_space, || {
+        user_copy::strncpy_from_user(&mut buf, end_of(READ_ONLY, 6))
+    });
+    assert_eq!(result, Ok(5));
+    assert_eq!(&buf, b"hello\0\xff\xff");
+}
+
+#[test_case]
+fn strncpy_from_user_stops_at_end_of_buffer() {
+    let address_space = address_space(b"hello\0");
+    let mut buf = [0; 3];
+    let result = with_active(&address_space, || {
+        user_copy::strncpy_from_user(&mut buf, end_of(READ_ONLY, 6))
+    });
+    assert_eq!(result, Ok(3));
+    assert_eq!(&buf, b"hel");
+}
+
+#[test_case]
+fn strncpy_from_user_fails_at_unmapped_page() {
+    let address_space = address_space(b"hello");
+    let mut buf = [0; 8];
+    let result = with_active(&address_space, || {
+        user_copy::strncpy_from_user(&mut buf, end_of(READ_ONLY, 5))
+    });
+    assert_eq!(
+        result,
+        Err(UserCopyError::Fault(VirtAddr::new(READ_ONLY + 4096)))
+    );
+}
+
+#[test_case]
+fn kernel_survives_many_faulting_copies() {
+    let address_space = address_space(b"");
+    let mut buf = [0; 8];
+    with_active(&address_space, || {
+        for i in 0..1000 {
+            let addr = VirtAddr::new(WRITABLE + 4096 + i * 8);
+            assert!(user_copy::copy_from_user(&mut buf, addr).is_err());
+        }
+    });
+}
@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use rust_os::{
+    allocator, init,
+    memory::{self, frame::BitmapFrameAllocator},
+    syscall, test_panic_handler,
+    user::{self, Exit},
+};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_demand_paging(mapper, frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+const EXIT: [u8; 4] = [
+    0x31, 0xc0, // xor eax, eax (SYS_EXIT)
+    0x0f, 0x05, // syscall
+];
+
+static SECRET: u64 = 42;
+
+#[test_case]
+fn program_exits_with_code() {
+    let mut code = [0xbf, 42, 0, 0, 0].to_vec(); // mov edi, 42
+    code.extend_from_slice(&EXIT);
+    assert_eq!(user::run(&code).unwrap(), Exit::Exited(42));
+}
+
+#[test_case]
+fn program_runs_in_ring_3() {
+    let mut code = [
+        0x8c, 0xcf, // mov edi, cs
+        0x83, 0xe7, 0x03, // and edi, 3
+    ]
+    .to_vec();
+    code.extend_from_slice(&EXIT);
+    assert_eq!(user::run(&code).unwrap(), Exit::Exited(3));
+}
+
+#[test_case]
+fn program_has_writable_stack() {
+    let mut code = [
+        0x6a, 0x07, // push 7
+        0x5f, // pop rdi
+    ]
+    .to_vec();
+    code.extend_from_slice(&EXIT);
+    assert_eq!(user::run(&code).unwrap(), Exit::Exited(7));
+}
+
+#[test_case]
+fn unknown_syscall_fails() {
+    let mut code = [
+        0xb8, 0xe7, 0x03, 0x00, 0x00, // mov eax, 999
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc7, // mov rdi, rax
+    ]
+    .to_vec();
+    code.extend_from_slice(&EXIT);
+    let expected = 0u64.wrapping_sub(syscall::ENOSYS as u64);
+    assert_eq!(user::run(&code).unwrap(), Exit::Exited(expected));
+}
+
+#[test_case]
+fn kernel_memory_is_not_accessible() {
+    let addr = &SECRET as *const u64 as u64;
+    let mut code = [0x48, 0xb8].to_vec(); // mov rax, addr
+    code.extend_from_slice(&addr.to_le_bytes());
+    code.extend_from_slice(&[0x48, 0x8b, 0x38]); // mov rdi, [rax]
+    code.extend_from_slice(&EXIT);
+    assert_eq!(
+        user::run(&code).unwrap(),
+        Exit::PageFault(VirtAddr::new(addr))
+    );
+}
+
+#[test_case]
+fn privileged_instructions_fault() {
+    let code = [0xfa]; // cli
+    assert_eq!(user::run(&code).unwrap(), Exit::GeneralProtectionFault);
+}
+
+#[test_case]
+fn kernel_survives_many_programs() {
+    let mut code = [0xbf, 1, 0, 0, 0].to_vec(); // mov edi, 1
+    code.extend_from_slice(&EXIT);
+    for _ in 0..100 {
+        assert_eq!(user::run(&code).unwrap(), Exit::Exited(1));
+    }
+}
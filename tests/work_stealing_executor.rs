@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use rust_os::{
+    allocator, init, interrupt,
+    memory::{self, frame::BitmapFrameAllocator},
+    percpu, smp,
+    task::{work_stealing_executor::WorkStealingExecutor, Task},
+    test_panic_handler, time,
+};
+use x86_64::{instructions::hlt, VirtAddr};
+
+extern crate alloc;
+
+entry_point!(main);
+
+lazy_static! {
+    static ref EXECUTOR: WorkStealingExecutor = WorkStealingExecutor::new();
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    interrupt::init_apic(&mut mapper, &mut frame_allocator).expect("APIC initialization failed");
+    smp::init(&mut mapper, &mut frame_allocator).expect("SMP initialization failed");
+
+    // only the application processors run the executor, so every task which
+    // completes has been stolen from the queue of the bootstrap processor
+    smp::run_on_application_processors(Box::leak(Box::new(|| EXECUTOR.run())));
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info);
+}
+
+/// Waits until the counter reaches the given value, failing after a second.
+fn wait_for(counter: &AtomicUsize, value: usize) {
+    let start = time::uptime();
+    while counter.load(Ordering::SeqCst) < value {
+        assert!(time::uptime() - start < Duration::from_secs(1), "timed out");
+        hlt();
+    }
+}
+
+/// Returns `Pending` once after waking itself up.
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[test_case]
+fn tasks_are_stolen_by_other_cpus() {
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+    static CPUS: AtomicU64 = AtomicU64::new(0);
+
+    for _ in 0..32 {
+        EXECUTOR.spawn(Task::new(async {
+            CPUS.fetch_or(1 << percpu::current().id, Ordering::SeqCst);
+            COMPLETED.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    wait_for(&COMPLETED, 32);
+
+    let cpus = CPUS.load(Ordering::SeqCst);
+    assert_eq!(cpus & 1, 0);
+    assert_ne!(cpus, 0);
+}
+
+#[test_case]
+fn woken_tasks_run_again() {
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    for _ in 0..32 {
+        EXECUTOR.spawn(Task::new(async {
+            YieldOnce(false).await;
+            YieldOnce(false).await;
+            COMPLETED.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    wait_for(&COMPLETED, 32);
+}
@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)] // enable x86-interrupt ABI
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use rust_os::{gdt, memory, qemu, serial_print, serial_println, test_panic_handler};
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+/// The address of the code which is written.
+static TARGET: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(expected) && Cr2::read().as_u64() == TARGET.load(Ordering::SeqCst) {
+        serial_println!("[ok]");
+        qemu::exit(qemu::ExitCode::Success)
+    }
+    serial_println!("[failed]");
+    serial_println!("Unexpected page fault: {error_code:?}");
+    qemu::exit(qemu::ExitCode::Failed)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("write_protect::writing_to_code_faults...\t");
+
+    gdt::init();
+    memory::protection::enable();
+    TEST_IDT.load();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    memory::protection::remap_kernel(&mut mapper, &boot_info.memory_map)
+        .expect("remapping the kernel failed");
+
+    let code = target as usize as *mut u8;
+    TARGET.store(code as u64, Ordering::SeqCst);
+    unsafe { code.write_volatile(0xcc) };
+
+    panic!("Execution continued after writing to code");
+}
+
+#[inline(never)]
+fn target() {}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    test_panic_handler(info)
+}